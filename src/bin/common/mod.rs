@@ -0,0 +1,18 @@
+//! Common utilities for the command line tools.
+#![allow(unused)]
+
+use muon::*;
+
+/// Converts a zero padded name (as stored in directory entries and symlink inodes) to a string.
+pub fn name_to_string(name: &[u8]) -> String {
+    String::from_utf8_lossy(trim_zero(name)).into_owned()
+}
+
+/// Joins a directory path and an entry name into an absolute muon path.
+pub fn join_path(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
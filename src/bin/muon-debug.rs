@@ -0,0 +1,448 @@
+//! Interactive shell for inspecting and patching muon images, in the spirit of debugfs.
+//!
+//! Usage: muon-debug [-w] [-R request] <image>
+//!
+//! The image is opened read-only unless `-w` is given. With `-R`, the single request is
+//! executed and the shell exits, otherwise requests are read line by line from stdin.
+//! Inodes may be given either as absolute paths or as `<N>` inode numbers.
+
+mod common;
+
+use std::io::{BufRead, Write};
+use std::process::ExitCode;
+
+use common::name_to_string;
+use muon::*;
+
+const HELP: &str = "\
+requests:
+  sb                                show the superblock
+  ls [path]                         list a directory
+  stat <file>                       show inode fields
+  cat <file>                        print file contents
+  blocks <file>                     show the logical to physical block mapping
+  bitmap <data|inode> [start [n]]   dump bitmap bits
+  dirents <dir>                     dump raw directory slots, including empty ones
+  inode <file>                      hex dump the on-disk inode record
+  block <id>                        hex dump a block
+  set-inode <file> <field> <value>  set an inode field (ftype, mode, links, blocks, size, indirect, direct[N])
+  set-sb <field> <value>            set a superblock field
+  write-block <id> <offset> <hex>   overwrite bytes of a block
+  help                              show this message
+  quit                              leave the shell
+<file> is an absolute path or an inode number written as <N>.";
+
+struct Shell {
+    device: FileDevice,
+    superblock: SuperBlock,
+}
+
+type CmdResult = core::result::Result<(), String>;
+
+fn fs_err(e: Error) -> String {
+    format!("{:?}", e)
+}
+
+fn parse_num(s: &str) -> core::result::Result<u64, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number: {}", s))
+}
+
+fn parse_u32(s: &str) -> core::result::Result<u32, String> {
+    u32::try_from(parse_num(s)?).map_err(|_| format!("number out of range: {}", s))
+}
+
+fn parse_ftype(s: &str) -> core::result::Result<FileType, String> {
+    match s {
+        "regular" | "1" => Ok(FileType::Regular),
+        "directory" | "2" => Ok(FileType::Directory),
+        "symlink" | "3" => Ok(FileType::Symlink),
+        "special" | "4" => Ok(FileType::Special),
+        _ => Err(format!("invalid file type: {}", s)),
+    }
+}
+
+fn parse_mode(s: &str) -> core::result::Result<Mode, String> {
+    match s.to_ascii_uppercase().as_str() {
+        "-" | "NONE" => Ok(Mode::None),
+        "R" => Ok(Mode::Read),
+        "W" => Ok(Mode::Write),
+        "E" => Ok(Mode::Execute),
+        "RW" => Ok(Mode::RW),
+        "RE" => Ok(Mode::RE),
+        "RWE" => Ok(Mode::RWE),
+        _ => Err(format!("invalid mode: {}", s)),
+    }
+}
+
+fn parse_hex_bytes(s: &str) -> core::result::Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err("hex string must have an even number of digits".to_string());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid hex: {}", s)))
+        .collect()
+}
+
+fn hex_dump(out: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        write!(out, "{:04x}  ", i * 16)?;
+        for byte in row {
+            write!(out, "{:02x} ", byte)?;
+        }
+        let ascii: String = row
+            .iter()
+            .map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '.' })
+            .collect();
+        writeln!(out, "{:>pad$}|{}|", "", ascii, pad = (16 - row.len()) * 3)?;
+    }
+    Ok(())
+}
+
+impl Shell {
+    /// Resolves `<N>` or an absolute path to an inode number.
+    fn inode_id(&mut self, arg: &str) -> core::result::Result<u32, String> {
+        if let Some(num) = arg.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+            return parse_u32(num);
+        }
+        let (_, inode_id) = resolve_without_last(&self.device, &mut self.superblock, arg)
+            .map_err(fs_err)?;
+        Ok(inode_id)
+    }
+
+    fn inode(&mut self, arg: &str) -> core::result::Result<Inode, String> {
+        let inode_id = self.inode_id(arg)?;
+        get_inode(&self.device, &self.superblock, inode_id).map_err(fs_err)
+    }
+
+    fn check_writable(&self) -> CmdResult {
+        if self.device.is_read_only() {
+            return Err("image is opened read-only, restart with -w".to_string());
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, out: &mut impl Write, args: &[&str]) -> CmdResult {
+        let io = |e: std::io::Error| e.to_string();
+        match args {
+            ["sb"] => {
+                self.superblock = read_superblock(&self.device).map_err(fs_err)?;
+                writeln!(out, "{:#?}", self.superblock).map_err(io)
+            },
+            ["ls"] => self.ls(out, "/"),
+            ["ls", path] => self.ls(out, path),
+            ["stat", file] => {
+                let inode = self.inode(file)?;
+                writeln!(out, "{:#?}", inode).map_err(io)?;
+                if inode.is_symlink() {
+                    let target = link_target(&self.device, &self.superblock, &inode).map_err(fs_err)?;
+                    writeln!(out, "target: {}", name_to_string(&target)).map_err(io)?;
+                }
+                Ok(())
+            },
+            ["cat", file] => self.cat(out, file),
+            ["blocks", file] => self.blocks(out, file),
+            ["bitmap", which] => self.bitmap(out, which, 0, u32::MAX),
+            ["bitmap", which, start] => self.bitmap(out, which, parse_u32(start)?, u32::MAX),
+            ["bitmap", which, start, n] => self.bitmap(out, which, parse_u32(start)?, parse_u32(n)?),
+            ["dirents", dir] => self.dirents(out, dir),
+            ["inode", file] => {
+                let inode_id = self.inode_id(file)?;
+                if inode_id >= self.superblock.num_inodes {
+                    return Err(fs_err(Error::OutOfBounds));
+                }
+                let inodes_per_block = self.superblock.inodes_per_block();
+                let inode_size = self.superblock.inode_size();
+                let block_id = self.superblock.inode_table_start + inode_id / inodes_per_block;
+                let offset = (inode_id % inodes_per_block) as usize * inode_size;
+                let mut buf = [0u8; BLOCK_SIZE];
+                self.device.read_block(block_id, &mut buf).map_err(fs_err)?;
+                writeln!(out, "inode {} at block {}, offset {}", inode_id, block_id, offset).map_err(io)?;
+                hex_dump(out, &buf[offset..offset + inode_size]).map_err(io)
+            },
+            ["block", id] => {
+                let mut buf = [0u8; BLOCK_SIZE];
+                self.device.read_block(parse_u32(id)?, &mut buf).map_err(fs_err)?;
+                hex_dump(out, &buf).map_err(io)
+            },
+            ["set-inode", file, field, value] => self.set_inode(file, field, value),
+            ["set-sb", field, value] => self.set_sb(field, value),
+            ["write-block", id, offset, hex] => {
+                self.check_writable()?;
+                let block_id = parse_u32(id)?;
+                let offset = parse_num(offset)? as usize;
+                let bytes = parse_hex_bytes(hex)?;
+                if offset + bytes.len() > BLOCK_SIZE {
+                    return Err("write crosses the block boundary".to_string());
+                }
+                let mut buf = [0u8; BLOCK_SIZE];
+                self.device.read_block(block_id, &mut buf).map_err(fs_err)?;
+                buf[offset..offset + bytes.len()].copy_from_slice(&bytes);
+                self.device.write_block(block_id, &buf).map_err(fs_err)?;
+                self.device.flush().map_err(fs_err)
+            },
+            ["help"] => writeln!(out, "{}", HELP).map_err(io),
+            _ => Err(format!("unknown request: {}, try help", args.join(" "))),
+        }
+    }
+
+    fn ls(&mut self, out: &mut impl Write, path: &str) -> CmdResult {
+        let (_, inode_id) = resolve(&self.device, &mut self.superblock, path).map_err(fs_err)?;
+        let mut dir_inode = get_inode(&self.device, &self.superblock, inode_id).map_err(fs_err)?;
+        let entries = read_dir(&self.device, &mut self.superblock, &mut dir_inode).map_err(fs_err)?;
+        for entry in entries {
+            let name = name_to_string(&entry.name);
+            match get_inode(&self.device, &self.superblock, entry.inode_id) {
+                Ok(inode) => writeln!(
+                    out, "{:>6} {:?} {:?} {:>3} {:>10} {}",
+                    entry.inode_id, inode.ftype, inode.mode, inode.links_cnt, inode.size, name
+                ),
+                Err(e) => writeln!(out, "{:>6} <{:?}> {}", entry.inode_id, e, name),
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn cat(&mut self, out: &mut impl Write, file: &str) -> CmdResult {
+        let mut inode = self.inode(file)?;
+        if inode.is_symlink() && file.starts_with('/') {
+            // Unlike the other requests, cat follows a trailing symlink.
+            let (_, inode_id) = resolve(&self.device, &mut self.superblock, file).map_err(fs_err)?;
+            inode = get_inode(&self.device, &self.superblock, inode_id).map_err(fs_err)?;
+        }
+        let mut buf = vec![0u8; 16 * BLOCK_SIZE];
+        let mut offset = 0;
+        while (offset as u64) < inode.size {
+            let len = buf.len().min((inode.size - offset as u64) as usize);
+            let n = fread(&self.device, &mut self.superblock, &mut inode, offset, &mut buf[..len])
+                .map_err(fs_err)?;
+            if n == 0 {
+                break;
+            }
+            out.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    fn blocks(&mut self, out: &mut impl Write, file: &str) -> CmdResult {
+        let mut inode = self.inode(file)?;
+        let block_ptrs = *inode.get_block_ptrs().map_err(fs_err)?;
+        let io = |e: std::io::Error| e.to_string();
+        writeln!(out, "size {}, blocks {}", inode.size, inode.blocks).map_err(io)?;
+        if inode.has_inline_data() {
+            return writeln!(out, "inline data").map_err(io);
+        }
+        if let Some(indirect) = block_ptrs.indirect {
+            writeln!(out, "indirect pointer block: {}", indirect).map_err(io)?;
+        }
+        for i in 0..(NUM_DIRECT_PTRS + PTRS_PER_BLOCK) as u64 {
+            match bmap(&self.device, &mut self.superblock, &mut inode, i * BLOCK_SIZE as u64, false) {
+                Ok(block_id) => writeln!(out, "{:>6} -> {}", i, block_id).map_err(io)?,
+                Err(Error::OutOfBounds) => (),
+                Err(e) => return Err(fs_err(e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn bitmap(&mut self, out: &mut impl Write, which: &str, start: u32, n: u32) -> CmdResult {
+        let (bitmap_start, total) = match which {
+            "data" => (self.superblock.data_bitmap_start, self.superblock.num_blocks - self.superblock.data_start),
+            "inode" => (self.superblock.inode_bitmap_start, self.superblock.num_inodes),
+            _ => return Err(format!("unknown bitmap: {}, expected data or inode", which)),
+        };
+        let end = total.min(start.saturating_add(n));
+        let bits_per_block = BLOCK_SIZE as u32 * 8;
+        let mut buf = [0u8; BLOCK_SIZE];
+        let mut loaded = None;
+        let mut line = String::new();
+        for id in start..end {
+            let block_id = bitmap_start + id / bits_per_block;
+            if loaded != Some(block_id) {
+                self.device.read_block(block_id, &mut buf).map_err(fs_err)?;
+                loaded = Some(block_id);
+            }
+            let bit = id % bits_per_block;
+            line.push(if buf[bit as usize / 8] & (1 << (bit % 8)) != 0 { '1' } else { '0' });
+            if (id - start) % 64 == 63 || id + 1 == end {
+                writeln!(out, "{:>6}: {}", id - (id - start) % 64, line).map_err(|e| e.to_string())?;
+                line.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn dirents(&mut self, out: &mut impl Write, dir: &str) -> CmdResult {
+        let mut inode = self.inode(dir)?;
+        if !inode.is_directory() {
+            return Err(fs_err(Error::NotDirectory));
+        }
+        let io = |e: std::io::Error| e.to_string();
+        writeln!(out, "size {} ({} entries), blocks {}", inode.size, inode.size / DIR_ENTRY_SIZE as u64, inode.blocks).map_err(io)?;
+        if inode.has_inline_data() {
+            let entries = read_dir(&self.device, &mut self.superblock, &mut inode).map_err(fs_err)?;
+            writeln!(out, "inline:").map_err(io)?;
+            for (j, entry) in entries.iter().enumerate() {
+                writeln!(out, "  slot {:>3} used inode {:>6} name {:?}", j, entry.inode_id, name_to_string(&entry.name)).map_err(io)?;
+            }
+            return Ok(());
+        }
+        let mut buf = [0u8; BLOCK_SIZE];
+        for i in 0..inode.blocks as u64 {
+            let block_id = bmap(&self.device, &mut self.superblock, &mut inode, i * BLOCK_SIZE as u64, false)
+                .map_err(fs_err)?;
+            self.device.read_block(block_id, &mut buf).map_err(fs_err)?;
+            writeln!(out, "block {} (logical {}):", block_id, i).map_err(io)?;
+            for j in 0..NUM_ENTRY_PER_BLOCK {
+                let entry = unsafe {
+                    core::ptr::read_unaligned(buf.as_ptr().add(j * DIR_ENTRY_SIZE) as *const DirEntry)
+                };
+                let state = if entry.is_empty() { "free" } else { "used" };
+                writeln!(out, "  slot {:>3} {} inode {:>6} name {:?}", j, state, entry.inode_id, name_to_string(&entry.name)).map_err(io)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_inode(&mut self, file: &str, field: &str, value: &str) -> CmdResult {
+        self.check_writable()?;
+        let mut inode = self.inode(file)?;
+        match field {
+            "ftype" => inode.ftype = parse_ftype(value)?,
+            "mode" => inode.mode = parse_mode(value)?,
+            "links" => inode.links_cnt = parse_u32(value)?,
+            "blocks" => inode.blocks = parse_u32(value)?,
+            "size" => inode.size = parse_num(value)?,
+            "indirect" => {
+                let block_id = parse_u32(value)?;
+                inode.get_block_ptrs_mut().map_err(fs_err)?.indirect = (block_id != 0).then_some(block_id);
+            },
+            _ => {
+                let index = field
+                    .strip_prefix("direct[")
+                    .and_then(|s| s.strip_suffix(']'))
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .filter(|&i| i < NUM_DIRECT_PTRS)
+                    .ok_or_else(|| format!("unknown inode field: {}", field))?;
+                let block_id = parse_u32(value)?;
+                inode.get_block_ptrs_mut().map_err(fs_err)?.direct[index] = (block_id != 0).then_some(block_id);
+            },
+        }
+        write_inode(&self.device, &self.superblock, &inode).map_err(fs_err)?;
+        self.device.flush().map_err(fs_err)
+    }
+
+    fn set_sb(&mut self, field: &str, value: &str) -> CmdResult {
+        self.check_writable()?;
+        let value = parse_u32(value)?;
+        let sb = &mut self.superblock;
+        let target = match field {
+            "magic" => &mut sb.magic,
+            "num_blocks" => &mut sb.num_blocks,
+            "block_size" => &mut sb.block_size,
+            "free_blocks" => &mut sb.free_blocks,
+            "num_inodes" => &mut sb.num_inodes,
+            "free_inodes" => &mut sb.free_inodes,
+            "root_inode" => &mut sb.root_inode,
+            "data_bitmap_start" => &mut sb.data_bitmap_start,
+            "data_bitmap_blocks" => &mut sb.data_bitmap_blocks,
+            "inode_bitmap_start" => &mut sb.inode_bitmap_start,
+            "inode_bitmap_blocks" => &mut sb.inode_bitmap_blocks,
+            "inode_table_start" => &mut sb.inode_table_start,
+            "inode_table_blocks" => &mut sb.inode_table_blocks,
+            "data_start" => &mut sb.data_start,
+            "rev_level" => &mut sb.rev_level,
+            "feature_compat" => &mut sb.feature_compat,
+            "feature_incompat" => &mut sb.feature_incompat,
+            "feature_ro_compat" => &mut sb.feature_ro_compat,
+            _ => return Err(format!("unknown superblock field: {}", field)),
+        };
+        *target = value;
+        write_superblock_with_backups(&self.device, &self.superblock).map_err(fs_err)?;
+        self.device.flush().map_err(fs_err)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut writable = false;
+    let mut request = None;
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-w" => writable = true,
+            "-R" => request = args.next(),
+            _ => positional.push(arg),
+        }
+    }
+    let [image] = positional.as_slice() else {
+        eprintln!("usage: muon-debug [-w] [-R request] <image>");
+        return ExitCode::from(2);
+    };
+
+    let device = if writable {
+        FileDevice::open(image)
+    } else {
+        FileDevice::open_read_only(image)
+    };
+    let device = match device {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("muon-debug: cannot open {}: {}", image, e);
+            return ExitCode::from(2);
+        },
+    };
+    // A damaged superblock is exactly what this tool may be used to repair,
+    // so fall back to the raw block contents instead of refusing to start.
+    let superblock = read_superblock(&device).unwrap_or_else(|e| {
+        if let Ok((superblock, block_id)) = find_backup_superblock(&device) {
+            eprintln!("muon-debug: warning: superblock is invalid ({:?}), using the backup in block {}", e, block_id);
+            return superblock;
+        }
+        eprintln!("muon-debug: warning: superblock is invalid ({:?}), using it as is", e);
+        let mut buf = [0u8; BLOCK_SIZE];
+        let _ = device.read_block(SUPERBLOCK_ID, &mut buf);
+        unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const SuperBlock) }
+    });
+    let mut shell = Shell { device, superblock };
+    let mut out = std::io::stdout();
+
+    if let Some(request) = request {
+        let args: Vec<&str> = request.split_whitespace().collect();
+        return match shell.execute(&mut out, &args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(msg) => {
+                eprintln!("muon-debug: {}", msg);
+                ExitCode::FAILURE
+            },
+        };
+    }
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("muon-debug: ");
+        let _ = out.flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => continue,
+            ["quit"] | ["q"] => break,
+            _ => {
+                if let Err(msg) = shell.execute(&mut out, &args) {
+                    eprintln!("{}", msg);
+                }
+            },
+        }
+    }
+    ExitCode::SUCCESS
+}
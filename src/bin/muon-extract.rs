@@ -0,0 +1,178 @@
+//! Extracts the contents of a muon image into a directory on the host.
+//!
+//! Usage: muon-extract <image> <dest-dir>
+//!
+//! The image is opened read-only. Directories, regular files and symlinks are recreated,
+//! inodes with several hard links are extracted once and hard linked afterwards.
+//! Entries that cannot be read are reported and skipped, rather than aborting the extraction.
+
+mod common;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use common::{join_path, name_to_string};
+use muon::*;
+
+/// Number of bytes requested from the file system per read.
+const CHUNK_SIZE: usize = 64 * BLOCK_SIZE;
+
+struct Extractor {
+    fs: FileSystem<FileDevice>,
+    /// Host path of the first extracted name of every multiply linked inode.
+    linked: HashMap<u32, PathBuf>,
+    /// Directories already visited, guarding against cycles in corrupt images.
+    visited: HashSet<u32>,
+    errors: usize,
+}
+
+impl Extractor {
+    fn report(&mut self, path: &str, msg: impl std::fmt::Display) {
+        eprintln!("muon-extract: {}: {}", path, msg);
+        self.errors += 1;
+    }
+
+    fn extract_dir(&mut self, path: &str, dir_id: u32, host: &Path) {
+        if !self.visited.insert(dir_id) {
+            self.report(path, "directory cycle detected, skipped");
+            return;
+        }
+
+        let entries = match self.fs.read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return self.report(path, format_args!("cannot read directory: {:?}", e)),
+        };
+
+        for entry in entries {
+            if entry.name_eq(DOT_NAME) || entry.name_eq(DOTDOT_NAME) {
+                continue;
+            }
+            let name = name_to_string(&entry.name);
+            let child_path = join_path(path, &name);
+            if name.contains('/') || entry.inode_id == 0 {
+                self.report(&child_path, "corrupt directory entry, skipped");
+                continue;
+            }
+            self.extract_entry(&child_path, entry.inode_id, &host.join(&name));
+        }
+    }
+
+    fn extract_entry(&mut self, path: &str, inode_id: u32, host: &Path) {
+        let inode = match self.fs.get_inode(inode_id) {
+            Ok(inode) if inode.id == inode_id => inode,
+            Ok(_) => return self.report(path, format_args!("corrupt inode {}", inode_id)),
+            Err(e) => return self.report(path, format_args!("cannot read inode {}: {:?}", inode_id, e)),
+        };
+
+        if inode.ftype == FileType::Regular && inode.links_cnt > 1
+            && let Some(first) = self.linked.get(&inode_id).cloned()
+        {
+            if let Err(e) = std::fs::hard_link(&first, host) {
+                self.report(path, format_args!("cannot create hard link: {}", e));
+            }
+            return;
+        }
+
+        match inode.ftype {
+            FileType::Directory => {
+                if let Err(e) = std::fs::create_dir_all(host) {
+                    return self.report(path, format_args!("cannot create directory: {}", e));
+                }
+                self.extract_dir(path, inode_id, host);
+            },
+            FileType::Regular => {
+                match self.extract_file(&inode, host) {
+                    Ok(()) => {
+                        if inode.links_cnt > 1 {
+                            self.linked.insert(inode_id, host.to_path_buf());
+                        }
+                    },
+                    Err(msg) => self.report(path, msg),
+                }
+            },
+            FileType::Symlink => {
+                let mut target = [0u8; MAX_SYMLINK_LEN];
+                let len = match self.fs.read_link_into_by_inode_id(inode_id, &mut target) {
+                    Ok(len) => len,
+                    Err(e) => return self.report(path, format_args!("cannot read symlink: {:?}", e)),
+                };
+                if let Err(e) = std::os::unix::fs::symlink(name_to_string(&target[..len]), host) {
+                    self.report(path, format_args!("cannot create symlink: {}", e));
+                }
+            },
+            FileType::Special => self.report(path, "special files are not supported, skipped"),
+        }
+    }
+
+    fn extract_file(&mut self, inode: &Inode, host: &Path) -> core::result::Result<(), String> {
+        let mut file = std::fs::File::create(host)
+            .map_err(|e| format!("cannot create file: {}", e))?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut offset = 0;
+
+        while (offset as u64) < inode.size {
+            let len = CHUNK_SIZE.min((inode.size - offset as u64) as usize);
+            let bytes_read = match self.fs.fread_by_inode(inode.id, offset, &mut buf[..len]) {
+                Ok(n) => n,
+                Err(Error::EOF(_)) => break,
+                Err(e) => return Err(format!("read failed at offset {}: {:?}", offset, e)),
+            };
+            file.write_all(&buf[..bytes_read])
+                .map_err(|e| format!("cannot write file: {}", e))?;
+            offset += bytes_read;
+        }
+
+        if (offset as u64) < inode.size {
+            return Err(format!("truncated, {} of {} bytes extracted", offset, inode.size));
+        }
+        Ok(())
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: muon-extract <image> <dest-dir>");
+        return ExitCode::from(2);
+    }
+
+    let device = match FileDevice::open_read_only(&args[1]) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("muon-extract: cannot open {}: {}", args[1], e);
+            return ExitCode::from(2);
+        },
+    };
+    let options = MountOptions { read_only: true, ..Default::default() };
+    let fs = match FileSystem::mount_with_options(Arc::new(device), options) {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("muon-extract: cannot mount {}: {:?}", args[1], e);
+            return ExitCode::from(2);
+        },
+    };
+
+    let dest = PathBuf::from(&args[2]);
+    if let Err(e) = std::fs::create_dir_all(&dest) {
+        eprintln!("muon-extract: cannot create {}: {}", dest.display(), e);
+        return ExitCode::from(2);
+    }
+
+    let mut extractor = Extractor {
+        fs,
+        linked: HashMap::new(),
+        visited: HashSet::new(),
+        errors: 0,
+    };
+    let root = extractor.fs.root_inode_id();
+    extractor.extract_dir("/", root, &dest);
+
+    if extractor.errors > 0 {
+        eprintln!("muon-extract: {} entries could not be extracted", extractor.errors);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,99 @@
+//! Imports tar archives into muon images and exports subtrees of images as tar archives.
+//!
+//! Usage:
+//!   muon-tar import <image> <archive|-> [dest]
+//!   muon-tar export <image> <archive|-> [path]
+//!
+//! `-` reads the archive from stdin or writes it to stdout.
+//! `dest` and `path` default to the root directory.
+
+use std::io::{Read, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use muon::*;
+
+fn import(fs: &mut FileSystem<FileDevice>, mut input: impl Read, dest: &str) -> Result<usize> {
+    let mut importer = TarImporter::new(fs, dest)?;
+    let mut record = [0u8; TAR_RECORD_SIZE];
+    loop {
+        let mut filled = 0;
+        while filled < TAR_RECORD_SIZE {
+            match input.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return Err(Error::ReadError),
+            }
+        }
+        match filled {
+            0 => break,
+            TAR_RECORD_SIZE => importer.feed(&record)?,
+            _ => return Err(Error::InvalidArchive),
+        }
+    }
+    importer.finish()
+}
+
+fn export(fs: &mut FileSystem<FileDevice>, mut output: impl Write, path: &str) -> Result<()> {
+    export_tar(fs, path, |record| output.write_all(record).map_err(|_| Error::WriteError))?;
+    output.flush().map_err(|_| Error::WriteError)
+}
+
+fn run(args: &[String]) -> core::result::Result<(), String> {
+    let (command, image, archive, path) = match args {
+        [command, image, archive] => (command, image, archive, "/"),
+        [command, image, archive, path] => (command, image, archive, path.as_str()),
+        _ => return Err("usage: muon-tar <import|export> <image> <archive|-> [path]".to_string()),
+    };
+    let writable = match command.as_str() {
+        "import" => true,
+        "export" => false,
+        _ => return Err(format!("unknown command: {}", command)),
+    };
+
+    let device = if writable {
+        FileDevice::open(image)
+    } else {
+        FileDevice::open_read_only(image)
+    };
+    let device = device.map_err(|e| format!("cannot open {}: {}", image, e))?;
+    let options = MountOptions { read_only: !writable, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(Arc::new(device), options)
+        .map_err(|e| format!("cannot mount {}: {:?}", image, e))?;
+
+    if writable {
+        let result = if archive == "-" {
+            import(&mut fs, std::io::stdin().lock(), path)
+        } else {
+            let file = std::fs::File::open(archive).map_err(|e| format!("cannot open {}: {}", archive, e))?;
+            import(&mut fs, std::io::BufReader::new(file), path)
+        };
+        // Persist whatever was imported, even if the archive turned out to be broken.
+        fs.unmount().map_err(|e| format!("cannot unmount {}: {:?}", image, e))?;
+        let skipped = result.map_err(|e| format!("import failed: {:?}", e))?;
+        if skipped > 0 {
+            eprintln!("muon-tar: {} entries of unsupported types skipped", skipped);
+        }
+    } else {
+        let result = if archive == "-" {
+            export(&mut fs, std::io::stdout().lock(), path)
+        } else {
+            let file = std::fs::File::create(archive).map_err(|e| format!("cannot create {}: {}", archive, e))?;
+            export(&mut fs, std::io::BufWriter::new(file), path)
+        };
+        result.map_err(|e| format!("export failed: {:?}", e))?;
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("muon-tar: {}", msg);
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -2,9 +2,11 @@
 //! Data bitmap then uses these pointers to track which blocks are used for according data.
 //! Inode bitmap for tracking files' inodes, which tell direct and indirect pointers to data blocks.
 
-use alloc::boxed::Box;
 use alloc::vec;
+use alloc::vec::Vec;
 
+use crate::caches::block_runs;
+use crate::checksum::{get_meta_block, put_meta_block, update_data_checksum};
 use crate::superblock::write_superblock;
 use crate::{config::*, BlockDevice, Result, SuperBlock};
 use crate::error::FsError;
@@ -14,16 +16,16 @@ use crate::error::FsError;
 /// Returns the item ID of the bit that was set or cleared.
 fn set_first_fit_bit(
     device: &impl BlockDevice,
+    superblock: &SuperBlock,
     bitmap_start: u32,
     bitmap_blocks: u32,
     total_items: u32,
     value: bool, // true for setting first false to true, false for setting first true to false
 ) -> Result<u32> {
-    let mut buf = Box::new([0u8; BLOCK_SIZE]);
-
     for i in 0..bitmap_blocks {
         let current_block_id = bitmap_start + i;
-        device.read_block(current_block_id, &mut buf)?;
+        let bh = get_meta_block(device, superblock, current_block_id)?;
+        let mut buf = bh.lock();
 
         for j in 0..BLOCK_SIZE {
             let byte = buf[j];
@@ -41,7 +43,9 @@ fn set_first_fit_bit(
                         // Clear the bit
                         buf[j] &= !(1 << k);
                     }
-                    device.write_block(current_block_id, buf.as_ref())?;
+                    drop(buf);
+                    bh.mark_dirty();
+                    put_meta_block(device, superblock, bh)?;
                     return Ok(current_item_id);
                 }
             }
@@ -57,6 +61,7 @@ fn set_first_fit_bit(
 /// Returns previously set value of the bit.
 fn set_bit_at(
     device: &impl BlockDevice,
+    superblock: &SuperBlock,
     bitmap_start: u32,
     bitmap_blocks: u32,
     item_id: u32,
@@ -76,20 +81,55 @@ fn set_bit_at(
     }
 
     let target_block_id = bitmap_start + block_id;
-    let mut buf = Box::new([0u8; BLOCK_SIZE]);
+    let bh = get_meta_block(device, superblock, target_block_id)?;
 
-    device.read_block(target_block_id, buf.as_mut())?;
+    let mut buf = bh.lock();
     let pre_value = (buf[byte_offset as usize] & (1 << bit_offset)) != 0;
     if set_value {
         buf[byte_offset as usize] |= 1 << bit_offset;
     } else {
         buf[byte_offset as usize] &= !(1 << bit_offset);
     }
-    device.write_block(target_block_id, buf.as_ref())?;
+    drop(buf);
+    bh.mark_dirty();
+    put_meta_block(device, superblock, bh)?;
 
     Ok(pre_value)
 }
 
+/// Block of the data bitmap recording the allocation of a data block.
+pub(crate) fn data_bitmap_block_id(superblock: &SuperBlock, block_id: u32) -> u32 {
+    superblock.data_bitmap_start + (block_id - superblock.data_start) / (BLOCK_SIZE as u32 * 8)
+}
+
+/// Block of the inode bitmap recording the allocation of an inode.
+pub(crate) fn inode_bitmap_block_id(superblock: &SuperBlock, inode_id: u32) -> u32 {
+    superblock.inode_bitmap_start + inode_id / (BLOCK_SIZE as u32 * 8)
+}
+
+/// Blocks of the data and inode bitmaps.
+/// The data bitmap is not necessarily followed by the inode bitmap, `grow` may relocate it.
+pub(crate) fn bitmap_block_ids(superblock: &SuperBlock) -> impl Iterator<Item = u32> + use<> {
+    let data_bitmap = superblock.data_bitmap_start..superblock.data_bitmap_start + superblock.data_bitmap_blocks;
+    let inode_bitmap = superblock.inode_bitmap_start..superblock.inode_bitmap_start + superblock.inode_bitmap_blocks;
+    data_bitmap.chain(inode_bitmap)
+}
+
+/// Number of blocks zeroed per transfer by `zero_blocks`.
+const ZERO_CHUNK_BLOCKS: u32 = 64;
+
+/// Zeroes `count` consecutive blocks, a chunk at a time.
+pub(crate) fn zero_blocks(device: &impl BlockDevice, start_block_id: u32, count: u32) -> Result<()> {
+    let zeroes = vec![0u8; ZERO_CHUNK_BLOCKS.min(count) as usize * BLOCK_SIZE];
+    let mut done = 0;
+    while done < count {
+        let n = ZERO_CHUNK_BLOCKS.min(count - done);
+        device.write_blocks(start_block_id + done, &zeroes[..n as usize * BLOCK_SIZE])?;
+        done += n;
+    }
+    Ok(())
+}
+
 // Public API for managing data bitmap and inode bitmap.
 
 /// Allocates a new data block, setting bit in the data bitmap.
@@ -98,23 +138,60 @@ fn set_bit_at(
 pub fn alloc_data_block(
     device: &impl BlockDevice,
     superblock: &mut SuperBlock,
+) -> Result<u32> {
+    let block_id = claim_data_block(device, superblock)?;
+
+    // Zero out the block
+    let bh = device.new_block(block_id)?;
+    device.put_block(bh)?;
+    update_data_checksum(device, superblock, block_id, &[0u8; BLOCK_SIZE])?;
+
+    Ok(block_id)
+}
+
+/// Allocates a data block like `alloc_data_block`, but leaves its contents and checksum as they are.
+/// For callers overwriting the whole block right away, so that a run of new blocks is written once.
+pub(crate) fn claim_data_block(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
 ) -> Result<u32> {
     let block_id = set_first_fit_bit(
         device, 
+        superblock,
         superblock.data_bitmap_start, 
         superblock.data_bitmap_blocks, 
         superblock.num_blocks - superblock.data_start,
         true)?;
     superblock.free_blocks -= 1;
     write_superblock(device, superblock)?;
-
-    // Zero out the block
-    let zero_block = Box::new([0u8; BLOCK_SIZE]);
-    device.write_block(block_id + superblock.data_start, zero_block.as_ref())?;
-
     Ok(block_id + superblock.data_start)
 }
 
+/// Marks a data block as used without handing it to a file, e.g. for a superblock backup.
+pub(crate) fn reserve_data_block(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    block_id: u32,
+) -> Result<()> {
+    if block_id < superblock.data_start {
+        return Err(FsError::OutOfBounds);
+    }
+    let was_set = set_bit_at(
+        device,
+        superblock,
+        superblock.data_bitmap_start,
+        superblock.data_bitmap_blocks,
+        block_id - superblock.data_start,
+        superblock.num_blocks - superblock.data_start,
+        true
+    )?;
+    if !was_set {
+        superblock.free_blocks -= 1;
+        write_superblock(device, superblock)?;
+    }
+    Ok(())
+}
+
 /// Frees a data block, clearing bit in the data bitmap.
 pub fn free_data_block(
     device: &impl BlockDevice,
@@ -128,6 +205,7 @@ pub fn free_data_block(
 
     set_bit_at(
         device, 
+        superblock,
         superblock.data_bitmap_start, 
         superblock.data_bitmap_blocks, 
         relative_block_id, 
@@ -139,6 +217,73 @@ pub fn free_data_block(
     Ok(())
 }
 
+/// Discards freed data blocks, one request per run of consecutive blocks.
+/// Discarding is advisory, failures are ignored as the blocks are free either way.
+pub(crate) fn discard_blocks(device: &impl BlockDevice, block_ids: &mut [u32]) {
+    block_ids.sort_unstable();
+    for (start, count) in block_runs(block_ids) {
+        let _ = device.discard(start, count as u32);
+    }
+}
+
+/// Discards every free data block, like `fstrim`.
+/// Returns the number of blocks discarded.
+pub fn trim_free_blocks(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+) -> Result<u32> {
+    let total_items = superblock.num_blocks - superblock.data_start;
+    let mut discarded = 0;
+    let mut run_start = None;
+
+    for i in 0..superblock.data_bitmap_blocks {
+        // Copied out, the device must not be called with the buffer locked.
+        let bitmap = get_meta_block(device, superblock, superblock.data_bitmap_start + i)?.read_obj::<[u8; BLOCK_SIZE]>(0);
+        for j in 0..BLOCK_SIZE as u32 * 8 {
+            let item_id = i * BLOCK_SIZE as u32 * 8 + j;
+            if item_id >= total_items {
+                break;
+            }
+            let is_set = bitmap[j as usize / 8] & (1 << (j % 8)) != 0;
+            match (is_set, run_start) {
+                (false, None) => run_start = Some(item_id),
+                (true, Some(start)) => {
+                    device.discard(superblock.data_start + start, item_id - start)?;
+                    discarded += item_id - start;
+                    run_start = None;
+                },
+                _ => {},
+            }
+        }
+    }
+    if let Some(start) = run_start {
+        device.discard(superblock.data_start + start, total_items - start)?;
+        discarded += total_items - start;
+    }
+    Ok(discarded)
+}
+
+/// IDs of the inodes allocated in the inode bitmap, in ascending order.
+pub(crate) fn allocated_inode_ids(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+) -> Result<Vec<u32>> {
+    let mut inode_ids = Vec::new();
+    for i in 0..superblock.inode_bitmap_blocks {
+        let bitmap = device.get_block(superblock.inode_bitmap_start + i)?.read_obj::<[u8; BLOCK_SIZE]>(0);
+        for j in 0..BLOCK_SIZE as u32 * 8 {
+            let inode_id = i * BLOCK_SIZE as u32 * 8 + j;
+            if inode_id >= superblock.num_inodes {
+                break;
+            }
+            if bitmap[j as usize / 8] & (1 << (j % 8)) != 0 {
+                inode_ids.push(inode_id);
+            }
+        }
+    }
+    Ok(inode_ids)
+}
+
 /// Allocates a new inode, setting bit in the inode bitmap.
 /// Only called by inode::alloc_inode.
 pub(crate) fn alloc_inode_id(
@@ -147,6 +292,7 @@ pub(crate) fn alloc_inode_id(
 ) -> Result<u32> {
     let inode_id = set_first_fit_bit(
         device, 
+        superblock,
         superblock.inode_bitmap_start, 
         superblock.inode_bitmap_blocks, 
         superblock.num_inodes,
@@ -165,6 +311,7 @@ pub(crate) fn free_inode_id(
 ) -> Result<()> {
     set_bit_at(
         device, 
+        superblock,
         superblock.inode_bitmap_start, 
         superblock.inode_bitmap_blocks, 
         inode_id, 
@@ -1,4 +1,6 @@
-use crate::{error::FsError, BLOCK_SIZE};
+use alloc::sync::Arc;
+
+use crate::{error::FsError, BufferHead, BLOCK_SIZE};
 
 
 pub trait BlockDevice: Send + Sync {
@@ -13,12 +15,123 @@ pub trait BlockDevice: Send + Sync {
     /// buf.len() must be equal to block_size().
     fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), FsError>;
     
+    /// Reads consecutive blocks starting at `start_block_id`.
+    /// buf.len() must be a multiple of block_size().
+    /// Defaults to one `read_block` per block, drivers able to do larger transfers should override it.
+    fn read_blocks(&self, start_block_id: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(FsError::InvalidArgument);
+        }
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            self.read_block(start_block_id + i as u32, block.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Writes consecutive blocks starting at `start_block_id`.
+    /// buf.len() must be a multiple of block_size().
+    /// Defaults to one `write_block` per block, drivers able to do larger transfers should override it.
+    fn write_blocks(&self, start_block_id: u32, buf: &[u8]) -> Result<(), FsError> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(FsError::InvalidArgument);
+        }
+        for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write_block(start_block_id + i as u32, block.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+
     /// Flushes any cached data to the block device.
     /// This is typically used to ensure that all writes are persisted.
     fn flush(&self) -> Result<(), FsError>;
+
+    /// Writes back the given blocks, if cached, and makes them durable, like `flush` restricted
+    /// to a set of blocks. Used by `fsync` so that syncing one file leaves the others cached,
+    /// and to order dependent metadata updates, e.g. an inode before the entry pointing to it.
+    /// Defaults to `flush`, which writes back everything.
+    fn flush_blocks(&self, block_ids: &[u32]) -> Result<(), FsError> {
+        self.flush()
+    }
+
+    /// Write barrier: every write issued before the barrier reaches stable storage before any
+    /// write issued after it. The file system relies on it at commit points, before the superblock.
+    /// Defaults to `flush`; devices able to order writes without waiting for them may do better.
+    fn barrier(&self) -> Result<(), FsError> {
+        self.flush()
+    }
+
+    /// Writes a block with Force Unit Access: the block is on stable storage when this returns.
+    /// Says nothing about the other writes, which need a `barrier` first if they must precede it.
+    /// Defaults to `write_block` followed by `flush`.
+    fn write_block_fua(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        self.write_block(block_id, buf)?;
+        self.flush()
+    }
     
+    /// Reads a block from the underlying storage, bypassing any cache.
+    /// Defaults to `read_block`, caching devices forward it to the device they cache.
+    fn read_block_uncached(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        self.read_block(block_id, buf)
+    }
+
+    /// Writes a block to stable storage like `write_block_fua`, but leaves a cached copy of the
+    /// block as it is, to be written back as usual. Lets `fsync` store a version of a block
+    /// without the changes of other files.
+    /// Defaults to `write_block_fua`, caching devices forward it to the device they cache.
+    fn write_block_uncached(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        self.write_block_fua(block_id, buf)
+    }
+    
+    /// Whether the device rejects writes, e.g. a write protected disk or an image opened
+    /// read-only. Such devices can only be mounted read-only.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
     /// Returns the size of each block in bytes.
     fn block_size(&self) -> usize {
         crate::config::BLOCK_SIZE
     }
-}
\ No newline at end of file
+
+    /// Tells the device that `count` blocks from `start_block_id` no longer hold data, so that
+    /// SSDs and thin provisioned disks can reclaim them. Their contents are undefined afterwards.
+    /// Devices without such a notion ignore it.
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// Hints that the given blocks are about to be read, so that caching devices can fetch them
+    /// in one go. Devices without a cache ignore it.
+    fn readahead(&self, block_ids: &[u32]) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// Returns a buffer holding the contents of a block.
+    /// Caching devices hand out the cached buffer itself, the default reads into a new one.
+    fn get_block(&self, block_id: u32) -> Result<Arc<BufferHead>, FsError> {
+        let bh = Arc::new(BufferHead::new(block_id));
+        self.read_block(block_id, &mut bh.lock())?;
+        Ok(bh)
+    }
+
+    /// Returns a zeroed, dirty buffer for a block whose current contents are irrelevant,
+    /// without reading it from the device.
+    fn new_block(&self, block_id: u32) -> Result<Arc<BufferHead>, FsError> {
+        let bh = Arc::new(BufferHead::new(block_id));
+        bh.mark_dirty();
+        Ok(bh)
+    }
+
+    /// Releases a buffer obtained from `get_block` or `new_block`, writing it back if dirty.
+    /// Buffers that were only read may simply be dropped.
+    fn put_block(&self, bh: Arc<BufferHead>) -> Result<(), FsError> {
+        if bh.clear_dirty() {
+            let res = self.write_block(bh.block_id(), &bh.lock());
+            if res.is_err() {
+                bh.mark_dirty();
+            }
+            return res;
+        }
+        Ok(())
+    }
+}
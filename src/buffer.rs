@@ -0,0 +1,399 @@
+//! Buffer heads: reference counted, lockable block buffers shared between the cache and its users.
+//!
+//! The fs layers get a buffer with `BlockDevice::get_block`, read or modify it in place under its lock,
+//! mark it dirty when modified and hand it back with `BlockDevice::put_block`.
+//! With `BufferCache` the buffer is the cached block itself, so no block is copied or allocated on a hit.
+//!
+//! Never hold a buffer's lock while calling into the device, the cache may need to lock it as well.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::DerefMut;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::caches::{block_runs, BlockIndex, LruList, NIL};
+use crate::sync::SpinLock;
+use crate::{BlockDevice, Error, Result, BLOCK_SIZE};
+
+pub struct BufferHead {
+    block_id: u32,
+    data: SpinLock<[u8; BLOCK_SIZE]>,
+    dirty: AtomicBool,
+}
+
+impl BufferHead {
+    /// Creates a zeroed, clean buffer for the given block.
+    pub fn new(block_id: u32) -> Self {
+        BufferHead {
+            block_id,
+            data: SpinLock::new([0u8; BLOCK_SIZE]),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn block_id(&self) -> u32 {
+        self.block_id
+    }
+
+    /// Locks the block contents for reading or in-place modification.
+    /// Modifications must be followed by `mark_dirty`.
+    pub fn lock(&self) -> impl DerefMut<Target = [u8; BLOCK_SIZE]> + '_ {
+        self.data.lock()
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Clears the dirty flag, returning whether it was set.
+    pub fn clear_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    /// Reads an on-disk structure (e.g. an inode or a directory entry) at the given byte offset.
+    pub fn read_obj<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= BLOCK_SIZE, "Object out of block bounds");
+        let data = self.lock();
+        unsafe { core::ptr::read_unaligned(data.as_ptr().add(offset) as *const T) }
+    }
+
+    /// Writes an on-disk structure at the given byte offset and marks the buffer dirty.
+    pub fn write_obj<T: Copy>(&self, offset: usize, obj: &T) {
+        assert!(offset + size_of::<T>() <= BLOCK_SIZE, "Object out of block bounds");
+        let mut data = self.lock();
+        unsafe { core::ptr::write_unaligned(data.as_mut_ptr().add(offset) as *mut T, *obj) };
+        self.mark_dirty();
+    }
+}
+
+struct BufferCacheInner {
+    index: BlockIndex,
+    list: LruList,
+    buffers: Vec<Option<Arc<BufferHead>>>,
+    free: Vec<usize>,
+}
+
+/// Block cache handing out its buffers directly, with LRU write-back of unused buffers.
+///
+/// Buffers still referenced outside the cache are never evicted. If all of them are, the cache
+/// grows beyond `capacity` rather than failing; it does not shrink back.
+/// Runs of missing blocks are read in one transfer, runs of dirty blocks are written back in one.
+/// The device is never accessed with the cache locked, so hits do not wait for block I/O.
+pub struct BufferCache<D: BlockDevice> {
+    device: D,
+    inner: SpinLock<BufferCacheInner>,
+    /// Serializes write-backs, so that a flush only returns once the buffers another
+    /// write-back took out as clean are on the device as well.
+    writeback: SpinLock<()>,
+}
+
+impl<D: BlockDevice> BufferCache<D> {
+    /// Creates a cache holding `capacity` blocks of the given device.
+    pub fn new(device: D, capacity: usize) -> Self {
+        assert!(capacity > 0, "Cache capacity must be positive");
+        BufferCache {
+            device,
+            inner: SpinLock::new(BufferCacheInner {
+                index: BlockIndex::new(capacity),
+                list: LruList::new(capacity),
+                buffers: vec![None; capacity],
+                free: (0..capacity).rev().collect(),
+            }),
+            writeback: SpinLock::new(()),
+        }
+    }
+
+    /// Number of blocks currently cached.
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.buffers.len() - inner.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Looks up the buffer of a block, inserting one on a miss.
+    /// The new buffer is filled with `init`, or from the device without it, before it is published.
+    fn lookup(&self, block_id: u32, init: Option<&[u8; BLOCK_SIZE]>) -> Result<(Arc<BufferHead>, bool)> {
+        if let Some(bh) = self.cached(&mut self.inner.lock(), block_id) {
+            return Ok((bh, true));
+        }
+
+        // The device is read without holding the cache lock,
+        // so another thread may have inserted the block in the meantime.
+        let mut buf = [0u8; BLOCK_SIZE];
+        let data = match init {
+            Some(data) => data,
+            None => {
+                self.device.read_block(block_id, &mut buf)?;
+                &buf
+            }
+        };
+        let bh = Arc::new(BufferHead::new(block_id));
+        *bh.lock() = *data;
+        self.publish(bh)
+    }
+
+    /// Returns the cached buffer of a block, marking it as recently used.
+    fn cached(&self, inner: &mut BufferCacheInner, block_id: u32) -> Option<Arc<BufferHead>> {
+        let slot = inner.index.get(block_id)?;
+        inner.list.touch(slot);
+        inner.buffers[slot].clone()
+    }
+
+    /// Inserts a filled buffer, unless its block was cached in the meantime.
+    /// Returns the cached buffer, and whether it was cached already.
+    fn publish(&self, bh: Arc<BufferHead>) -> Result<(Arc<BufferHead>, bool)> {
+        loop {
+            let dirty = {
+                let mut inner = self.inner.lock();
+                if let Some(cached) = self.cached(&mut inner, bh.block_id()) {
+                    return Ok((cached, true));
+                }
+                match self.free_slot(&mut inner) {
+                    Ok(slot) => {
+                        inner.index.insert(bh.block_id(), slot);
+                        inner.list.push_front(slot);
+                        inner.buffers[slot] = Some(Arc::clone(&bh));
+                        return Ok((bh, false));
+                    }
+                    Err(dirty) => dirty,
+                }
+            };
+            // Written back unlocked, then evicted once clean.
+            self.write_buffers(vec![dirty])?;
+        }
+    }
+
+    /// Frees a slot: a free one, or that of the least recently used buffer nobody else references,
+    /// which is evicted if clean. A dirty one is returned instead, to be written back first.
+    /// Adds a slot if every buffer is in use.
+    fn free_slot(&self, inner: &mut BufferCacheInner) -> core::result::Result<usize, Arc<BufferHead>> {
+        if let Some(slot) = inner.free.pop() {
+            return Ok(slot);
+        }
+        let mut slot = inner.list.tail();
+        while slot != NIL {
+            let bh = inner.buffers[slot].as_ref().unwrap();
+            if Arc::strong_count(bh) == 1 {
+                if bh.is_dirty() {
+                    return Err(Arc::clone(bh));
+                }
+                let block_id = bh.block_id();
+                inner.index.remove(block_id, slot);
+                inner.list.unlink(slot);
+                inner.buffers[slot] = None;
+                return Ok(slot);
+            }
+            slot = inner.list.prev(slot);
+        }
+
+        let slot = inner.index.add_slot();
+        inner.list.add_slot();
+        inner.buffers.push(None);
+        Ok(slot)
+    }
+
+    /// Writes back the dirty buffers of the given blocks, or all of them.
+    fn write_back(&self, block_ids: Option<&[u32]>) -> Result<()> {
+        let dirty: Vec<Arc<BufferHead>> = {
+            let inner = self.inner.lock();
+            match block_ids {
+                Some(block_ids) => block_ids
+                    .iter()
+                    .filter_map(|&block_id| inner.buffers[inner.index.get(block_id)?].clone())
+                    .collect(),
+                None => inner.buffers.iter().flatten().cloned().collect(),
+            }
+        };
+        self.write_buffers(dirty)
+    }
+
+    /// Writes the buffers still dirty to the device, merging consecutive blocks into single transfers.
+    /// Called without the cache lock: buffers are cleaned before they are copied,
+    /// so modifications made meanwhile leave them dirty.
+    fn write_buffers(&self, mut dirty: Vec<Arc<BufferHead>>) -> Result<()> {
+        let _writeback = self.writeback.lock();
+        dirty.retain(|bh| bh.is_dirty());
+        dirty.sort_unstable_by_key(|bh| bh.block_id());
+        dirty.dedup_by_key(|bh| bh.block_id());
+        let block_ids: Vec<u32> = dirty.iter().map(|bh| bh.block_id()).collect();
+
+        let mut done = 0;
+        let mut buf = Vec::new();
+        for (start, count) in block_runs(&block_ids) {
+            let run = &dirty[done..done + count];
+            buf.clear();
+            for bh in run {
+                bh.clear_dirty();
+                buf.extend_from_slice(&bh.lock()[..]);
+            }
+            if let Err(e) = self.device.write_blocks(start, &buf) {
+                run.iter().for_each(|bh| bh.mark_dirty());
+                return Err(e);
+            }
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BufferCache<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.num_blocks()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        let (bh, _) = self.lookup(block_id, None)?;
+        buf.copy_from_slice(&bh.lock()[..]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        let (bh, cached) = self.lookup(block_id, Some(buf))?;
+        if cached {
+            bh.lock().copy_from_slice(buf);
+        }
+        bh.mark_dirty();
+        Ok(())
+    }
+
+    /// Cached blocks are copied, each run of missing ones is read from the device in one go.
+    fn read_blocks(&self, start_block_id: u32, buf: &mut [u8]) -> Result<()> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::InvalidArgument);
+        }
+        let mut missing = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+                match self.cached(&mut inner, start_block_id + i as u32) {
+                    Some(bh) => block.copy_from_slice(&bh.lock()[..]),
+                    None => missing.push(start_block_id + i as u32),
+                }
+            }
+        }
+        for (start, count) in block_runs(&missing) {
+            let offset = (start - start_block_id) as usize * BLOCK_SIZE;
+            let run = &mut buf[offset..offset + count * BLOCK_SIZE];
+            self.device.read_blocks(start, run)?;
+            for (i, block) in run.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+                let bh = Arc::new(BufferHead::new(start + i as u32));
+                bh.lock().copy_from_slice(block);
+                // Cached in the meantime, the buffer may be newer than the device.
+                if let (bh, true) = self.publish(bh)? {
+                    block.copy_from_slice(&bh.lock()[..]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.write_back(None)?;
+        self.device.flush()
+    }
+
+    fn flush_blocks(&self, block_ids: &[u32]) -> Result<()> {
+        self.write_back(Some(block_ids))?;
+        self.device.flush_blocks(block_ids)
+    }
+
+    fn barrier(&self) -> Result<()> {
+        self.write_back(None)?;
+        self.device.barrier()
+    }
+
+    /// Written through to the device, the cached buffer is kept clean.
+    fn write_block_fua(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        // Only cached once written, a failed write must not leave a zeroed buffer behind.
+        self.device.write_block_fua(block_id, buf)?;
+        let (bh, cached) = self.lookup(block_id, Some(buf))?;
+        if cached {
+            bh.lock().copy_from_slice(buf);
+            bh.clear_dirty();
+        }
+        Ok(())
+    }
+
+    fn read_block_uncached(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.device.read_block_uncached(block_id, buf)
+    }
+
+    fn write_block_uncached(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.device.write_block_uncached(block_id, buf)
+    }
+
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<()> {
+        let mut inner = self.inner.lock();
+        for block_id in start_block_id..start_block_id + count {
+            let Some(slot) = inner.index.get(block_id) else {
+                continue;
+            };
+            let bh = inner.buffers[slot].as_ref().unwrap();
+            bh.clear_dirty();
+            // Buffers still in use stay, but are no longer written back.
+            if Arc::strong_count(bh) == 1 {
+                inner.index.remove(block_id, slot);
+                inner.list.unlink(slot);
+                inner.buffers[slot] = None;
+                inner.free.push(slot);
+            }
+        }
+        drop(inner);
+        self.device.discard(start_block_id, count)
+    }
+
+    fn readahead(&self, block_ids: &[u32]) -> Result<()> {
+        let missing: Vec<u32> = {
+            let inner = self.inner.lock();
+            block_ids.iter().copied().filter(|&id| inner.index.get(id).is_none()).collect()
+        };
+        let mut buf = Vec::new();
+        for (start, count) in block_runs(&missing) {
+            buf.resize(count * BLOCK_SIZE, 0);
+            self.device.read_blocks(start, &mut buf)?;
+            for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+                // Blocks cached in the meantime are kept.
+                let bh = Arc::new(BufferHead::new(start + i as u32));
+                bh.lock().copy_from_slice(block);
+                self.publish(bh)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_block(&self, block_id: u32) -> Result<Arc<BufferHead>> {
+        let (bh, _) = self.lookup(block_id, None)?;
+        Ok(bh)
+    }
+
+    fn new_block(&self, block_id: u32) -> Result<Arc<BufferHead>> {
+        let (bh, cached) = self.lookup(block_id, Some(&[0u8; BLOCK_SIZE]))?;
+        if cached {
+            bh.lock().fill(0);
+        }
+        bh.mark_dirty();
+        Ok(bh)
+    }
+
+    fn put_block(&self, _bh: Arc<BufferHead>) -> Result<()> {
+        // Dirty buffers stay in the cache until flushed or evicted.
+        Ok(())
+    }
+}
@@ -2,16 +2,44 @@
 //! This design efficiently decouples the cache logic from the underlying block device,
 //! allowing for flexible caching strategies.
 
+use alloc::vec::Vec;
+
+use crate::caches::block_runs;
 use crate::{BlockDevice, Error, Result, BLOCK_SIZE};
 
 pub trait Cache: Send + Sync {
     fn write_cache(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()>;
     
     fn read_cache(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()>;
+
+    /// Caches a block just read from the device, which is therefore clean.
+    /// Defaults to `write_cache`, for implementations that do not track clean blocks.
+    fn fill_cache(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.write_cache(block_id, buf)
+    }
     
+    /// Whether the block is cached, without counting as an access.
+    /// Defaults to a `read_cache` into a scratch buffer.
+    fn contains(&self, block_id: u32) -> bool {
+        let mut buf = [0u8; BLOCK_SIZE];
+        self.read_cache(block_id, &mut buf).is_ok()
+    }
+
     fn flush(&self, device: &impl BlockDevice) -> Result<()>;
+
+    /// Writes back the given blocks if they are cached and dirty, leaving the others cached.
+    /// Defaults to `flush`, which is correct but writes back everything.
+    fn flush_blocks(&self, device: &impl BlockDevice, block_ids: &[u32]) -> Result<()> {
+        self.flush(device)
+    }
     
     fn evict(&self, device: &impl BlockDevice, block_id: u32) -> Result<()>;
+
+    /// Drops a discarded block without writing it back.
+    /// Defaults to keeping it, which is correct but may write the block again later.
+    fn invalidate(&self, block_id: u32) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub struct Cached<D: BlockDevice, C: Cache> {
@@ -27,6 +55,18 @@ where
     pub fn new(device: D, cache: C) -> Self {
         Cached { device, cache }
     }
+
+    /// Caches a block just read from the device, evicting another one if needed.
+    fn fill(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        match self.cache.fill_cache(block_id, buf) {
+            Ok(_) => Ok(()),
+            Err(Error::CacheEvict(evicted_block_id)) => {
+                self.cache.evict(&self.device, evicted_block_id)?;
+                self.cache.fill_cache(block_id, buf)
+            },
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl<D, C> BlockDevice for Cached<D, C>
@@ -42,22 +82,16 @@ where
         self.device.num_blocks()
     }
 
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
     fn read_block(&self, block_id: u32, buf: &mut [u8; crate::BLOCK_SIZE]) -> Result<()> {
         match self.cache.read_cache(block_id, buf) {
             Ok(_) => Ok(()),
             Err(Error::CacheMiss) => {
                 self.device.read_block(block_id, buf)?;
-                match self.cache.write_cache(block_id, buf) {
-                    Ok(_) => {
-                        ;
-                    },
-                    Err(Error::CacheEvict(evicted_block_id)) => {
-                        self.cache.evict(&self.device, evicted_block_id)?;
-                        self.cache.write_cache(block_id, buf)?;
-                    },
-                    Err(e) => return Err(e),
-                }
-                Ok(())
+                self.fill(block_id, buf)
             },
             Err(e) => Err(e),
         }
@@ -84,4 +118,81 @@ where
         self.cache.flush(&self.device)?;
         Ok(())
     }
+
+    fn flush_blocks(&self, block_ids: &[u32]) -> Result<()> {
+        self.cache.flush_blocks(&self.device, block_ids)?;
+        self.device.flush_blocks(block_ids)
+    }
+
+    fn read_blocks(&self, start_block_id: u32, buf: &mut [u8]) -> Result<()> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::InvalidArgument);
+        }
+        let num_blocks = buf.len() / BLOCK_SIZE;
+        let mut i = 0;
+        while i < num_blocks {
+            let block = &mut buf[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE];
+            match self.cache.read_cache(start_block_id + i as u32, block.try_into().unwrap()) {
+                Ok(()) => {
+                    i += 1;
+                    continue;
+                },
+                Err(Error::CacheMiss) => {},
+                Err(e) => return Err(e),
+            }
+            // Fetch the whole run of missing blocks at once.
+            let mut end = i + 1;
+            while end < num_blocks && !self.cache.contains(start_block_id + end as u32) {
+                end += 1;
+            }
+            self.device.read_blocks(start_block_id + i as u32, &mut buf[i * BLOCK_SIZE..end * BLOCK_SIZE])?;
+            for j in i..end {
+                let block = &buf[j * BLOCK_SIZE..(j + 1) * BLOCK_SIZE];
+                self.fill(start_block_id + j as u32, block.try_into().unwrap())?;
+            }
+            i = end;
+        }
+        Ok(())
+    }
+
+    /// Everything cached is written back, so that it precedes later writes.
+    fn barrier(&self) -> Result<()> {
+        self.cache.flush(&self.device)?;
+        self.device.barrier()
+    }
+
+    /// Written through to the device, the cached copy is kept clean.
+    fn write_block_fua(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.cache.invalidate(block_id)?;
+        self.device.write_block_fua(block_id, buf)?;
+        self.fill(block_id, buf)
+    }
+
+    fn read_block_uncached(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.device.read_block_uncached(block_id, buf)
+    }
+
+    fn write_block_uncached(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.device.write_block_uncached(block_id, buf)
+    }
+
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<()> {
+        for block_id in start_block_id..start_block_id + count {
+            self.cache.invalidate(block_id)?;
+        }
+        self.device.discard(start_block_id, count)
+    }
+
+    fn readahead(&self, block_ids: &[u32]) -> Result<()> {
+        let missing: Vec<u32> = block_ids.iter().copied().filter(|&id| !self.cache.contains(id)).collect();
+        let mut buf = Vec::new();
+        for (start, count) in block_runs(&missing) {
+            buf.resize(count * BLOCK_SIZE, 0);
+            self.device.read_blocks(start, &mut buf)?;
+            for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+                self.fill(start + i as u32, block.try_into().unwrap())?;
+            }
+        }
+        Ok(())
+    }
 }
\ No newline at end of file
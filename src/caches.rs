@@ -0,0 +1,468 @@
+//! Built-in implementations of the `Cache` trait.
+//! - `LruCache`: evicts the least recently used block.
+//! - `ClockCache`: CLOCK (second chance) approximation of LRU, cheaper on hits.
+//!
+//! Both keep a fixed number of block buffers allocated up front, indexed by a hash table on
+//! block IDs, so lookups, insertions and evictions are O(1).
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::sync::SpinLock;
+use crate::{BlockDevice, Cache, Error, Result, BLOCK_SIZE};
+
+pub(crate) const NIL: usize = usize::MAX;
+
+/// Hash index from block IDs to cache slots, chained through the slots.
+pub(crate) struct BlockIndex {
+    buckets: Vec<usize>,
+    /// Next slot in the same bucket, for every slot.
+    chain: Vec<usize>,
+    /// Block ID held by every slot.
+    keys: Vec<u32>,
+    shift: u32,
+}
+
+impl BlockIndex {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let num_buckets = (capacity * 2).next_power_of_two().max(2);
+        Self {
+            buckets: vec![NIL; num_buckets],
+            chain: vec![NIL; capacity],
+            keys: vec![0; capacity],
+            shift: 32 - num_buckets.trailing_zeros(),
+        }
+    }
+
+    fn bucket(&self, block_id: u32) -> usize {
+        // Fibonacci hashing, consecutive block IDs spread over the buckets.
+        (block_id.wrapping_mul(0x9E37_79B9) >> self.shift) as usize
+    }
+
+    pub(crate) fn get(&self, block_id: u32) -> Option<usize> {
+        let mut slot = self.buckets[self.bucket(block_id)];
+        while slot != NIL {
+            if self.keys[slot] == block_id {
+                return Some(slot);
+            }
+            slot = self.chain[slot];
+        }
+        None
+    }
+
+    pub(crate) fn insert(&mut self, block_id: u32, slot: usize) {
+        let bucket = self.bucket(block_id);
+        self.keys[slot] = block_id;
+        self.chain[slot] = self.buckets[bucket];
+        self.buckets[bucket] = slot;
+    }
+
+    pub(crate) fn remove(&mut self, block_id: u32, slot: usize) {
+        let bucket = self.bucket(block_id);
+        if self.buckets[bucket] == slot {
+            self.buckets[bucket] = self.chain[slot];
+        } else {
+            let mut prev = self.buckets[bucket];
+            while self.chain[prev] != slot {
+                prev = self.chain[prev];
+            }
+            self.chain[prev] = self.chain[slot];
+        }
+        self.chain[slot] = NIL;
+    }
+
+    /// Appends a slot, for caches allowed to grow beyond their nominal capacity.
+    /// Buckets are not rehashed, so chains just get longer.
+    pub(crate) fn add_slot(&mut self) -> usize {
+        self.chain.push(NIL);
+        self.keys.push(0);
+        self.chain.len() - 1
+    }
+
+    pub(crate) fn key(&self, slot: usize) -> u32 {
+        self.keys[slot]
+    }
+}
+
+/// Doubly linked recency list over cache slots, most recently used first.
+pub(crate) struct LruList {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    head: usize,
+    tail: usize,
+}
+
+impl LruList {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            prev: vec![NIL; capacity],
+            next: vec![NIL; capacity],
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    pub(crate) fn add_slot(&mut self) {
+        self.prev.push(NIL);
+        self.next.push(NIL);
+    }
+
+    /// Least recently used slot.
+    pub(crate) fn tail(&self) -> usize {
+        self.tail
+    }
+
+    /// Slot used just before the given one.
+    pub(crate) fn prev(&self, slot: usize) -> usize {
+        self.prev[slot]
+    }
+
+    pub(crate) fn unlink(&mut self, slot: usize) {
+        let (prev, next) = (self.prev[slot], self.next[slot]);
+        if prev != NIL {
+            self.next[prev] = next;
+        } else {
+            self.head = next;
+        }
+        if next != NIL {
+            self.prev[next] = prev;
+        } else {
+            self.tail = prev;
+        }
+    }
+
+    pub(crate) fn push_front(&mut self, slot: usize) {
+        self.prev[slot] = NIL;
+        self.next[slot] = self.head;
+        if self.head != NIL {
+            self.prev[self.head] = slot;
+        } else {
+            self.tail = slot;
+        }
+        self.head = slot;
+    }
+
+    pub(crate) fn touch(&mut self, slot: usize) {
+        self.unlink(slot);
+        self.push_front(slot);
+    }
+}
+
+/// Splits block IDs into runs of consecutive blocks, as `(start_block_id, count)`.
+pub(crate) fn block_runs(block_ids: &[u32]) -> Vec<(u32, usize)> {
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    for &block_id in block_ids {
+        match runs.last_mut() {
+            Some((start, count)) if *start as usize + *count == block_id as usize => *count += 1,
+            _ => runs.push((block_id, 1)),
+        }
+    }
+    runs
+}
+
+/// Block buffers and bookkeeping shared by the cache implementations.
+struct Slots {
+    index: BlockIndex,
+    data: Vec<[u8; BLOCK_SIZE]>,
+    dirty: Vec<bool>,
+    free: Vec<usize>,
+}
+
+impl Slots {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Cache capacity must be positive");
+        Self {
+            index: BlockIndex::new(capacity),
+            data: vec![[0u8; BLOCK_SIZE]; capacity],
+            dirty: vec![false; capacity],
+            // Reversed so that slots are handed out in order.
+            free: (0..capacity).rev().collect(),
+        }
+    }
+
+    /// Claims a free slot for the block, if any is left.
+    fn insert(&mut self, block_id: u32, buf: &[u8; BLOCK_SIZE], dirty: bool) -> Option<usize> {
+        let slot = self.free.pop()?;
+        self.data[slot].copy_from_slice(buf);
+        self.dirty[slot] = dirty;
+        self.index.insert(block_id, slot);
+        Some(slot)
+    }
+
+    /// Writes the slot back if dirty and releases it.
+    fn evict(&mut self, device: &impl BlockDevice, block_id: u32) -> Result<usize> {
+        let slot = self.index.get(block_id).ok_or(Error::CacheMiss)?;
+        if self.dirty[slot] {
+            device.write_block(block_id, &self.data[slot])?;
+            self.dirty[slot] = false;
+        }
+        self.index.remove(block_id, slot);
+        self.free.push(slot);
+        Ok(slot)
+    }
+
+    /// Drops the block without writing it back, returning the released slot if it was cached.
+    fn invalidate(&mut self, block_id: u32) -> Option<usize> {
+        let slot = self.index.get(block_id)?;
+        self.dirty[slot] = false;
+        self.index.remove(block_id, slot);
+        self.free.push(slot);
+        Some(slot)
+    }
+
+    /// Writes back all dirty slots, merging consecutive blocks into single transfers.
+    fn flush(&mut self, device: &impl BlockDevice) -> Result<()> {
+        let dirty: Vec<(u32, usize)> = (0..self.data.len())
+            .filter(|&slot| self.dirty[slot])
+            .map(|slot| (self.index.keys[slot], slot))
+            .collect();
+        self.write_back(device, dirty)
+    }
+
+    /// Writes back the given blocks that are cached and dirty.
+    fn flush_blocks(&mut self, device: &impl BlockDevice, block_ids: &[u32]) -> Result<()> {
+        let dirty: Vec<(u32, usize)> = block_ids
+            .iter()
+            .filter_map(|&block_id| Some((block_id, self.index.get(block_id)?)))
+            .filter(|&(_, slot)| self.dirty[slot])
+            .collect();
+        self.write_back(device, dirty)
+    }
+
+    fn write_back(&mut self, device: &impl BlockDevice, mut dirty: Vec<(u32, usize)>) -> Result<()> {
+        dirty.sort_unstable();
+        dirty.dedup();
+        let block_ids: Vec<u32> = dirty.iter().map(|&(block_id, _)| block_id).collect();
+
+        let mut done = 0;
+        let mut buf = Vec::new();
+        for (start, count) in block_runs(&block_ids) {
+            let run = &dirty[done..done + count];
+            buf.clear();
+            for &(_, slot) in run {
+                buf.extend_from_slice(&self.data[slot]);
+            }
+            device.write_blocks(start, &buf)?;
+            for &(_, slot) in run {
+                self.dirty[slot] = false;
+            }
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+struct LruInner {
+    slots: Slots,
+    list: LruList,
+}
+
+impl LruInner {
+    fn insert(&mut self, block_id: u32, buf: &[u8; BLOCK_SIZE], dirty: bool) -> Result<()> {
+        match self.slots.insert(block_id, buf, dirty) {
+            Some(slot) => {
+                self.list.push_front(slot);
+                Ok(())
+            },
+            None => Err(Error::CacheEvict(self.slots.index.key(self.list.tail()))),
+        }
+    }
+}
+
+/// Least recently used block cache.
+pub struct LruCache {
+    inner: SpinLock<LruInner>,
+}
+
+impl LruCache {
+    /// Creates a cache holding up to `capacity` blocks.
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            inner: SpinLock::new(LruInner {
+                slots: Slots::new(capacity),
+                list: LruList::new(capacity),
+            }),
+        }
+    }
+
+    /// Number of blocks currently cached.
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.slots.data.len() - inner.slots.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Cache for LruCache {
+    fn write_cache(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        match inner.slots.index.get(block_id) {
+            Some(slot) => {
+                inner.slots.data[slot].copy_from_slice(buf);
+                inner.slots.dirty[slot] = true;
+                inner.list.touch(slot);
+                Ok(())
+            },
+            None => inner.insert(block_id, buf, true),
+        }
+    }
+
+    fn fill_cache(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        match inner.slots.index.get(block_id) {
+            // Never overwrite cached data with what may be an older copy from the device.
+            Some(_) => Ok(()),
+            None => inner.insert(block_id, buf, false),
+        }
+    }
+
+    fn read_cache(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let slot = inner.slots.index.get(block_id).ok_or(Error::CacheMiss)?;
+        buf.copy_from_slice(&inner.slots.data[slot]);
+        inner.list.touch(slot);
+        Ok(())
+    }
+
+    fn contains(&self, block_id: u32) -> bool {
+        self.inner.lock().slots.index.get(block_id).is_some()
+    }
+
+    fn flush(&self, device: &impl BlockDevice) -> Result<()> {
+        self.inner.lock().slots.flush(device)
+    }
+
+    fn flush_blocks(&self, device: &impl BlockDevice, block_ids: &[u32]) -> Result<()> {
+        self.inner.lock().slots.flush_blocks(device, block_ids)
+    }
+
+    fn evict(&self, device: &impl BlockDevice, block_id: u32) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let slot = inner.slots.evict(device, block_id)?;
+        inner.list.unlink(slot);
+        Ok(())
+    }
+
+    fn invalidate(&self, block_id: u32) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if let Some(slot) = inner.slots.invalidate(block_id) {
+            inner.list.unlink(slot);
+        }
+        Ok(())
+    }
+}
+
+struct ClockInner {
+    slots: Slots,
+    referenced: Vec<bool>,
+    hand: usize,
+}
+
+impl ClockInner {
+    fn insert(&mut self, block_id: u32, buf: &[u8; BLOCK_SIZE], dirty: bool) -> Result<()> {
+        if let Some(slot) = self.slots.insert(block_id, buf, dirty) {
+            self.referenced[slot] = true;
+            return Ok(());
+        }
+        // All slots are in use: sweep, giving referenced blocks a second chance.
+        loop {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.referenced.len();
+            if self.referenced[slot] {
+                self.referenced[slot] = false;
+            } else {
+                return Err(Error::CacheEvict(self.slots.index.key(slot)));
+            }
+        }
+    }
+}
+
+/// CLOCK (second chance) block cache.
+/// Hits only set a reference bit, instead of reordering a list as LRU does.
+pub struct ClockCache {
+    inner: SpinLock<ClockInner>,
+}
+
+impl ClockCache {
+    /// Creates a cache holding up to `capacity` blocks.
+    pub fn new(capacity: usize) -> Self {
+        ClockCache {
+            inner: SpinLock::new(ClockInner {
+                slots: Slots::new(capacity),
+                referenced: vec![false; capacity],
+                hand: 0,
+            }),
+        }
+    }
+
+    /// Number of blocks currently cached.
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.slots.data.len() - inner.slots.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Cache for ClockCache {
+    fn write_cache(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        match inner.slots.index.get(block_id) {
+            Some(slot) => {
+                inner.slots.data[slot].copy_from_slice(buf);
+                inner.slots.dirty[slot] = true;
+                inner.referenced[slot] = true;
+                Ok(())
+            },
+            None => inner.insert(block_id, buf, true),
+        }
+    }
+
+    fn fill_cache(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        match inner.slots.index.get(block_id) {
+            Some(_) => Ok(()),
+            None => inner.insert(block_id, buf, false),
+        }
+    }
+
+    fn read_cache(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let slot = inner.slots.index.get(block_id).ok_or(Error::CacheMiss)?;
+        buf.copy_from_slice(&inner.slots.data[slot]);
+        inner.referenced[slot] = true;
+        Ok(())
+    }
+
+    fn contains(&self, block_id: u32) -> bool {
+        self.inner.lock().slots.index.get(block_id).is_some()
+    }
+
+    fn flush(&self, device: &impl BlockDevice) -> Result<()> {
+        self.inner.lock().slots.flush(device)
+    }
+
+    fn flush_blocks(&self, device: &impl BlockDevice, block_ids: &[u32]) -> Result<()> {
+        self.inner.lock().slots.flush_blocks(device, block_ids)
+    }
+
+    fn evict(&self, device: &impl BlockDevice, block_id: u32) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let slot = inner.slots.evict(device, block_id)?;
+        inner.referenced[slot] = false;
+        Ok(())
+    }
+
+    fn invalidate(&self, block_id: u32) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if let Some(slot) = inner.slots.invalidate(block_id) {
+            inner.referenced[slot] = false;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+//! CRC32C (Castagnoli) checksums of on-disk metadata.
+//!
+//! With `RO_COMPAT_METADATA_CSUM` set in the superblock:
+//! - the superblock carries a checksum of its other fields,
+//! - each inode carries the low 16 bits of a checksum of its record in the inode table,
+//! - bitmap and directory blocks have their checksums in the checksum table,
+//!   a region after the inode table with one u32 entry per block of the file system.
+//!
+//! With `RO_COMPAT_DATA_CSUM` as well, data and indirect blocks have their checksums
+//! in the checksum table too, maintained by `fwrite` and verified by `fread`.
+//!
+//! Checksums are verified whenever the structure is read from the device,
+//! and a mismatch is reported as `FsError::ChecksumMismatch`.
+//! `scrub` verifies everything at once.
+
+use core::mem::{offset_of, size_of};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::bitmap::{allocated_inode_ids, bitmap_block_ids};
+use crate::error::FsError;
+use crate::{config::*, get_inode, read_superblock, BlockDevice, BufferHead, FileType, Inode, InodeExtra, Result, SuperBlock};
+
+/// Reflected CRC32C polynomial.
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            k += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32C of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+/// Extends the CRC32C `crc` of some bytes with the bytes in `data`.
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Whether the file system keeps metadata checksums.
+pub(crate) fn has_metadata_csum(superblock: &SuperBlock) -> bool {
+    superblock.has_ro_compat(RO_COMPAT_METADATA_CSUM)
+}
+
+/// Whether the file system keeps checksums of file contents.
+pub(crate) fn has_data_csum(superblock: &SuperBlock) -> bool {
+    superblock.has_ro_compat(RO_COMPAT_METADATA_CSUM | RO_COMPAT_DATA_CSUM)
+}
+
+/// Checksum of the superblock, covering every field but `checksum` itself.
+/// Images of revision 0 predate the fields after it, their checksum only covers those before.
+pub(crate) fn superblock_checksum(superblock: &SuperBlock) -> u32 {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(superblock as *const SuperBlock as *const u8, size_of::<SuperBlock>())
+    };
+    let offset = offset_of!(SuperBlock, checksum);
+    let crc = crc32c(&bytes[..offset]);
+    if superblock.rev_level == 0 {
+        return crc;
+    }
+    crc32c_append(crc, &bytes[offset + size_of::<u32>()..])
+}
+
+/// Offset of the inode checksum within an inode record.
+const INODE_CSUM_OFFSET: usize = offset_of!(Inode, checksum);
+/// Offset of the high half of the inode checksum within a record larger than `INODE_SIZE`.
+const INODE_CSUM_HI_OFFSET: usize = INODE_SIZE + offset_of!(InodeExtra, checksum_hi);
+
+/// Whether the extra fields of an inode record include the high half of its checksum.
+fn has_checksum_hi(record: &[u8]) -> bool {
+    record.len() > INODE_SIZE
+        && u16::from_le_bytes([record[INODE_SIZE], record[INODE_SIZE + 1]]) as usize >= offset_of!(InodeExtra, checksum_hi) + 2
+}
+
+/// Checksum of an inode record, computed as if its checksum fields were zero.
+fn inode_checksum(record: &[u8]) -> u32 {
+    let crc = crc32c(&record[..INODE_CSUM_OFFSET]);
+    let crc = crc32c_append(crc, &[0, 0]);
+    if !has_checksum_hi(record) {
+        return crc32c_append(crc, &record[INODE_CSUM_OFFSET + 2..]);
+    }
+    let crc = crc32c_append(crc, &record[INODE_CSUM_OFFSET + 2..INODE_CSUM_HI_OFFSET]);
+    let crc = crc32c_append(crc, &[0, 0]);
+    crc32c_append(crc, &record[INODE_CSUM_HI_OFFSET + 2..])
+}
+
+/// Stores the checksum of the inode record at `offset` of the buffer.
+/// Records with room for it get the high half as well.
+pub(crate) fn seal_inode(bh: &BufferHead, offset: usize, inode_size: usize) {
+    let mut data = bh.lock();
+    let record = &mut data[offset..offset + inode_size];
+    let csum = inode_checksum(record);
+    record[INODE_CSUM_OFFSET..INODE_CSUM_OFFSET + 2].copy_from_slice(&(csum as u16).to_le_bytes());
+    if has_checksum_hi(record) {
+        record[INODE_CSUM_HI_OFFSET..INODE_CSUM_HI_OFFSET + 2].copy_from_slice(&((csum >> 16) as u16).to_le_bytes());
+    }
+}
+
+/// Verifies the inode record at `offset` of the buffer.
+/// Records never written since the inode table was zeroed have no checksum.
+pub(crate) fn verify_inode(bh: &BufferHead, offset: usize, inode_size: usize) -> Result<()> {
+    let data = bh.lock();
+    let record = &data[offset..offset + inode_size];
+    let mut stored = u16::from_le_bytes([record[INODE_CSUM_OFFSET], record[INODE_CSUM_OFFSET + 1]]) as u32;
+    let mut csum = inode_checksum(record);
+    if has_checksum_hi(record) {
+        stored |= (u16::from_le_bytes([record[INODE_CSUM_HI_OFFSET], record[INODE_CSUM_HI_OFFSET + 1]]) as u32) << 16;
+    } else {
+        csum &= 0xffff;
+    }
+    if stored != csum && record.iter().any(|&b| b != 0) {
+        return Err(FsError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Block and byte offset of the checksum table entry of a block.
+fn csum_entry(superblock: &SuperBlock, block_id: u32) -> Result<(u32, usize)> {
+    if block_id >= superblock.num_blocks {
+        return Err(FsError::OutOfBounds);
+    }
+    let table_block = csum_table_block_id(superblock, block_id);
+    let offset = (block_id as usize % CSUMS_PER_BLOCK) * size_of::<u32>();
+    Ok((table_block, offset))
+}
+
+/// Block of the checksum table holding the checksum of a block.
+pub(crate) fn csum_table_block_id(superblock: &SuperBlock, block_id: u32) -> u32 {
+    superblock.csum_table_start + block_id / CSUMS_PER_BLOCK as u32
+}
+
+/// Checksum recorded for a block in the checksum table.
+fn stored_checksum(device: &impl BlockDevice, superblock: &SuperBlock, block_id: u32) -> Result<u32> {
+    let (table_block, offset) = csum_entry(superblock, block_id)?;
+    Ok(device.get_block(table_block)?.read_obj::<u32>(offset))
+}
+
+/// Records the checksum of a block's contents in the checksum table.
+pub(crate) fn set_block_checksum(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    block_id: u32,
+    data: &[u8; BLOCK_SIZE],
+) -> Result<()> {
+    let (table_block, offset) = csum_entry(superblock, block_id)?;
+    let bh = device.get_block(table_block)?;
+    bh.write_obj(offset, &crc32c(data));
+    device.put_block(bh)
+}
+
+/// Stores a block of the checksum table for `fsync`, once the blocks in `synced`, sorted, are on disk.
+/// Their entries are stored as cached. The table block is shared with other files, whose new
+/// entries are only stored if their blocks reached the disk as well, so that no entry ever
+/// describes contents the disk does not hold yet. The cached table block keeps all of them.
+pub(crate) fn sync_csum_table_block(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    table_block: u32,
+    synced: &[u32],
+) -> Result<()> {
+    let entry = |data: &[u8; BLOCK_SIZE], i: usize| u32::from_ne_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+    let cached = device.get_block(table_block)?.read_obj::<[u8; BLOCK_SIZE]>(0);
+    let mut stored = [0u8; BLOCK_SIZE];
+    device.read_block_uncached(table_block, &mut stored)?;
+    let first_block_id = (table_block - superblock.csum_table_start) * CSUMS_PER_BLOCK as u32;
+    let mut changed = false;
+    let mut buf = [0u8; BLOCK_SIZE];
+    for i in 0..CSUMS_PER_BLOCK {
+        let csum = entry(&cached, i);
+        if csum == entry(&stored, i) {
+            continue;
+        }
+        let block_id = first_block_id + i as u32;
+        if synced.binary_search(&block_id).is_err() {
+            device.read_block_uncached(block_id, &mut buf)?;
+            if crc32c(&buf) != csum {
+                continue;
+            }
+        }
+        stored[i * 4..i * 4 + 4].copy_from_slice(&csum.to_ne_bytes());
+        changed = true;
+    }
+    if changed {
+        device.write_block_uncached(table_block, &stored)?;
+    }
+    Ok(())
+}
+
+/// Records the checksums of the freshly zeroed bitmap blocks of a new file system.
+pub(crate) fn init_bitmap_checksums(device: &impl BlockDevice, superblock: &SuperBlock) -> Result<()> {
+    if !has_metadata_csum(superblock) {
+        return Ok(());
+    }
+    let zeroes = [0u8; BLOCK_SIZE];
+    for block_id in bitmap_block_ids(superblock) {
+        set_block_checksum(device, superblock, block_id, &zeroes)?;
+    }
+    Ok(())
+}
+
+/// Gets a bitmap or directory block, verifying it against the checksum table.
+pub(crate) fn get_meta_block(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    block_id: u32,
+) -> Result<Arc<BufferHead>> {
+    let bh = device.get_block(block_id)?;
+    if has_metadata_csum(superblock) && crc32c(&bh.lock()[..]) != stored_checksum(device, superblock, block_id)? {
+        return Err(FsError::ChecksumMismatch);
+    }
+    Ok(bh)
+}
+
+/// Puts back a bitmap or directory block, updating its checksum if it was modified.
+pub(crate) fn put_meta_block(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    bh: Arc<BufferHead>,
+) -> Result<()> {
+    if has_metadata_csum(superblock) && bh.is_dirty() {
+        // Copied out, the device must not be called with the buffer locked.
+        let data = bh.read_obj::<[u8; BLOCK_SIZE]>(0);
+        set_block_checksum(device, superblock, bh.block_id(), &data)?;
+    }
+    device.put_block(bh)
+}
+
+/// Verifies the contents of a data or indirect block read from the device.
+pub(crate) fn verify_data_block(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    block_id: u32,
+    data: &[u8],
+) -> Result<()> {
+    if has_data_csum(superblock) && crc32c(data) != stored_checksum(device, superblock, block_id)? {
+        return Err(FsError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Records the new contents of a data or indirect block.
+pub(crate) fn update_data_checksum(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    block_id: u32,
+    data: &[u8; BLOCK_SIZE],
+) -> Result<()> {
+    if has_data_csum(superblock) {
+        set_block_checksum(device, superblock, block_id, data)?;
+    }
+    Ok(())
+}
+
+/// A damaged structure found by `scrub`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrubError {
+    /// The superblock.
+    SuperBlock,
+    /// A block of the data or inode bitmap.
+    Bitmap { block_id: u32 },
+    /// The record of an inode in the inode table.
+    Inode { inode_id: u32 },
+    /// A directory, data or indirect block of an inode, or a block pointer outside of the data region.
+    Block { inode_id: u32, block_id: u32 },
+}
+
+/// Outcome of `scrub`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Allocated inodes verified.
+    pub inodes_checked: u32,
+    /// Bitmap, directory, data and indirect blocks verified.
+    pub blocks_checked: u32,
+    pub errors: Vec<ScrubError>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Inodes whose record or blocks are damaged, i.e. the affected files, in ascending order.
+    pub fn damaged_inodes(&self) -> Vec<u32> {
+        let mut inode_ids: Vec<u32> = self.errors.iter().filter_map(|e| match *e {
+            ScrubError::Inode { inode_id } | ScrubError::Block { inode_id, .. } => Some(inode_id),
+            _ => None,
+        }).collect();
+        inode_ids.sort_unstable();
+        inode_ids.dedup();
+        inode_ids
+    }
+}
+
+/// Verifies a block against the checksum table, returning whether it is intact.
+fn block_intact(device: &impl BlockDevice, superblock: &SuperBlock, block_id: u32) -> Result<bool> {
+    let data = device.get_block(block_id)?.read_obj::<[u8; BLOCK_SIZE]>(0);
+    Ok(crc32c(&data) == stored_checksum(device, superblock, block_id)?)
+}
+
+/// Verifies the blocks of an allocated inode, recording the damaged ones in the report.
+/// Contents of regular files are only verified with `RO_COMPAT_DATA_CSUM`.
+fn scrub_inode(device: &impl BlockDevice, superblock: &SuperBlock, inode: &Inode, report: &mut ScrubReport) -> Result<()> {
+    let verify_contents = match inode.ftype {
+        FileType::Directory => true,
+        FileType::Symlink if inode.is_slow_symlink() => true,
+        FileType::Regular => has_data_csum(superblock),
+        FileType::Symlink | FileType::Special => return Ok(()),
+    };
+    if inode.has_inline_data() {
+        // Covered by the inode checksum.
+        return Ok(());
+    }
+    let in_data_region = |block_id: u32| block_id >= superblock.data_start && block_id < superblock.num_blocks;
+    let check = |block_id: u32, verify: bool, report: &mut ScrubReport| -> Result<bool> {
+        let intact = in_data_region(block_id) && (!verify || {
+            report.blocks_checked += 1;
+            block_intact(device, superblock, block_id)?
+        });
+        if !intact {
+            report.errors.push(ScrubError::Block { inode_id: inode.id, block_id });
+        }
+        Ok(intact)
+    };
+
+    let block_ptrs = inode.get_block_ptrs()?;
+    for &block_id in block_ptrs.direct.iter().flatten() {
+        check(block_id, verify_contents, report)?;
+    }
+    if let Some(indirect_block_id) = block_ptrs.indirect
+        && check(indirect_block_id, has_data_csum(superblock), report)?
+    {
+        let ptrs = device.get_block(indirect_block_id)?.read_obj::<[u32; PTRS_PER_BLOCK]>(0);
+        for &block_id in ptrs.iter().filter(|&&block_id| block_id != 0) {
+            check(block_id, verify_contents, report)?;
+        }
+    }
+    Ok(())
+}
+
+/// Verifies every checksum of the file system: the superblock, the bitmaps, and the inodes
+/// and blocks of all allocated files. Damage is collected in the report, only device errors
+/// fail the scrub.
+/// Without `RO_COMPAT_METADATA_CSUM` there is nothing to verify.
+pub fn scrub(device: &impl BlockDevice, superblock: &SuperBlock) -> Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+    if !has_metadata_csum(superblock) {
+        return Ok(report);
+    }
+
+    match read_superblock(device) {
+        Ok(_) => {},
+        Err(e) if e.is_corruption() => report.errors.push(ScrubError::SuperBlock),
+        Err(e) => return Err(e),
+    }
+
+    for block_id in bitmap_block_ids(superblock) {
+        report.blocks_checked += 1;
+        if !block_intact(device, superblock, block_id)? {
+            report.errors.push(ScrubError::Bitmap { block_id });
+        }
+    }
+
+    for inode_id in allocated_inode_ids(device, superblock)? {
+        report.inodes_checked += 1;
+        match get_inode(device, superblock, inode_id) {
+            Ok(inode) => scrub_inode(device, superblock, &inode, &mut report)?,
+            Err(FsError::ChecksumMismatch) => report.errors.push(ScrubError::Inode { inode_id }),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
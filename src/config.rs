@@ -1,12 +1,27 @@
 pub const MAGIC: u32 = 0x4D554F4E; // "MUON" in ASCII
+pub const FS_STATE_CLEAN: u32 = 1; // Cleanly unmounted, or never mounted read-write
+pub const FS_STATE_DIRTY: u32 = 2; // Mounted read-write, or not cleanly unmounted
+pub const MUON_REV: u32 = 1; // On-disk format revision written by this driver, the first with feature masks
+pub const SUPPORTED_COMPAT: u32 = 0; // COMPAT_* features known to this driver
+pub const SUPPORTED_INCOMPAT: u32 = INCOMPAT_INODE_SIZE | INCOMPAT_INLINE_DATA | INCOMPAT_LONG_SYMLINK; // INCOMPAT_* features known to this driver
+pub const INCOMPAT_INODE_SIZE: u32 = 0x1; // Inode records larger than INODE_SIZE, see SuperBlock::inode_size
+pub const INCOMPAT_INLINE_DATA: u32 = 0x2; // Small files and directories stored in their inode, see SuperBlock::max_inline_size
+pub const INCOMPAT_LONG_SYMLINK: u32 = 0x4; // Symlink targets longer than MAX_PATH_LEN stored in data blocks, set by the first one
+pub const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_METADATA_CSUM | RO_COMPAT_DATA_CSUM | RO_COMPAT_BACKUP_SB; // RO_COMPAT_* features known to this driver
+pub const RO_COMPAT_METADATA_CSUM: u32 = 0x1; // CRC32C checksums on superblock, inodes, bitmap and directory blocks; only 16 bits for INODE_SIZE inodes
+pub const RO_COMPAT_DATA_CSUM: u32 = 0x2; // CRC32C checksums on data and indirect blocks, requires RO_COMPAT_METADATA_CSUM
+pub const RO_COMPAT_BACKUP_SB: u32 = 0x4; // Superblock copies at SuperBlock::backup_block_ids
 
 pub const BLOCK_SIZE: usize = 512;
 pub const SUPERBLOCK_ID: u32 = 0; // Block ID for the superblock
 pub const ROOT_INODE_ID: u32 = 1; // Inode ID for the root directory
 pub const MAX_FSIZE: usize = 1024 * 1024 * 1024; // 1 GiB
 pub const MAX_PATH_LEN: usize = 104;
+pub const MAX_SYMLINK_LEN: usize = 4095; // Longest symlink target, those over MAX_PATH_LEN are stored in data blocks
+pub const MAX_LABEL_LEN: usize = 32; // Volume label length, zero padded in the superblock
 pub const MAX_INODES: usize = 1024; // Maximum number of inodes
-pub const INODE_SIZE: usize = 128;  // Distance between inodes in the inode table
+pub const INODE_SIZE: usize = 128;  // Size of the base inode record, and default distance between inodes in the inode table
+pub const MAX_INODE_SIZE: usize = BLOCK_SIZE; // Largest inode record, records do not cross blocks
 
 pub const MAX_DIR_ENTRIES: usize = 128; // Maximum number of directory entries per directory
 pub const MAX_FILE_NAME_LEN: usize = 64 - 4; // DirEntry name length minus inode ID (4 bytes)
@@ -18,4 +33,8 @@ pub const DOTDOT_NAME: &[u8; 2] = b"..";
 pub const NUM_DIRECT_PTRS: usize = 12; // Number of direct pointers in an inode
 pub const NUM_INDIRECT_PTRS: usize = 1; // Number of indirect pointers in an inode
 pub const PTRS_PER_BLOCK: usize = BLOCK_SIZE / 4; // Number of pointers per block (assuming 32-bit pointers)
-pub const SYMLOOP_MAX: usize = 16; // Maximum number of symbolic link hops
\ No newline at end of file
+pub const BACKUP_SB_MIN_DATA_BLOCKS: u32 = 128; // Smaller data regions are not worth giving up blocks for superblock backups
+pub const CSUMS_PER_BLOCK: usize = BLOCK_SIZE / 4; // Number of u32 checksums per checksum table block
+pub const SYMLOOP_MAX: usize = 16; // Maximum number of symbolic link hops
+pub const READAHEAD_MIN_BLOCKS: usize = 4; // Read-ahead window on the first sequential read
+pub const READAHEAD_MAX_BLOCKS: usize = 32; // Default limit of the read-ahead window
\ No newline at end of file
@@ -0,0 +1,320 @@
+//! Ready-made block devices for hosted environments, available with the `std` feature.
+//! - `RamDevice`: a volatile disk kept in memory, handy for tests and for building images.
+//! - `FileDevice`: a disk image file on the host, accessed with positioned reads and writes.
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::RwLock;
+use std::vec;
+use std::vec::Vec;
+
+use crate::{BlockDevice, Error, Result, BLOCK_SIZE};
+
+/// Block device backed by memory.
+#[derive(Debug)]
+pub struct RamDevice {
+    data: RwLock<Vec<u8>>,
+    num_blocks: usize,
+}
+
+impl RamDevice {
+    /// Creates a zeroed device with the specified number of blocks.
+    pub fn new(num_blocks: usize) -> Self {
+        RamDevice {
+            data: RwLock::new(vec![0u8; num_blocks * BLOCK_SIZE]),
+            num_blocks,
+        }
+    }
+
+    /// Creates a device holding the given image.
+    /// A trailing partial block is ignored.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        let num_blocks = data.len() / BLOCK_SIZE;
+        RamDevice {
+            data: RwLock::new(data),
+            num_blocks,
+        }
+    }
+
+    /// Returns a copy of the whole device contents.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+
+    fn byte_range(&self, start_block_id: u32, len: usize) -> Result<Range<usize>> {
+        check_blocks(self.num_blocks, start_block_id, len)?;
+        let start = start_block_id as usize * BLOCK_SIZE;
+        Ok(start..start + len)
+    }
+}
+
+/// Checks that a multi-block transfer of `len` bytes fits on the device.
+fn check_blocks(num_blocks: usize, start_block_id: u32, len: usize) -> Result<()> {
+    if !len.is_multiple_of(BLOCK_SIZE) {
+        return Err(Error::InvalidArgument);
+    }
+    if start_block_id as usize + len / BLOCK_SIZE > num_blocks {
+        return Err(Error::InvalidBlockId);
+    }
+    Ok(())
+}
+
+impl BlockDevice for RamDevice {
+    fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        if block_id as usize >= self.num_blocks {
+            return Err(Error::InvalidBlockId);
+        }
+        let start = block_id as usize * BLOCK_SIZE;
+        let data = self.data.read().map_err(|_| Error::IoError)?;
+        buf.copy_from_slice(&data[start..start + BLOCK_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        if block_id as usize >= self.num_blocks {
+            return Err(Error::InvalidBlockId);
+        }
+        let start = block_id as usize * BLOCK_SIZE;
+        let mut data = self.data.write().map_err(|_| Error::IoError)?;
+        data[start..start + BLOCK_SIZE].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_blocks(&self, start_block_id: u32, buf: &mut [u8]) -> Result<()> {
+        let range = self.byte_range(start_block_id, buf.len())?;
+        let data = self.data.read().map_err(|_| Error::IoError)?;
+        buf.copy_from_slice(&data[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start_block_id: u32, buf: &[u8]) -> Result<()> {
+        let range = self.byte_range(start_block_id, buf.len())?;
+        let mut data = self.data.write().map_err(|_| Error::IoError)?;
+        data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Discarded blocks read back as zeroes.
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<()> {
+        let range = self.byte_range(start_block_id, count as usize * BLOCK_SIZE)?;
+        let mut data = self.data.write().map_err(|_| Error::IoError)?;
+        data[range].fill(0);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Data is already in memory.
+        Ok(())
+    }
+}
+
+/// Value of `O_DIRECT`, which differs between architectures.
+#[cfg(target_os = "linux")]
+#[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64"))]
+const O_DIRECT: i32 = 0o200000;
+#[cfg(target_os = "linux")]
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64")))]
+const O_DIRECT: i32 = 0o40000;
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+unsafe extern "C" {
+    fn fallocate(fd: i32, mode: i32, offset: i64, len: i64) -> i32;
+}
+
+/// Block sized buffer aligned for direct I/O.
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; BLOCK_SIZE]);
+
+/// Block device backed by a file on the host, typically a disk image.
+/// The number of blocks is derived from the file length, a trailing partial block is ignored.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct FileDevice {
+    file: File,
+    num_blocks: usize,
+    read_only: bool,
+    direct: bool,
+}
+
+#[cfg(unix)]
+impl FileDevice {
+    /// Opens an existing image for reading and writing.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with(path, false, false)
+    }
+
+    /// Opens an existing image for reading only.
+    /// Writes to the device fail with `Error::NotWritable`.
+    pub fn open_read_only(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with(path, true, false)
+    }
+
+    /// Opens an existing image, bypassing the host page cache with `O_DIRECT`.
+    /// Useful when muon's own cache is in use, or to test against the real device.
+    #[cfg(target_os = "linux")]
+    pub fn open_direct(path: impl AsRef<Path>, read_only: bool) -> io::Result<Self> {
+        Self::open_with(path, read_only, true)
+    }
+
+    /// Creates an image of `num_blocks` zeroed blocks, truncating any existing file.
+    pub fn create(path: impl AsRef<Path>, num_blocks: usize) -> io::Result<Self> {
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len((num_blocks * BLOCK_SIZE) as u64)?;
+        Self::from_file(file, false)
+    }
+
+    /// Wraps an already opened image file.
+    pub fn from_file(file: File, read_only: bool) -> io::Result<Self> {
+        let num_blocks = (file.metadata()?.len() / BLOCK_SIZE as u64) as usize;
+        Ok(FileDevice {
+            file,
+            num_blocks,
+            read_only,
+            direct: false,
+        })
+    }
+
+    fn open_with(path: impl AsRef<Path>, read_only: bool, direct: bool) -> io::Result<Self> {
+        let mut options = File::options();
+        options.read(true).write(!read_only);
+        #[cfg(target_os = "linux")]
+        if direct {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(O_DIRECT);
+        }
+        let mut device = Self::from_file(options.open(path)?, read_only)?;
+        device.direct = direct;
+        Ok(device)
+    }
+}
+
+/// Maps host I/O errors to file system errors.
+/// `fallback` is used for errors without a more precise counterpart.
+fn map_io_error(e: io::Error, fallback: Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied,
+        io::ErrorKind::ReadOnlyFilesystem => Error::NotWritable,
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::WriteZero => Error::OutOfBounds,
+        io::ErrorKind::StorageFull => Error::OutOfSpace,
+        _ => fallback,
+    }
+}
+
+#[cfg(unix)]
+impl BlockDevice for FileDevice {
+    fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        if block_id as usize >= self.num_blocks {
+            return Err(Error::InvalidBlockId);
+        }
+        let offset = block_id as u64 * BLOCK_SIZE as u64;
+        if self.direct {
+            let mut aligned = AlignedBlock([0u8; BLOCK_SIZE]);
+            self.file.read_exact_at(&mut aligned.0, offset)
+                .map_err(|e| map_io_error(e, Error::ReadError))?;
+            buf.copy_from_slice(&aligned.0);
+        } else {
+            self.file.read_exact_at(buf, offset)
+                .map_err(|e| map_io_error(e, Error::ReadError))?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        if self.read_only {
+            return Err(Error::NotWritable);
+        }
+        if block_id as usize >= self.num_blocks {
+            return Err(Error::InvalidBlockId);
+        }
+        let offset = block_id as u64 * BLOCK_SIZE as u64;
+        if self.direct {
+            let aligned = AlignedBlock(*buf);
+            self.file.write_all_at(&aligned.0, offset)
+                .map_err(|e| map_io_error(e, Error::WriteError))?;
+        } else {
+            self.file.write_all_at(buf, offset)
+                .map_err(|e| map_io_error(e, Error::WriteError))?;
+        }
+        Ok(())
+    }
+
+    fn read_blocks(&self, start_block_id: u32, buf: &mut [u8]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        check_blocks(self.num_blocks, start_block_id, buf.len())?;
+        if self.direct {
+            // Only single blocks are bounced through an aligned buffer.
+            for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+                self.read_block(start_block_id + i as u32, block.try_into().unwrap())?;
+            }
+            return Ok(());
+        }
+        self.file.read_exact_at(buf, start_block_id as u64 * BLOCK_SIZE as u64)
+            .map_err(|e| map_io_error(e, Error::ReadError))
+    }
+
+    fn write_blocks(&self, start_block_id: u32, buf: &[u8]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        if self.read_only {
+            return Err(Error::NotWritable);
+        }
+        check_blocks(self.num_blocks, start_block_id, buf.len())?;
+        if self.direct {
+            for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+                self.write_block(start_block_id + i as u32, block.try_into().unwrap())?;
+            }
+            return Ok(());
+        }
+        self.file.write_all_at(buf, start_block_id as u64 * BLOCK_SIZE as u64)
+            .map_err(|e| map_io_error(e, Error::WriteError))
+    }
+
+    /// Punches a hole in the image, where the host file system supports it.
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<()> {
+        use std::os::fd::AsRawFd;
+
+        if self.read_only {
+            return Err(Error::NotWritable);
+        }
+        check_blocks(self.num_blocks, start_block_id, count as usize * BLOCK_SIZE)?;
+        let offset = start_block_id as i64 * BLOCK_SIZE as i64;
+        let len = count as i64 * BLOCK_SIZE as i64;
+        let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+        if unsafe { fallocate(self.file.as_raw_fd(), mode, offset, len) } != 0 {
+            return Err(map_io_error(io::Error::last_os_error(), Error::IoError));
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.sync_data().map_err(|e| map_io_error(e, Error::IoError))
+    }
+}
@@ -1,7 +1,10 @@
-use alloc::boxed::Box;
 use alloc::vec::Vec;
 
 use crate::{alloc_inode, bmap, write_inode, write_superblock, BlockDevice};
+use crate::bitmap::alloc_data_block;
+use crate::checksum::{get_meta_block, put_meta_block};
+use crate::file::{flush_dependencies, sync_blocks};
+use crate::inode::{read_inline, write_inline};
 use crate::error::{FsError, Result};
 use crate::config::*;
 use crate::structs::*;
@@ -51,6 +54,44 @@ impl DirEntry {
     }
 }
 
+/// Entries of a directory with inline data, which are kept packed: the size counts them all.
+fn inline_entries(device: &impl BlockDevice, superblock: &SuperBlock, dir_inode: &Inode) -> Result<Vec<DirEntry>> {
+    let data = read_inline(device, superblock, dir_inode)?;
+    Ok(data.chunks_exact(DIR_ENTRY_SIZE).map(|bytes| {
+        let mut entry = DirEntry::NULL;
+        entry.inode_id = u32::from_ne_bytes(bytes[..4].try_into().unwrap());
+        entry.name.copy_from_slice(&bytes[4..]);
+        entry
+    }).collect())
+}
+
+/// Stores the entries of a directory without data blocks inline.
+fn write_inline_entries(device: &impl BlockDevice, superblock: &SuperBlock, dir_inode: &mut Inode, entries: &[DirEntry]) -> Result<()> {
+    let mut data = Vec::with_capacity(entries.len() * DIR_ENTRY_SIZE);
+    for entry in entries {
+        data.extend_from_slice(&entry.inode_id.to_ne_bytes());
+        data.extend_from_slice(&entry.name);
+    }
+    write_inline(device, superblock, dir_inode, &data)
+}
+
+/// Moves the entries of an inline directory to its first block, when one more does not fit in the inode.
+/// Also stores the first entries of a new directory which do not fit in the inode together.
+fn inline_to_block(device: &impl BlockDevice, superblock: &mut SuperBlock, dir_inode: &mut Inode, entries: &[DirEntry]) -> Result<()> {
+    let block_id = alloc_data_block(device, superblock)?;
+    let bh = device.get_block(block_id)?;
+    for (i, entry) in entries.iter().enumerate() {
+        bh.write_obj(i * DIR_ENTRY_SIZE, entry);
+    }
+    put_meta_block(device, superblock, bh)?;
+
+    // The entries are in place before the inode refers to them.
+    dir_inode.inode_ptr = InodePtr::ZERO;
+    dir_inode.get_block_ptrs_mut()?.direct[0] = Some(block_id);
+    dir_inode.blocks = 1;
+    write_inode(device, superblock, dir_inode)
+}
+
 /// Query inode id of a file by name in the parent directory inode.
 /// Returns the inode ID of the file if found, or an error if not found or if the parent is not a directory.
 pub fn dir_lookup(
@@ -66,6 +107,14 @@ pub fn dir_lookup(
     if name.len() > MAX_FILE_NAME_LEN {
         return Err(FsError::InvalidFileName);
     }
+    if parent_inode.has_inline_data() {
+        let entries = inline_entries(device, superblock, parent_inode)?;
+        let entry = entries.iter().find(|entry| name_cmp(&entry.name, name)).ok_or(FsError::NotFound)?;
+        if entry.inode_id >= superblock.num_inodes {
+            return Err(FsError::Corrupted);
+        }
+        return Ok(entry.inode_id);
+    }
 
     let num_dirents = (parent_inode.size / DIR_ENTRY_SIZE as u64) as usize;
     let mut num_looked_up = 0;
@@ -79,17 +128,12 @@ pub fn dir_lookup(
             i as u64 * BLOCK_SIZE as u64,
             false,
         )?;
-        let mut direntries_buf = Box::new([0u8; BLOCK_SIZE]);
-        device.read_block(block_id, direntries_buf.as_mut())?;
+        let bh = get_meta_block(device, superblock, block_id)?;
         for j in 0..NUM_ENTRY_PER_BLOCK {
             if num_looked_up >= num_dirents {
                 break; // No more entries to check
             }
-            let cur_entry_offset = j * DIR_ENTRY_SIZE;
-            let entry_ptr = unsafe {
-                direntries_buf.as_mut_ptr().add(cur_entry_offset) as *mut DirEntry
-            };
-            let entry = unsafe { &*entry_ptr };
+            let entry = bh.read_obj::<DirEntry>(j * DIR_ENTRY_SIZE);
             
             if entry.inode_id == 0 {
                 continue;
@@ -100,6 +144,9 @@ pub fn dir_lookup(
             if name_cmp(&entry.name, name) {
                 // println!("[dir_lookup] Found entry: {}, inode_id: {}", 
                 //    String::from_utf8_lossy(&entry.name), entry.inode_id);
+                if entry.inode_id >= superblock.num_inodes {
+                    return Err(FsError::Corrupted);
+                }
                 return Ok(entry.inode_id);
             }
         }   
@@ -124,13 +171,23 @@ pub fn dir_add_entry(
     if let Ok(_) = dir_lookup(device, superblock, parent_inode, &child_entry.name) {
         return Err(FsError::AlreadyExists);
     }
+    if parent_inode.blocks == 0 {
+        let mut entries = inline_entries(device, superblock, parent_inode)?;
+        if (entries.len() + 1) * DIR_ENTRY_SIZE <= superblock.max_inline_size() {
+            entries.push(*child_entry);
+            return write_inline_entries(device, superblock, parent_inode, &entries);
+        }
+        if !entries.is_empty() {
+            // Then added to the block like to any other.
+            inline_to_block(device, superblock, parent_inode, &entries)?;
+        }
+    }
 
     let prev_size = parent_inode.size;
 
     // Check if we need to allocate a new block for the directory entry
     let mut block_id_to_write = 0;
     let mut block_inner_offset = 0;
-    let mut cur_block_buf = Box::new([0u8; BLOCK_SIZE]);
 
     let num_dirents = (prev_size / DIR_ENTRY_SIZE as u64) as usize;
     let num_blocks = parent_inode.blocks as u64;
@@ -145,15 +202,12 @@ pub fn dir_add_entry(
             i as u64 * BLOCK_SIZE as u64,
             false,
         )?;
-        device.read_block(block_id, cur_block_buf.as_mut())?;
+        let bh = get_meta_block(device, superblock, block_id)?;
 
         for j in 0..NUM_ENTRY_PER_BLOCK {
             //println!("Checking block {}, entry {}", i, j);
             let cur_dirent_offset = j * DIR_ENTRY_SIZE;
-            let dirent_ptr = unsafe {
-                cur_block_buf.as_mut_ptr().add(cur_dirent_offset) as *mut DirEntry
-            };
-            let dirent = unsafe { &*dirent_ptr };
+            let dirent = bh.read_obj::<DirEntry>(cur_dirent_offset);
                 //println!("entry {} name {}", i, String::from_utf8_lossy(&dirent.name));
             if dirent.inode_id == 0 && name_is_empty(&dirent.name) {
                 // Found an empty slot
@@ -179,19 +233,15 @@ pub fn dir_add_entry(
         )?;
         //println!("blocks: {}, new block id: {}", parent_inode.blocks, block_id_to_write);
         block_inner_offset = (prev_size % BLOCK_SIZE as u64) as usize;
-        device.read_block(block_id_to_write, cur_block_buf.as_mut())?;
 
         parent_inode.size = (num_dirents + 1) as u64 * DIR_ENTRY_SIZE as u64;
         write_inode(device, superblock, &parent_inode)?;
     }
 
-    let dest_ptr = unsafe {
-        cur_block_buf.as_mut_ptr().add(block_inner_offset) as *mut DirEntry
-    };
-    unsafe {
-        dest_ptr.write_unaligned(*child_entry);
-    }
-    device.write_block(block_id_to_write, cur_block_buf.as_ref())?;
+    // Verified while looking for the slot, or freshly allocated.
+    let bh = device.get_block(block_id_to_write)?;
+    bh.write_obj(block_inner_offset, child_entry);
+    put_meta_block(device, superblock, bh)?;
 
     // println!("After adding entry: {}, new size: {}, new blocks: {}", 
     //    String::from_utf8_lossy(&child_entry.name), parent_inode.size, parent_inode.blocks);
@@ -218,12 +268,18 @@ pub fn dir_rm_entry(
     if name_cmp(name, DOT_NAME) || name_cmp(name, DOTDOT_NAME) {
         return Err(FsError::InvalidFileName);
     }
+    if parent_inode.has_inline_data() {
+        let mut entries = inline_entries(device, superblock, parent_inode)?;
+        let index = entries.iter().position(|entry| name_cmp(&entry.name, name)).ok_or(FsError::NotFound)?;
+        let entry = entries.remove(index);
+        write_inline_entries(device, superblock, parent_inode, &entries)?;
+        return Ok(entry.inode_id);
+    }
 
     let num_dirents = (parent_inode.size / DIR_ENTRY_SIZE as u64) as usize;
     let mut num_looked_up = 0;
     let num_blocks = parent_inode.blocks;
     
-    let mut cur_block_buf = Box::new([0u8; BLOCK_SIZE]);
     let mut inode_id_to_remove = None;
     let mut block_id_to_modify = None;
     let mut block_inner_offset = 0;
@@ -236,16 +292,13 @@ pub fn dir_rm_entry(
             (i * BLOCK_SIZE) as u64, 
             false
         )?;
-        device.read_block(block_id, cur_block_buf.as_mut())?;
+        let bh = get_meta_block(device, superblock, block_id)?;
         for j in 0..NUM_ENTRY_PER_BLOCK {
             if num_looked_up >= num_dirents {
                 break; // No more entries to check
             }
             let cur_entry_offset = j * DIR_ENTRY_SIZE;
-            let entry_ptr = unsafe {
-                cur_block_buf.as_mut_ptr().add(cur_entry_offset) as *mut DirEntry
-            };
-            let entry = unsafe { &mut *entry_ptr };
+            let entry = bh.read_obj::<DirEntry>(cur_entry_offset);
             if entry.inode_id == 0 {
                 continue; // Empty entry, skip
             }
@@ -265,19 +318,14 @@ pub fn dir_rm_entry(
         return Err(FsError::NotFound);
     }
 
-    let dest_ptr = unsafe {
-        cur_block_buf.as_mut_ptr().add(block_inner_offset) as *mut DirEntry
-    };
-    unsafe {
-        dest_ptr.write_unaligned(DirEntry::NULL);
-    }
-
     // For simplicity, no possible reclaiming of data blocks.
     parent_inode.size -= DIR_ENTRY_SIZE as u64;
     // parent_inode.blocks = ((parent_inode.size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64) as u32;
 
     write_inode(device, superblock, &parent_inode)?;
-    device.write_block(block_id_to_modify.unwrap(), cur_block_buf.as_ref())?;
+    let bh = device.get_block(block_id_to_modify.unwrap())?;
+    bh.write_obj(block_inner_offset, &DirEntry::NULL);
+    put_meta_block(device, superblock, bh)?;
 
     // If the inode's links reaches 0 after this operation, caller should reclaim the inode.
     Ok(inode_id_to_remove.unwrap())
@@ -299,7 +347,8 @@ pub fn dir_is_empty(
     if num_dirents == 2 {
         return Ok(true); // Only '.' and '..' entries
     } else if num_dirents < 2 {
-        panic!("Directory should have at least '.' and '..' entries");
+        // Every directory has '.' and '..' entries.
+        Err(FsError::Corrupted)
     } else {
         Ok(false)
     }
@@ -337,31 +386,30 @@ pub fn mkdir(
     )?;
     let dir_inode_id = dir_inode.id;
 
+    // Fill in the new directory before linking it into the parent.
+    // Both entries go to the inode if they fit, straight to a block otherwise.
+    let entries = [
+        DirEntry::new(dir_inode_id, DOT_NAME)?,
+        DirEntry::new(parent_inode.id, DOTDOT_NAME)?,
+    ];
+    dir_inode.links_cnt += 1; // '.' entry counts as a link
+    dir_inode.links_cnt += 1; // Entry in the parent, added below
+    if entries.len() * DIR_ENTRY_SIZE <= superblock.max_inline_size() {
+        write_inline_entries(device, superblock, &mut dir_inode, &entries)?;
+    } else {
+        dir_inode.size = (entries.len() * DIR_ENTRY_SIZE) as u64;
+        inline_to_block(device, superblock, &mut dir_inode, &entries)?;
+    }
+    flush_dependencies(device, superblock, sync_blocks(device, superblock, &dir_inode)?)?;
+
     dir_add_entry(
         device, 
         superblock, 
         parent_inode, 
         &DirEntry::new(dir_inode_id, dir_name)?
     )?;
-    dir_inode.links_cnt += 1;
-    dir_add_entry(
-        device, 
-        superblock, 
-        &mut dir_inode, 
-        &DirEntry::new(dir_inode_id, DOT_NAME)?
-    )?;
-    dir_inode.links_cnt += 1; // '.' entry counts as a link
-    dir_add_entry(
-        device, 
-        superblock, 
-        &mut dir_inode, 
-        &DirEntry::new(parent_inode.id, DOTDOT_NAME)?
-    )?;
     parent_inode.links_cnt += 1; // '..' entry counts as a link
-    assert!(dir_inode.size == 2 * DIR_ENTRY_SIZE as u64);
-    assert!(dir_inode.blocks == 1);
     write_inode(device, superblock, &parent_inode)?;
-    write_inode(device, superblock, &dir_inode)?;
 
     Ok(dir_inode_id)
 }
@@ -374,6 +422,9 @@ pub fn read_dir(
     if dir_inode.ftype != FileType::Directory {
         return Err(FsError::NotDirectory);
     }
+    if dir_inode.has_inline_data() {
+        return inline_entries(device, superblock, dir_inode);
+    }
 
     let num_dirents = (dir_inode.size / DIR_ENTRY_SIZE as u64) as usize;
     let num_blocks = (dir_inode.size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
@@ -382,7 +433,6 @@ pub fn read_dir(
     //    dir_inode.id, num_dirents, num_blocks);
 
     let mut entries = Vec::new();
-    let mut cur_block_buf = Box::new([0u8; BLOCK_SIZE]);
 
     for i in 0..num_blocks as usize {
         let block_id = bmap(
@@ -392,19 +442,15 @@ pub fn read_dir(
             i as u64 * BLOCK_SIZE as u64,
             false,
         )?;
-        device.read_block(block_id, cur_block_buf.as_mut())?;
+        let bh = get_meta_block(device, superblock, block_id)?;
         for j in 0..NUM_ENTRY_PER_BLOCK {
             if i * NUM_ENTRY_PER_BLOCK + j >= num_dirents {
                 break; // No more entries to read
             }
-            let cur_entry_offset = j * DIR_ENTRY_SIZE;
-            let entry_ptr = unsafe {
-                cur_block_buf.as_mut_ptr().add(cur_entry_offset) as *mut DirEntry
-            };
-            let entry = unsafe { &*entry_ptr };
+            let entry = bh.read_obj::<DirEntry>(j * DIR_ENTRY_SIZE);
             
             if entry.inode_id != 0 || !name_is_empty(&entry.name) {
-                entries.push(*entry);
+                entries.push(entry);
             }
         }
     }
@@ -28,6 +28,23 @@ pub enum FsError {
     NotReadable,
     NotWritable,
     NotEmpty,
+    InvalidArchive,
+    ReadOnly,
+    /// On-disk structures are inconsistent, e.g. a block pointer outside of the data region.
+    Corrupted,
+    /// A metadata checksum does not match the contents it covers.
+    ChecksumMismatch,
+    /// A file system check is due, and the mount options require a clean file system.
+    NeedsCheck,
+    /// The file system uses a revision or incompatible features this driver does not know.
+    UnsupportedFeature,
+}
+
+impl FsError {
+    /// Whether the error reports corrupted on-disk data, rather than a failed request or device.
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, FsError::Corrupted | FsError::ChecksumMismatch | FsError::InvalidMagic | FsError::InvalidSuperBlock)
+    }
 }
 
 pub type Result<T> = core::result::Result<T, FsError>;
\ No newline at end of file
@@ -1,8 +1,51 @@
 //! Encapsulation of inode operations.
 
-use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
 
-use crate::{bitmap::free_data_block, bmap, write_inode, BlockDevice, Error, FileType, Inode, Result, SuperBlock, BLOCK_SIZE, PTRS_PER_BLOCK};
+use crate::{bitmap::{alloc_data_block, data_bitmap_block_id, discard_blocks, free_data_block, inode_bitmap_block_id, zero_blocks}, bmap, checksum::{csum_table_block_id, has_data_csum, sync_csum_table_block, has_metadata_csum, update_data_checksum, verify_data_block}, inode::{inode_block_id, inode_blocks, map_block, read_inline, unmap_block, write_inline}, write_inode, BlockDevice, Error, FileType, Inode, InodePtr, Result, SuperBlock, BLOCK_SIZE, CSUMS_PER_BLOCK, PTRS_PER_BLOCK, READAHEAD_MIN_BLOCKS};
+
+/// Sequential read detection for `fread_ahead`.
+/// A read starting at the beginning of a file, or where the previous read of the same file ended,
+/// opens a window of `READAHEAD_MIN_BLOCKS` blocks past the requested range, doubled on every
+/// following sequential read up to `max_window`. Any other read closes the window.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadAhead {
+    inode_id: u32,
+    next_offset: usize,
+    window: usize,
+    max_window: usize,
+}
+
+impl ReadAhead {
+    /// Read-ahead of up to `max_window` blocks, 0 disables it.
+    pub const fn new(max_window: usize) -> Self {
+        ReadAhead {
+            inode_id: 0,
+            next_offset: 0,
+            window: 0,
+            max_window,
+        }
+    }
+
+    pub fn max_window(&self) -> usize {
+        self.max_window
+    }
+
+    /// Updates the window for a read at `offset`, returning its size in blocks.
+    fn update(&mut self, inode_id: u32, offset: usize) -> usize {
+        let sequential = offset == 0 || (inode_id == self.inode_id && offset == self.next_offset);
+        self.window = if !sequential {
+            0
+        } else if self.window == 0 {
+            READAHEAD_MIN_BLOCKS.min(self.max_window)
+        } else {
+            (self.window * 2).min(self.max_window)
+        };
+        self.inode_id = inode_id;
+        self.window
+    }
+}
 
 /// Reads data from a file into the provided buffer.
 /// The `offset` is the position in the file to start reading from.
@@ -17,38 +60,76 @@ pub fn fread(
     if inode.ftype != FileType::Regular {
         return Err(Error::NotReadable);
     }
+    if inode.has_inline_data() {
+        let data = read_inline(device, superblock, inode)?;
+        let len = data.len().saturating_sub(offset).min(buffer.len());
+        if len > 0 {
+            buffer[..len].copy_from_slice(&data[offset..offset + len]);
+        }
+        return Ok(len);
+    }
 
     let mut bytes_read = 0;
     let mut current_offset = offset;
     let mut current_relative_block_id = current_offset / BLOCK_SIZE;
     let mut remain_buf_len = buffer.len();
-    let mut block_buf = Box::new([0u8; BLOCK_SIZE]);
 
     while remain_buf_len > 0 {
-        let bytes_to_read = BLOCK_SIZE.min(remain_buf_len).min(inode.size as usize - bytes_read - offset);
+        let start_offset = current_offset % BLOCK_SIZE;
+        let bytes_left = remain_buf_len.min((inode.size as usize).saturating_sub(current_offset));
+        let bytes_to_read = (BLOCK_SIZE - start_offset).min(bytes_left);
         if bytes_to_read == 0  {
             break;
         }
+        // Never allocates, so that reading works on read-only mounts.
         let current_block_id = match bmap(
             device,
             superblock,
             inode,
             current_relative_block_id as u64 * BLOCK_SIZE as u64,
-            true,
+            false,
         ) {
             Ok(block_id) => block_id,
             Err(Error::OutOfBounds) => {
-                // If we reach beyond the file size, we stop reading.
-                break;
+                // A hole within the file, which reads as zeros.
+                buffer[bytes_read..bytes_read + bytes_to_read].fill(0);
+                bytes_read += bytes_to_read;
+                remain_buf_len -= bytes_to_read;
+                current_offset += bytes_to_read;
+                current_relative_block_id = current_offset / BLOCK_SIZE;
+                continue;
             }
             Err(e) => return Err(e),
         };
+
+        if bytes_to_read == BLOCK_SIZE {
+            // Whole blocks go straight into the caller's buffer, one transfer per contiguous run.
+            let count = contiguous_run(
+                device,
+                superblock,
+                inode,
+                current_relative_block_id,
+                current_block_id,
+                bytes_left / BLOCK_SIZE,
+                None,
+            )?;
+            let len = count * BLOCK_SIZE;
+            device.read_blocks(current_block_id, &mut buffer[bytes_read..bytes_read + len])?;
+            for (i, data) in buffer[bytes_read..bytes_read + len].chunks_exact(BLOCK_SIZE).enumerate() {
+                verify_data_block(device, superblock, current_block_id + i as u32, data)?;
+            }
+            bytes_read += len;
+            remain_buf_len -= len;
+            current_offset += len;
+            current_relative_block_id = current_offset / BLOCK_SIZE;
+            continue;
+        }
         
-        device.read_block(current_block_id, block_buf.as_mut())?;
-        let start_offset = current_offset % BLOCK_SIZE;
+        // Copied out, so that it can be verified without holding the buffer's lock.
+        let data = device.get_block(current_block_id)?.read_obj::<[u8; BLOCK_SIZE]>(0);
+        verify_data_block(device, superblock, current_block_id, &data)?;
         let end_offset = start_offset + bytes_to_read;
-        buffer[bytes_read..bytes_read + bytes_to_read]
-            .copy_from_slice(&block_buf[start_offset..end_offset]);
+        buffer[bytes_read..bytes_read + bytes_to_read].copy_from_slice(&data[start_offset..end_offset]);
         
         bytes_read += bytes_to_read;
         remain_buf_len -= bytes_to_read;
@@ -59,6 +140,45 @@ pub fn fread(
     Ok(bytes_read)
 }
 
+/// Same as `fread`, hinting the device to fetch the blocks of the requested range at once,
+/// plus those of the read-ahead window if the file is read sequentially.
+pub fn fread_ahead(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    inode: &mut Inode,
+    offset: usize,
+    buffer: &mut [u8],
+    readahead: &mut ReadAhead,
+) -> Result<usize> {
+    if inode.ftype != FileType::Regular {
+        return Err(Error::NotReadable);
+    }
+
+    let window = readahead.update(inode.id, offset);
+    let end = (offset + buffer.len()).min(inode.size as usize);
+    if offset < end {
+        let first_block = offset / BLOCK_SIZE;
+        let last_block = ((end - 1) / BLOCK_SIZE + window).min((inode.size as usize - 1) / BLOCK_SIZE);
+        if last_block > first_block {
+            let mut block_ids = Vec::with_capacity(last_block - first_block + 1);
+            for i in first_block..=last_block {
+                match bmap(device, superblock, inode, (i * BLOCK_SIZE) as u64, false) {
+                    Ok(block_id) => block_ids.push(block_id),
+                    // Holes have nothing to fetch.
+                    Err(Error::OutOfBounds) => continue,
+                    Err(_) => break,
+                }
+            }
+            // Only a hint, the actual reads report errors.
+            let _ = device.readahead(&block_ids);
+        }
+    }
+
+    let bytes_read = fread(device, superblock, inode, offset, buffer)?;
+    readahead.next_offset = offset + bytes_read;
+    Ok(bytes_read)
+}
+
 /// Writes data from the provided buffer to a file at the specified offset.
 /// Returns the number of bytes written, or an error if the operation fails.
 pub fn fwrite(
@@ -74,31 +194,83 @@ pub fn fwrite(
     if buffer.is_empty() {
         return Ok(0);
     }
+    let end = offset + buffer.len();
+    if inode.blocks == 0 && end <= superblock.max_inline_size() {
+        // Stays in the inode, or moves back there once the file was truncated.
+        let mut data = read_inline(device, superblock, inode)?;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buffer);
+        write_inline(device, superblock, inode, &data)?;
+        return Ok(buffer.len());
+    }
+    if inode.has_inline_data() {
+        inline_to_block(device, superblock, inode)?;
+    }
 
     let mut bytes_written = 0;
     let mut current_offset = offset;
     let mut current_relative_block_id = current_offset / BLOCK_SIZE;
     let mut remain_buf_len = buffer.len();
-    let mut block_buf = Box::new([0u8; BLOCK_SIZE]);
 
     while remain_buf_len > 0 {
-        let bytes_to_write = BLOCK_SIZE.min(remain_buf_len);
+        let start_offset = current_offset % BLOCK_SIZE;
+        let bytes_to_write = (BLOCK_SIZE - start_offset).min(remain_buf_len);
         if bytes_to_write == 0 {
             break;
         }
-        let current_block_id = bmap(
+        // Blocks written whole are not zeroed first, the run is written once below.
+        let blocks = inode.blocks;
+        let current_block_id = map_block(
             device,
             superblock,
             inode,
             current_relative_block_id as u64 * BLOCK_SIZE as u64,
             true,
+            bytes_to_write < BLOCK_SIZE,
         )?;
+
+        if bytes_to_write == BLOCK_SIZE {
+            let mut claimed = Vec::new();
+            if inode.blocks > blocks {
+                claimed.push(current_relative_block_id);
+            }
+            let count = contiguous_run(
+                device,
+                superblock,
+                inode,
+                current_relative_block_id,
+                current_block_id,
+                remain_buf_len / BLOCK_SIZE,
+                Some(&mut claimed),
+            )?;
+            let len = count * BLOCK_SIZE;
+            if let Err(e) = device.write_blocks(current_block_id, &buffer[bytes_written..bytes_written + len]) {
+                release_claimed(device, superblock, inode, &claimed)?;
+                return Err(e);
+            }
+            for (i, data) in buffer[bytes_written..bytes_written + len].chunks_exact(BLOCK_SIZE).enumerate() {
+                update_data_checksum(device, superblock, current_block_id + i as u32, data.try_into().unwrap())?;
+            }
+            bytes_written += len;
+            remain_buf_len -= len;
+            current_offset += len;
+            current_relative_block_id = current_offset / BLOCK_SIZE;
+            continue;
+        }
         
-        device.read_block(current_block_id, block_buf.as_mut())?;
-        let start_offset = current_offset % BLOCK_SIZE;
-        block_buf[start_offset..start_offset + bytes_to_write]
+        let bh = device.get_block(current_block_id)?;
+        if has_data_csum(superblock) {
+            // Never turn a damaged block into a valid one by checksumming it with the new bytes.
+            verify_data_block(device, superblock, current_block_id, &bh.read_obj::<[u8; BLOCK_SIZE]>(0))?;
+        }
+        bh.lock()[start_offset..start_offset + bytes_to_write]
             .copy_from_slice(&buffer[bytes_written..bytes_written + bytes_to_write]);
-        device.write_block(current_block_id, block_buf.as_ref())?;
+        bh.mark_dirty();
+        let data = bh.read_obj::<[u8; BLOCK_SIZE]>(0);
+        device.put_block(bh)?;
+        update_data_checksum(device, superblock, current_block_id, &data)?;
         bytes_written += bytes_to_write;
         remain_buf_len -= bytes_to_write;
         current_offset += bytes_to_write;
@@ -113,6 +285,72 @@ pub fn fwrite(
     Ok(bytes_written)
 }
 
+/// Gives back the blocks a failed write newly claimed, given by relative id, leaving holes.
+/// They may still hold another file's data and are zeroed on the way, as far as the device allows;
+/// blocks the file already owned keep their contents and checksums.
+fn release_claimed(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    inode: &mut Inode,
+    claimed: &[usize],
+) -> Result<()> {
+    for &relative_block_id in claimed {
+        let block_id = unmap_block(device, superblock, inode, (relative_block_id * BLOCK_SIZE) as u64)?;
+        let _ = zero_blocks(device, block_id, 1);
+        update_data_checksum(device, superblock, block_id, &[0u8; BLOCK_SIZE])?;
+        free_data_block(device, superblock, block_id)?;
+    }
+    Ok(())
+}
+
+/// Moves the inline contents of a file to its first data block, when it grows past the inline limit.
+fn inline_to_block(device: &impl BlockDevice, superblock: &mut SuperBlock, inode: &mut Inode) -> Result<()> {
+    let data = read_inline(device, superblock, inode)?;
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..data.len()].copy_from_slice(&data);
+    let block_id = alloc_data_block(device, superblock)?;
+    let bh = device.get_block(block_id)?;
+    bh.write_obj(0, &block);
+    device.put_block(bh)?;
+    update_data_checksum(device, superblock, block_id, &block)?;
+
+    // The contents are in place before the inode refers to them.
+    inode.inode_ptr = InodePtr::ZERO;
+    inode.get_block_ptrs_mut()?.direct[0] = Some(block_id);
+    inode.blocks = 1;
+    write_inode(device, superblock, inode)
+}
+
+/// Counts how many file blocks from `relative_block_id` on, at most `max_blocks`, are stored
+/// contiguously from `block_id`, which the first one maps to.
+/// Missing blocks are allocated, without being zeroed, if `claimed` is given, which records their
+/// relative ids; they end the run otherwise.
+fn contiguous_run(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    inode: &mut Inode,
+    relative_block_id: usize,
+    block_id: u32,
+    max_blocks: usize,
+    mut claimed: Option<&mut Vec<usize>>,
+) -> Result<usize> {
+    let mut count = 1;
+    while count < max_blocks {
+        let file_offset = ((relative_block_id + count) * BLOCK_SIZE) as u64;
+        let blocks = inode.blocks;
+        let next = map_block(device, superblock, inode, file_offset, claimed.is_some(), false);
+        if inode.blocks > blocks && let Some(claimed) = claimed.as_deref_mut() {
+            claimed.push(relative_block_id + count);
+        }
+        match next {
+            Ok(next) if next == block_id + count as u32 => count += 1,
+            Ok(_) | Err(Error::OutOfBounds) | Err(Error::FileTooLarge) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(count)
+}
+
 pub fn ftruncate(
     device: &impl BlockDevice,
     superblock: &mut SuperBlock,
@@ -121,38 +359,81 @@ pub fn ftruncate(
     if inode.ftype != FileType::Regular {
         return Err(Error::NotWritable);
     }
+    if inode.has_inline_data() {
+        return write_inline(device, superblock, inode, &[]);
+    }
 
+    // Blocks to free, also discarded in runs at the end.
+    let mut freed = Vec::new();
     let blk_ptr = inode.get_block_ptrs_mut()?;
     for direct_blk in blk_ptr.direct.iter_mut() {
-        if let Some(block_id) = *direct_blk {
-            free_data_block(device, superblock, block_id)?;
-            *direct_blk = None;
+        if let Some(block_id) = direct_blk.take() {
+            freed.push(block_id);
         }
     }
-    if let Some(indirect_block) = blk_ptr.indirect {
-        let mut indirect_ptr_buf = Box::new([0u8; BLOCK_SIZE]);
-        device.read_block(indirect_block, indirect_ptr_buf.as_mut())?;
-        
-        let ptrs = unsafe {
-            core::slice::from_raw_parts_mut(
-                indirect_ptr_buf.as_mut_ptr() as *mut u32,
-                PTRS_PER_BLOCK
-            )
-        };
-        
-        for &block_id in ptrs.iter() {
-            if block_id != 0 {
-                free_data_block(device, superblock, block_id)?;
-            }
-        }
-
-        free_data_block(device, superblock, indirect_block)?;
-        blk_ptr.indirect = None;
+    if let Some(indirect_block) = blk_ptr.indirect.take() {
+        let ptrs = device.get_block(indirect_block)?.read_obj::<[u32; PTRS_PER_BLOCK]>(0);
+        freed.extend(ptrs.iter().filter(|&&block_id| block_id != 0));
+        freed.push(indirect_block);
     }
 
     inode.blocks = 0;
     inode.size = 0;
+    // The inode must stop referencing the blocks on disk before they can be reallocated.
     write_inode(device, superblock, inode)?;
+    flush_dependencies(device, superblock, inode_record_blocks(superblock, inode.id).to_vec())?;
+
+    for &block_id in freed.iter() {
+        free_data_block(device, superblock, block_id)?;
+    }
+    discard_blocks(device, &mut freed);
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Blocks that must be on disk for an inode and its contents to be found again:
+/// its data and indirect blocks, its inode table block, and the bitmap blocks recording all of them.
+pub(crate) fn sync_blocks(device: &impl BlockDevice, superblock: &SuperBlock, inode: &Inode) -> Result<Vec<u32>> {
+    let mut block_ids = inode_blocks(device, inode)?;
+    let bitmap_blocks: Vec<u32> = block_ids.iter().map(|&block_id| data_bitmap_block_id(superblock, block_id)).collect();
+    block_ids.extend(bitmap_blocks);
+    block_ids.extend(inode_record_blocks(superblock, inode.id));
+    Ok(block_ids)
+}
+
+/// Blocks recording an inode itself: its inode table block and its inode bitmap block.
+pub(crate) fn inode_record_blocks(superblock: &SuperBlock, inode_id: u32) -> [u32; 2] {
+    [inode_block_id(superblock, inode_id), inode_bitmap_block_id(superblock, inode_id)]
+}
+
+/// Writes back the blocks of a file and makes them durable, leaving other cached blocks alone.
+/// With `parent`, the blocks of that directory are synced as well, for a new file whose
+/// directory entry may not be on disk yet.
+pub fn fsync(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    inode: &Inode,
+    parent: Option<&Inode>,
+) -> Result<()> {
+    let mut block_ids = sync_blocks(device, superblock, inode)?;
+    if let Some(parent) = parent {
+        block_ids.extend(sync_blocks(device, superblock, parent)?);
+    }
+    flush_dependencies(device, superblock, block_ids)
+}
+
+/// Makes the given blocks durable, with their checksums, before anything written afterwards:
+/// orders dependent metadata updates like a barrier, but leaves the rest of the cache alone.
+pub(crate) fn flush_dependencies(device: &impl BlockDevice, superblock: &SuperBlock, mut block_ids: Vec<u32>) -> Result<()> {
+    block_ids.sort_unstable();
+    block_ids.dedup();
+    device.flush_blocks(&block_ids)?;
+    if has_metadata_csum(superblock) {
+        // The checksums of the file's blocks follow them to the disk.
+        let table_blocks: BTreeSet<u32> = block_ids.iter().map(|&block_id| csum_table_block_id(superblock, block_id)).collect();
+        for table_block in table_blocks {
+            sync_csum_table_block(device, superblock, table_block, &block_ids)?;
+        }
+    }
+    Ok(())
+}
@@ -1,13 +1,127 @@
-use alloc::{boxed::Box, string::{String, ToString}, sync::Arc, vec::Vec};
-use crate::{alloc_inode, bmap, canonicalize, dir_is_empty, directory::{dir_add_entry, dir_rm_entry}, file::{fread, fwrite}, free_inode, ftruncate, get_inode, mkdir, path::{self, resolve, split}, read_dir, read_superblock, resolve_without_last, structs::*, superblock, write_inode, write_superblock, BlockDevice, Error, Result, DOTDOT_NAME, DOT_NAME, ROOT_INODE_ID};
+use alloc::{boxed::Box, collections::BTreeSet, string::{String, ToString}, sync::Arc, vec::Vec};
+use crate::{alloc_inode, bitmap::{reserve_data_block, trim_free_blocks, zero_blocks}, bmap, checksum::{init_bitmap_checksums, scrub, ScrubReport}, canonicalize, dir_is_empty, directory::{dir_add_entry, dir_rm_entry}, file::{flush_dependencies, fread_ahead, fsync, fwrite, inode_record_blocks, sync_blocks, ReadAhead}, free_inode, ftruncate, get_inode, inode::write_link_target, link_target, mkdir, path::{self, resolve, split}, read_dir, read_superblock_or_backup, resize::grow, commit_superblock, superblock::encode_label, resolve_without_last, structs::*, superblock, write_inode, write_superblock, write_superblock_with_backups, BlockDevice, Error, Result, DOTDOT_NAME, DOT_NAME, ROOT_INODE_ID};
 use crate::structs::*;
 use crate::config::*;
+use crate::sync::SpinLock;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Options given to `FileSystem::format_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions<'a> {
+    /// Keeps checksums of file contents as well as of metadata (`RO_COMPAT_DATA_CSUM`),
+    /// verified by every read and by `FileSystem::scrub`, at the cost of a checksum table
+    /// update per written block.
+    pub data_checksums: bool,
+    /// Random bytes from the caller's entropy source, from which the version 4 UUID of the file
+    /// system is generated. Required by `FileSystem::format_with_options`, which fails with
+    /// `Error::InvalidArgument` without them, or if they are all zero. The default draws them from
+    /// the host with the `std` feature, and has none without it, as Muon has no entropy source of its own.
+    pub uuid_random: Option<[u8; 16]>,
+    /// Volume label, up to `MAX_LABEL_LEN` bytes.
+    pub label: &'a str,
+    /// Bytes per inode record, a power of two from `INODE_SIZE` to `MAX_INODE_SIZE`, 0 for `INODE_SIZE`.
+    /// Larger records have room for `InodeExtra` fields, at the cost of a larger inode table.
+    /// `INODE_SIZE` records only keep the low 16 bits of their CRC32C, which misses about one
+    /// corruption in 65536; larger ones keep all 32.
+    pub inode_size: u32,
+    /// Stores regular files and directories small enough in their inode rather than in data blocks
+    /// (`INCOMPAT_INLINE_DATA`), see `SuperBlock::max_inline_size`.
+    pub inline_data: bool,
+}
+
+impl Default for FormatOptions<'_> {
+    fn default() -> Self {
+        Self {
+            data_checksums: false,
+            uuid_random: host_random_bytes(),
+            label: "",
+            inode_size: 0,
+            inline_data: false,
+        }
+    }
+}
+
+/// Random bytes for `FormatOptions::uuid_random`, from the per-process keys of the standard
+/// library's hasher, which it seeds from the operating system, mixed with the current time.
+#[cfg(feature = "std")]
+fn host_random_bytes() -> Option<[u8; 16]> {
+    use core::hash::{BuildHasher, Hasher};
+    let state = std::collections::hash_map::RandomState::new();
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+    let mut bytes = [0u8; 16];
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = state.build_hasher();
+        hasher.write_usize(i);
+        hasher.write_u128(nanos);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    Some(bytes)
+}
+
+#[cfg(not(feature = "std"))]
+fn host_random_bytes() -> Option<[u8; 16]> {
+    None
+}
+
+/// Options given to `FileSystem::mount_with_options`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Rejects every modification with `Error::ReadOnly`, no block is ever written.
+    /// Required for devices reporting themselves read-only.
+    pub read_only: bool,
+    /// Flushes the device after every modifying operation.
+    pub sync: bool,
+    /// Flushes the device after every directory update (creating, removing or linking files),
+    /// leaving file contents to the cache. Implied by `sync`.
+    pub dirsync: bool,
+    /// What to do when an operation detects corruption.
+    pub errors: ErrorBehavior,
+    /// Refuses to mount with `Error::NeedsCheck` when a check is due, instead of only
+    /// reporting it through `FileSystem::check_due`.
+    pub require_clean: bool,
+    /// Current time in seconds since the Unix epoch, recorded as the last mount time.
+    /// Muon has no clock of its own; 0 if unknown.
+    pub time: u64,
+}
+
+/// Reaction to corruption detected by an operation, see `MountOptions::errors`.
+/// The operation itself fails in any case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorBehavior {
+    /// Keep the file system mounted as is.
+    #[default]
+    Continue,
+    /// Switch to read-only, so that the damage does not spread.
+    RemountReadOnly,
+    /// Panic, e.g. to let a supervisor restart with a checked disk.
+    Panic,
+}
+
+/// What an operation modified, deciding whether `sync` or `dirsync` flush after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Update {
+    None,
+    Data,
+    Namespace,
+}
 
 #[derive(Debug)]
 pub struct FileSystem<D: BlockDevice> {
     device: Arc<D>,
     /// In-memory copy of the superblock.
     superblock: SuperBlock,
+    /// Sequential read detection, for the last file read.
+    readahead: ReadAhead,
+    /// Files created or linked since the device was last synced, as (inode, parent directory)
+    /// pairs. `fsync` syncs the parent directories as well.
+    new_files: SpinLock<BTreeSet<(u32, u32)>>,
+    options: MountOptions,
+    /// Set once corruption was detected with `ErrorBehavior::RemountReadOnly`.
+    remounted_read_only: AtomicBool,
+    /// Set once any operation detected corruption, which keeps the file system dirty on unmount.
+    corruption_detected: AtomicBool,
+    /// Whether a check was due when mounted.
+    check_due: Option<CheckReason>,
 }
 
 impl<D: BlockDevice> FileSystem<D> {
@@ -15,20 +129,43 @@ impl<D: BlockDevice> FileSystem<D> {
     /// Formats the filesystem on the given block device.
     /// Initializes the superblock and zeroes out the metadata blocks.
     /// Returns a new `FileSystem` instance.
+    /// The UUID is drawn from the host with the `std` feature, and nil without it;
+    /// `format_with_options` takes random bytes from the caller instead.
     pub fn format(device: Arc<D>, num_blocks: u32, num_inodes: u32) -> Result<Self> {
-        let mut superblock = SuperBlock::new(num_blocks, num_inodes)?;
+        let uuid = host_random_bytes().map_or(Uuid::NIL, Uuid::from_random_bytes);
+        Self::format_with_uuid(device, num_blocks, num_inodes, FormatOptions::default(), uuid)
+    }
 
-        let zero_block = Box::new([0u8; BLOCK_SIZE]);
-        
-        // Zero out metadata blocks
-        for i in 0..superblock.data_bitmap_blocks {
-            device.write_block(superblock.data_bitmap_start + i, &zero_block)?;
+    /// Same as `format`, with the given options.
+    /// Fails with `Error::InvalidArgument` without `FormatOptions::uuid_random`.
+    pub fn format_with_options(device: Arc<D>, num_blocks: u32, num_inodes: u32, options: FormatOptions) -> Result<Self> {
+        let random = options.uuid_random.filter(|random| *random != [0; 16]).ok_or(Error::InvalidArgument)?;
+        Self::format_with_uuid(device, num_blocks, num_inodes, options, Uuid::from_random_bytes(random))
+    }
+
+    /// Formats with the given UUID, already validated or chosen by the caller.
+    fn format_with_uuid(device: Arc<D>, num_blocks: u32, num_inodes: u32, options: FormatOptions, uuid: Uuid) -> Result<Self> {
+        let inode_size = match options.inode_size {
+            0 => INODE_SIZE as u32,
+            size => size,
+        };
+        let mut superblock = SuperBlock::with_inode_size(num_blocks, num_inodes, inode_size)?;
+        if options.data_checksums {
+            superblock.feature_ro_compat |= RO_COMPAT_DATA_CSUM;
         }
-        for i in 0..superblock.inode_bitmap_blocks {
-            device.write_block(superblock.inode_bitmap_start + i, &zero_block)?;
+        if options.inline_data {
+            superblock.feature_incompat |= INCOMPAT_INLINE_DATA;
         }
-        for i in 0..superblock.inode_table_blocks {
-            device.write_block(superblock.inode_table_start + i, &zero_block)?;
+        superblock.uuid = uuid.0;
+        superblock.label = encode_label(options.label)?;
+        // The new file system is in use until unmounted.
+        superblock.state = FS_STATE_DIRTY;
+
+        // Zero out metadata blocks, which are laid out contiguously after the superblock.
+        zero_blocks(&*device, superblock.data_bitmap_start, superblock.data_start - superblock.data_bitmap_start)?;
+        init_bitmap_checksums(&*device, &superblock)?;
+        for block_id in superblock.backup_block_ids() {
+            reserve_data_block(&*device, &mut superblock, block_id)?;
         }
 
         
@@ -60,15 +197,21 @@ impl<D: BlockDevice> FileSystem<D> {
         )?;
         root_inode.links_cnt = 2; // '.' and '..' entries
         assert!(root_inode.size == 2 * DIR_ENTRY_SIZE as u64, "Root inode size mismatch");
-        assert!(root_inode.blocks == 1, "Root inode blocks count mismatch");
+        assert!(root_inode.blocks == 1 || root_inode.has_inline_data(), "Root inode blocks count mismatch");
         assert!(root_inode.size == DIR_ENTRY_SIZE as u64 * 2, "Root inode size mismatch");
         write_inode(&*device, &mut superblock, &root_inode)?; // Write root inode to inode table
 
-        write_superblock(&*device, &superblock)?;
+        write_superblock_with_backups(&*device, &superblock)?;
 
         let mut fs_inst = Self {
             device: Arc::clone(&device),
             superblock,
+            readahead: ReadAhead::new(READAHEAD_MAX_BLOCKS),
+            new_files: SpinLock::new(BTreeSet::new()),
+            options: MountOptions::default(),
+            remounted_read_only: AtomicBool::new(false),
+            corruption_detected: AtomicBool::new(false),
+            check_due: None,
         };
 
         Ok(fs_inst)
@@ -77,45 +220,237 @@ impl<D: BlockDevice> FileSystem<D> {
     /// Mounts the filesystem from the given block device.
     /// Reads the superblock and initializes the filesystem instance.
     pub fn mount(device: Arc<D>) -> Result<Self> {
-        let superblock = read_superblock(&*device)?;
+        Self::mount_with_options(device, MountOptions::default())
+    }
+
+    /// Same as `mount`, with the given options.
+    /// Read-only devices can only be mounted with `read_only` set.
+    /// Read-write mounts mark the file system dirty and count the mount, see `check_due`.
+    /// A damaged primary superblock is replaced by a backup, and rewritten on read-write mounts.
+    /// File systems with unknown incompatible features fail with `Error::UnsupportedFeature`,
+    /// those with unknown read-only compatible features are mounted read-only.
+    pub fn mount_with_options(device: Arc<D>, mut options: MountOptions) -> Result<Self> {
+        if device.is_read_only() && !options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let (mut superblock, backup) = read_superblock_or_backup(&*device)?;
+        // Writing could break what unknown features rely on, reading is safe.
+        if superblock.unsupported_ro_compat() != 0 {
+            options.read_only = true;
+        }
+        let check_due = match backup {
+            Some(_) => Some(CheckReason::DamagedSuperBlock),
+            None => superblock.check_due(),
+        };
+        if check_due.is_some() && options.require_clean {
+            return Err(Error::NeedsCheck);
+        }
+        if !options.read_only {
+            // Stays dirty on disk until unmounted, so that a crash can be told apart.
+            superblock.state = FS_STATE_DIRTY;
+            superblock.mount_count = superblock.mount_count.saturating_add(1);
+            superblock.last_mount_time = options.time;
+            commit_superblock(&*device, &superblock)?;
+        }
         Ok(Self {
             device,
             superblock,
+            readahead: ReadAhead::new(READAHEAD_MAX_BLOCKS),
+            new_files: SpinLock::new(BTreeSet::new()),
+            options,
+            remounted_read_only: AtomicBool::new(false),
+            // Whatever damaged the primary superblock may have damaged more.
+            corruption_detected: AtomicBool::new(backup.is_some()),
+            check_due,
         })
     }
 
+    /// Whether the file system should have been checked before this mount: it was not cleanly
+    /// unmounted, or reached its maximum mount count.
+    pub fn check_due(&self) -> Option<CheckReason> {
+        self.check_due
+    }
+
+    /// Sets the number of read-write mounts allowed between checks, 0 for no limit.
+    pub fn set_max_mount_count(&mut self, count: u32) -> Result<()> {
+        self.check_writable()?;
+        self.superblock.max_mount_count = count;
+        write_superblock_with_backups(&*self.device, &self.superblock)
+    }
+
+    /// UUID and label of the file system.
+    pub fn volume_id(&self) -> VolumeId {
+        self.superblock.volume_id()
+    }
+
+    /// Sets the volume label, up to `MAX_LABEL_LEN` bytes.
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        self.check_writable()?;
+        self.superblock.label = encode_label(label)?;
+        write_superblock_with_backups(&*self.device, &self.superblock)
+    }
+
+    pub fn options(&self) -> MountOptions {
+        self.options
+    }
+
+    /// Whether the file system is mounted read-only, or was switched to read-only on errors.
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only || self.remounted_read_only.load(Ordering::Acquire)
+    }
+
+    /// Fails with `Error::ReadOnly` on read-only mounts, checked by every modifying method.
+    fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Runs an operation of a public method. Modifying ones, with an `update` other than
+    /// `Update::None`, fail on read-only mounts. The outcome goes through `complete`.
+    fn run<T>(&mut self, update: Update, op: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if update != Update::None {
+            self.check_writable()?;
+        }
+        let res = op(self);
+        self.complete(res, update)
+    }
+
+    /// Applies the mount options to the outcome of an operation: flushes after updates in
+    /// `sync` / `dirsync` mode, and reacts to corruption as set by `errors`.
+    fn complete<T>(&self, res: Result<T>, update: Update) -> Result<T> {
+        match res {
+            Ok(value) => {
+                let flush = match update {
+                    Update::None => false,
+                    Update::Data => self.options.sync,
+                    Update::Namespace => self.options.sync || self.options.dirsync,
+                };
+                if flush {
+                    self.flush()?;
+                }
+                Ok(value)
+            },
+            Err(e) if e.is_corruption() => {
+                self.corruption_detected.store(true, Ordering::Release);
+                match self.options.errors {
+                    ErrorBehavior::Continue => {},
+                    ErrorBehavior::RemountReadOnly => self.remounted_read_only.store(true, Ordering::Release),
+                    ErrorBehavior::Panic => panic!("muon: corruption detected: {:?}", e),
+                }
+                Err(e)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resolves a path, following symlinks, with corruption handled as set by `errors`.
+    fn resolve_path(&mut self, path: &str) -> Result<u32> {
+        let res = resolve(&*self.device, &mut self.superblock, path).map(|(_, inode_id)| inode_id);
+        self.complete(res, Update::None)
+    }
+
+    /// Writes everything back to the device, refreshing the superblock backups.
     pub fn flush(&self) -> Result<()> {
+        if !self.is_read_only() {
+            write_superblock_with_backups(self.device.as_ref(), &self.superblock)?;
+        }
         self.device.flush()?;
+        self.new_files.lock().clear();
         Ok(())
     }
 
+    /// Makes the file at the given path durable, without flushing the rest of the device:
+    /// its data, block pointers and inode. A file created since the last sync has its
+    /// parent directory synced as well, so that it can be found after a crash.
+    /// Symlinks are followed.
+    pub fn fsync(&mut self, path: &str) -> Result<()> {
+        let inode_id = self.resolve_path(path)?;
+        self.fsync_by_inode_id(inode_id)
+    }
+
+    pub fn fsync_by_inode_id(&mut self, inode_id: u32) -> Result<()> {
+        self.run(Update::None, |fs| {
+            let inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            let parent_ids: Vec<u32> = fs.new_files.lock()
+                .range((inode_id, 0)..=(inode_id, u32::MAX))
+                .map(|&(_, parent_id)| parent_id)
+                .collect();
+            if parent_ids.is_empty() {
+                return fsync(fs.device.as_ref(), &fs.superblock, &inode, None);
+            }
+            for parent_id in parent_ids {
+                let parent = get_inode(&*fs.device, &fs.superblock, parent_id)?;
+                fsync(fs.device.as_ref(), &fs.superblock, &inode, Some(&parent))?;
+                fs.new_files.lock().remove(&(inode_id, parent_id));
+            }
+            Ok(())
+        })
+    }
+
+    /// Like `fsync`, but only the file's contents and the metadata needed to read them back are
+    /// made durable. The parent directory of a new file is not synced.
+    pub fn fdatasync(&mut self, path: &str) -> Result<()> {
+        let inode_id = self.resolve_path(path)?;
+        self.fdatasync_by_inode_id(inode_id)
+    }
+
+    pub fn fdatasync_by_inode_id(&mut self, inode_id: u32) -> Result<()> {
+        self.run(Update::None, |fs| {
+            let inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            fsync(fs.device.as_ref(), &fs.superblock, &inode, None)
+        })
+    }
+
+    /// Verifies every checksum of the file system, see `scrub`.
+    /// Damage found keeps the file system dirty on unmount, but is otherwise only reported.
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let report = scrub(self.device.as_ref(), &self.superblock)?;
+        if !report.is_clean() {
+            self.corruption_detected.store(true, Ordering::Release);
+        }
+        Ok(report)
+    }
+
     pub fn get_inode(&self, inode_id: u32) -> Result<Inode> {
-        get_inode(self.device.as_ref(), &self.superblock, inode_id)
+        let res = get_inode(self.device.as_ref(), &self.superblock, inode_id);
+        self.complete(res, Update::None)
     }
 
-    /// Unmounts the filesystem, writing the superblock back to the device.
+    /// Unmounts the filesystem, writing the superblock back to the device and marking it clean,
+    /// unless corruption was detected.
     /// This should be called before the device is closed to ensure all metadata is saved.
     pub fn unmount(&self) -> Result<()> {
-        write_superblock(self.device.as_ref(), &self.superblock)?;
-        self.device.flush()?;
-        Ok(())
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let mut superblock = self.superblock;
+        if !self.corruption_detected.load(Ordering::Acquire) {
+            superblock.state = FS_STATE_CLEAN;
+        }
+        // Everything else reaches the disk before the superblock.
+        commit_superblock(self.device.as_ref(), &superblock)
     }
     
     /// Query the inode ID for the given path.
     /// Returns the inode ID and its file type.
     pub fn lookup(&mut self, path: &str) -> Result<(u32, FileType)> {
-        let (_, inode_id) = resolve(&*self.device, &mut self.superblock, path)?;
-        let inode = get_inode(&*self.device, &self.superblock, inode_id)?;
-        Ok((inode_id, inode.ftype))
+        self.run(Update::None, |fs| {
+            let (_, inode_id) = resolve(&*fs.device, &mut fs.superblock, path)?;
+            let inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            Ok((inode_id, inode.ftype))
+        })
     }
 
     pub fn canonicalize(&mut self, path: &str) -> Result<String> {
-        canonicalize(
+        let res = canonicalize(
             self.device.as_ref(), 
             &mut self.superblock, 
             path, 
             false,
-        )
+        );
+        self.complete(res, Update::None)
     }
 
     pub fn creat(
@@ -124,104 +459,116 @@ impl<D: BlockDevice> FileSystem<D> {
         file_type: FileType,
         mode: Mode,
     ) -> Result<u32> {
-        let (parent_path, file_name) = split(path)?;
-        let (_, parent_inode_id) = resolve(self.device.as_ref(), &mut self.superblock, &parent_path)?;
-        let mut parent_inode = get_inode(&*self.device, &mut self.superblock, parent_inode_id)?;
-        if parent_inode.ftype != FileType::Directory {
-            return Err(Error::NotDirectory);
-        }
-        // println!("parent inode: {:?}", parent_inode);
-        match file_type {
-            FileType::Regular => {
-                let mut new_inode = alloc_inode(
-                    self.device.as_ref(), 
-                    &mut self.superblock,
-                    FileType::Regular, 
-                    mode
-                )?;
-                dir_add_entry(
-                    self.device.as_ref(),
-                    &mut self.superblock,
-                    &mut parent_inode,
-                    &DirEntry::new(new_inode.id, file_name.as_bytes())?
-                )?;
-                new_inode.links_cnt = 1;
-                write_inode(self.device.as_ref(), &self.superblock, &parent_inode)?;
-                write_inode(self.device.as_ref(), &mut self.superblock, &new_inode)?;
-                Ok(new_inode.id)
-            },
-            FileType::Directory => {
-                let dir_inode_id = mkdir(
-                    self.device.as_ref(), 
-                    &mut self.superblock, 
-                    &mut parent_inode, 
-                    file_name.as_bytes()
-                )?;
-                Ok(dir_inode_id)
-            },
-            _ => return Err(Error::InvalidArgument),
-        }
+        self.run(Update::Namespace, |fs| {
+            let (parent_path, file_name) = split(path)?;
+            let (_, parent_inode_id) = resolve(fs.device.as_ref(), &mut fs.superblock, &parent_path)?;
+            let mut parent_inode = get_inode(&*fs.device, &mut fs.superblock, parent_inode_id)?;
+            if parent_inode.ftype != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            // println!("parent inode: {:?}", parent_inode);
+            match file_type {
+                FileType::Regular => {
+                    let entry = DirEntry::new(0, file_name.as_bytes())?;
+                    let mut new_inode = alloc_inode(
+                        fs.device.as_ref(), 
+                        &mut fs.superblock,
+                        FileType::Regular, 
+                        mode
+                    )?;
+                    new_inode.links_cnt = 1;
+                    write_inode(fs.device.as_ref(), &mut fs.superblock, &new_inode)?;
+                    // The inode must be initialized on disk before any entry points to it.
+                    flush_dependencies(fs.device.as_ref(), &fs.superblock, inode_record_blocks(&fs.superblock, new_inode.id).to_vec())?;
+                    dir_add_entry(
+                        fs.device.as_ref(),
+                        &mut fs.superblock,
+                        &mut parent_inode,
+                        &DirEntry { inode_id: new_inode.id, ..entry }
+                    )?;
+                    write_inode(fs.device.as_ref(), &fs.superblock, &parent_inode)?;
+                    fs.new_files.lock().insert((new_inode.id, parent_inode_id));
+                    Ok(new_inode.id)
+                },
+                FileType::Directory => {
+                    let dir_inode_id = mkdir(
+                        fs.device.as_ref(), 
+                        &mut fs.superblock, 
+                        &mut parent_inode, 
+                        file_name.as_bytes()
+                    )?;
+                    fs.new_files.lock().insert((dir_inode_id, parent_inode_id));
+                    Ok(dir_inode_id)
+                },
+                _ => return Err(Error::InvalidArgument),
+            }
+        })
     }
 
     pub fn remove(&mut self, path: &str, ftype: FileType) -> Result<()> {
-        let (parent_path, file_name) = split(path)?;
-        let (_, parent_inode_id) =  resolve(&*self.device, &mut self.superblock, &parent_path)?;
-        let mut parent_inode = get_inode(&*self.device, &mut self.superblock, parent_inode_id)?;
-        if parent_inode.ftype != FileType::Directory {
-            return Err(Error::NotDirectory);
-        }
-        // println!("[remove] parent inode: {:?}", parent_inode);
-        let (_, inode_id) = if ftype != FileType::Symlink {
-            resolve(&*self.device, &mut self.superblock, path)?
-        } else {
-            resolve_without_last(&*self.device, &mut self.superblock, path)?
-        };
-        // println!("[remove] inode_id: {}", inode_id);
-        let mut file_inode = get_inode(&*self.device, &mut self.superblock, inode_id)?;
-        
-        if matches!(ftype, FileType::Special) {
-            return Err(Error::InvalidArgument);
-        }
-        if file_inode.ftype != ftype {
-            return Err(Error::InvalidArgument);
-        }
-
-        if ftype == FileType::Directory && !dir_is_empty(self.device.as_ref(), &mut self.superblock, &file_inode)? {
-            return Err(Error::DirNotEmpty);
-        }
-
-        dir_rm_entry(
-            &*self.device,
-            &mut self.superblock,
-            &mut parent_inode,
-            file_name.as_bytes(),
-        )?;
-
-        // Free the inode if hard links count reaches 0.
-        file_inode.links_cnt -= 1;
-        if ftype == FileType::Directory {
-            // .
+        self.run(Update::Namespace, |fs| {
+            let (parent_path, file_name) = split(path)?;
+            let (_, parent_inode_id) =  resolve(&*fs.device, &mut fs.superblock, &parent_path)?;
+            let mut parent_inode = get_inode(&*fs.device, &mut fs.superblock, parent_inode_id)?;
+            if parent_inode.ftype != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            // println!("[remove] parent inode: {:?}", parent_inode);
+            let (_, inode_id) = if ftype != FileType::Symlink {
+                resolve(&*fs.device, &mut fs.superblock, path)?
+            } else {
+                resolve_without_last(&*fs.device, &mut fs.superblock, path)?
+            };
+            // println!("[remove] inode_id: {}", inode_id);
+            let mut file_inode = get_inode(&*fs.device, &mut fs.superblock, inode_id)?;
+
+            if matches!(ftype, FileType::Special) {
+                return Err(Error::InvalidArgument);
+            }
+            if file_inode.ftype != ftype {
+                return Err(Error::InvalidArgument);
+            }
+
+            if ftype == FileType::Directory && !dir_is_empty(fs.device.as_ref(), &mut fs.superblock, &file_inode)? {
+                return Err(Error::DirNotEmpty);
+            }
+
+            dir_rm_entry(
+                &*fs.device,
+                &mut fs.superblock,
+                &mut parent_inode,
+                file_name.as_bytes(),
+            )?;
+            // The entry must be gone on disk before the inode is released.
+            flush_dependencies(fs.device.as_ref(), &fs.superblock, sync_blocks(fs.device.as_ref(), &fs.superblock, &parent_inode)?)?;
+
+            // Free the inode if hard links count reaches 0.
             file_inode.links_cnt -= 1;
-            // ..
-            parent_inode.links_cnt -= 1;
-            write_inode(self.device.as_ref(), &mut self.superblock, &parent_inode)?;
-        }
-
-        if file_inode.links_cnt == 0 {
-        //     println!("[remove] Freeing inode: {}", file_inode.id);
-            free_inode(self.device.as_ref(), &mut self.superblock, file_inode.id)?;
-        } else {
-            write_inode(self.device.as_ref(), &mut self.superblock, &file_inode)?;
-        }
-
-        Ok(())
+            if ftype == FileType::Directory {
+                // .
+                file_inode.links_cnt -= 1;
+                // ..
+                parent_inode.links_cnt -= 1;
+                write_inode(fs.device.as_ref(), &mut fs.superblock, &parent_inode)?;
+            }
+
+            if file_inode.links_cnt == 0 {
+            //     println!("[remove] Freeing inode: {}", file_inode.id);
+                free_inode(fs.device.as_ref(), &mut fs.superblock, file_inode.id)?;
+                fs.new_files.lock().retain(|&(inode_id, _)| inode_id != file_inode.id);
+            } else {
+                write_inode(fs.device.as_ref(), &mut fs.superblock, &file_inode)?;
+            }
+
+            Ok(())
+        })
     }
 
     pub fn ftruncate(
         &mut self,
         path: &str
     ) -> Result<()> {
-        let (_, inode_id) = resolve(&*self.device, &mut self.superblock, path)?;
+        let inode_id = self.resolve_path(path)?;
         self.ftruncate_by_inode_id(inode_id)
     }
 
@@ -229,36 +576,79 @@ impl<D: BlockDevice> FileSystem<D> {
         &mut self,
         inode_id: u32,
     ) -> Result<()> {
-        let mut inode = get_inode(&*self.device, &self.superblock, inode_id)?;
-        if inode.ftype != FileType::Regular {
-            return Err(Error::NotRegular);
-        }
-        if !matches!(inode.mode, Mode::Write|Mode::RW|Mode::RWE) {
-            return Err(Error::PermissionDenied);
-        }
-        
-        ftruncate(
-            self.device.as_ref(),
-            &mut self.superblock,
-            &mut inode,
-        )?;
+        self.run(Update::Data, |fs| {
+            let mut inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            if inode.ftype != FileType::Regular {
+                return Err(Error::NotRegular);
+            }
+            if !matches!(inode.mode, Mode::Write|Mode::RW|Mode::RWE) {
+                return Err(Error::PermissionDenied);
+            }
+
+            ftruncate(
+                fs.device.as_ref(),
+                &mut fs.superblock,
+                &mut inode,
+            )?;
+
+            Ok(())
+        })
+    }
 
-        Ok(())
+    /// Grows the file system onto blocks added at the end of its device, up to `new_num_blocks`.
+    /// See `resize::grow`.
+    pub fn grow(&mut self, new_num_blocks: u32) -> Result<()> {
+        self.run(Update::Data, |fs| grow(&*fs.device, &mut fs.superblock, new_num_blocks))
     }
 
-    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
-        let (_, inode_id) = resolve(&*self.device, &mut self.superblock, path)?;
-        let mut inode = get_inode(&*self.device, &self.superblock, inode_id)?;
-        if inode.ftype != FileType::Directory {
-            return Err(Error::NotDirectory);
-        }
-        let entries = read_dir(
-            self.device.as_ref(), 
-            &mut self.superblock, 
-            &mut inode
-        )?;
+    /// Discards all free data blocks on the device, like `fstrim`.
+    /// Blocks freed later are discarded as they are released, this catches up on the rest,
+    /// e.g. after formatting or when the device did not support discarding before.
+    /// Returns the number of blocks discarded.
+    pub fn trim_free_space(&mut self) -> Result<u32> {
+        self.run(Update::Data, |fs| trim_free_blocks(&*fs.device, &fs.superblock))
+    }
 
-        Ok(entries)
+    /// Changes the permissions of the file at the given path.
+    /// Symlinks are followed.
+    pub fn chmod(
+        &mut self,
+        path: &str,
+        mode: Mode,
+    ) -> Result<()> {
+        let inode_id = self.resolve_path(path)?;
+        self.chmod_by_inode_id(inode_id, mode)
+    }
+
+    /// Changes the permissions of the inode with the given id.
+    pub fn chmod_by_inode_id(
+        &mut self,
+        inode_id: u32,
+        mode: Mode,
+    ) -> Result<()> {
+        self.run(Update::Data, |fs| {
+            let mut inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            inode.mode = mode;
+            write_inode(fs.device.as_ref(), &fs.superblock, &inode)?;
+            Ok(())
+        })
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+        self.run(Update::None, |fs| {
+            let (_, inode_id) = resolve(&*fs.device, &mut fs.superblock, path)?;
+            let mut inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            if inode.ftype != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            let entries = read_dir(
+                fs.device.as_ref(), 
+                &mut fs.superblock, 
+                &mut inode
+            )?;
+
+            Ok(entries)
+        })
     }
 
     pub fn fread(
@@ -267,7 +657,7 @@ impl<D: BlockDevice> FileSystem<D> {
         offset: usize,
         buf: &mut [u8],
     ) -> Result<usize> {
-        let (_, inode_id) = resolve(&*self.device, &mut self.superblock, path)?;
+        let inode_id = self.resolve_path(path)?;
         self.fread_by_inode(inode_id, offset, buf)
     }
 
@@ -277,27 +667,35 @@ impl<D: BlockDevice> FileSystem<D> {
         offset: usize,
         buf: &mut [u8],
     ) -> Result<usize> {
-        let mut inode = get_inode(&*self.device, &self.superblock, inode_id)?; 
-        if inode.ftype != FileType::Regular {
-            return Err(Error::NotRegular);
-        }
-
-        if !matches!(inode.mode, Mode::Read|Mode::RW|Mode::RWE) {
-            return Err(Error::PermissionDenied);
-        }
-        let bytes_read = fread(
-            self.device.as_ref(),
-            &mut self.superblock,
-            &mut inode,
-            offset,
-            buf,
-        )?;
-
-        if bytes_read == 0 {
-            return Err(Error::EOF(Some(bytes_read)));
-        }
+        self.run(Update::None, |fs| {
+            let mut inode = get_inode(&*fs.device, &fs.superblock, inode_id)?; 
+            if inode.ftype != FileType::Regular {
+                return Err(Error::NotRegular);
+            }
+
+            if !matches!(inode.mode, Mode::Read|Mode::RW|Mode::RWE) {
+                return Err(Error::PermissionDenied);
+            }
+            let bytes_read = fread_ahead(
+                fs.device.as_ref(),
+                &mut fs.superblock,
+                &mut inode,
+                offset,
+                buf,
+                &mut fs.readahead,
+            )?;
+
+            if bytes_read == 0 {
+                return Err(Error::EOF(Some(bytes_read)));
+            }
+
+            Ok(bytes_read)
+        })
+    }
 
-        Ok(bytes_read)   
+    /// Sets the maximum read-ahead window of `fread`, in blocks. 0 disables read-ahead.
+    pub fn set_readahead(&mut self, max_blocks: usize) {
+        self.readahead = ReadAhead::new(max_blocks);
     }
 
     pub fn fwrite(
@@ -306,7 +704,7 @@ impl<D: BlockDevice> FileSystem<D> {
         offset: usize,
         buf: &[u8],
     ) -> Result<usize> {
-        let (_, inode_id) = resolve(&*self.device, &mut self.superblock, path)?;
+        let inode_id = self.resolve_path(path)?;
         self.fwrite_by_inode(inode_id, offset, buf)
     }
 
@@ -316,21 +714,23 @@ impl<D: BlockDevice> FileSystem<D> {
         offset: usize,
         buf: &[u8],
     ) -> Result<usize> {
-        let mut inode = get_inode(&*self.device, &self.superblock, inode_id)?;
-        if inode.ftype != FileType::Regular {
-            return Err(Error::NotRegular);
-        }
-        if !matches!(inode.mode, Mode::Write|Mode::RW|Mode::RWE) {
-            return Err(Error::PermissionDenied);
-        }
-        let bytes_written = fwrite(
-            self.device.as_ref(),
-            &mut self.superblock,
-            &mut inode,
-            offset,
-            buf,
-        )?;
-        Ok(bytes_written)
+        self.run(Update::Data, |fs| {
+            let mut inode = get_inode(&*fs.device, &fs.superblock, inode_id)?;
+            if inode.ftype != FileType::Regular {
+                return Err(Error::NotRegular);
+            }
+            if !matches!(inode.mode, Mode::Write|Mode::RW|Mode::RWE) {
+                return Err(Error::PermissionDenied);
+            }
+            let bytes_written = fwrite(
+                fs.device.as_ref(),
+                &mut fs.superblock,
+                &mut inode,
+                offset,
+                buf,
+            )?;
+            Ok(bytes_written)
+        })
     }
 
     /// Creates a hard link to the target file with the given link name.
@@ -343,76 +743,95 @@ impl<D: BlockDevice> FileSystem<D> {
         target: &str,
         link_name: &str,
     ) -> Result<u32> {
-        let (parent_path, link_name) = path::split(link_name)?;
-        let (_, parent_inode_id) = resolve(&*self.device, &mut self.superblock, &parent_path)?;
-        let mut parent_inode = get_inode(&*self.device, &mut self.superblock, parent_inode_id)?;
-        if parent_inode.ftype != FileType::Directory {
-            return Err(Error::NotDirectory);
-        }
-        let (_, target_inode_id) = resolve(&*self.device, &mut self.superblock, target)?;
-        let mut target_inode = get_inode(&*self.device, &mut self.superblock, target_inode_id)?;
-        if target_inode.ftype != FileType::Regular {
-            return Err(Error::NotRegular);
-        }
-        dir_add_entry(
-            self.device.as_ref(),
-            &mut self.superblock,
-            &mut parent_inode,
-            &DirEntry::new(target_inode_id, link_name.as_bytes())?,
-        )?;
-        target_inode.links_cnt += 1;
-        write_inode(self.device.as_ref(), &mut self.superblock, &target_inode)?;
-
-        Ok(target_inode_id)
+        self.run(Update::Namespace, |fs| {
+            let (parent_path, link_name) = path::split(link_name)?;
+            let (_, parent_inode_id) = resolve(&*fs.device, &mut fs.superblock, &parent_path)?;
+            let mut parent_inode = get_inode(&*fs.device, &mut fs.superblock, parent_inode_id)?;
+            if parent_inode.ftype != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            let (_, target_inode_id) = resolve(&*fs.device, &mut fs.superblock, target)?;
+            let mut target_inode = get_inode(&*fs.device, &mut fs.superblock, target_inode_id)?;
+            if target_inode.ftype != FileType::Regular {
+                return Err(Error::NotRegular);
+            }
+            let entry = DirEntry::new(target_inode_id, link_name.as_bytes())?;
+
+            // Count the link before adding it, a crash in between leaves a count fsck can lower.
+            target_inode.links_cnt += 1;
+            write_inode(fs.device.as_ref(), &mut fs.superblock, &target_inode)?;
+            flush_dependencies(fs.device.as_ref(), &fs.superblock, inode_record_blocks(&fs.superblock, target_inode_id).to_vec())?;
+            if let Err(e) = dir_add_entry(
+                fs.device.as_ref(),
+                &mut fs.superblock,
+                &mut parent_inode,
+                &entry,
+            ) {
+                target_inode.links_cnt -= 1;
+                write_inode(fs.device.as_ref(), &fs.superblock, &target_inode)?;
+                return Err(e);
+            }
+            fs.new_files.lock().insert((target_inode_id, parent_inode_id));
+
+            Ok(target_inode_id)
+        })
     }
 
     /// Creates a symbolic link to the target file with the given link name.
-    /// Generates only absolute paths.
+    /// Generates only absolute paths. Targets longer than `MAX_PATH_LEN`, up to `MAX_SYMLINK_LEN`,
+    /// are stored in data blocks.
     /// Returns the inode ID of the symlink.
     pub fn symlink(
         &mut self,
         target: &str,
         link_name: &str,
     ) -> Result<u32> {
-        if target.as_bytes().len() > MAX_PATH_LEN {
-            return Err(Error::PathTooLong);
-        }
-
-        let (parent_path, link_name) = path::split(link_name)?;
-        let (_, parent_inode_id) = resolve(&*self.device, &mut self.superblock, &parent_path)?;
-        let mut parent_inode = get_inode(&*self.device, &mut self.superblock, parent_inode_id)?;
-        if parent_inode.ftype != FileType::Directory {
-            return Err(Error::NotDirectory);
-        }
-        let mut new_inode = alloc_inode(
-            self.device.as_ref(),
-            &mut self.superblock,
-            FileType::Symlink,
-            Mode::Read,
-        )?;
-        let path_buf = new_inode.get_path_mut()?;
-        path_buf[..target.as_bytes().len()].copy_from_slice(target.as_bytes());
-        dir_add_entry(
-            self.device.as_ref(),
-            &mut self.superblock,
-            &mut parent_inode,
-            &DirEntry::new(new_inode.id, link_name.as_bytes())?,
-        )?;
-        new_inode.links_cnt = 1; // symlink itself
-        write_inode(self.device.as_ref(), &mut self.superblock, &new_inode)?;
-        
-        Ok(new_inode.id)
+        self.run(Update::Namespace, |fs| {
+            if target.len() > MAX_SYMLINK_LEN {
+                return Err(Error::PathTooLong);
+            }
+
+            let (parent_path, link_name) = path::split(link_name)?;
+            let (_, parent_inode_id) = resolve(&*fs.device, &mut fs.superblock, &parent_path)?;
+            let mut parent_inode = get_inode(&*fs.device, &mut fs.superblock, parent_inode_id)?;
+            if parent_inode.ftype != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            let mut new_inode = alloc_inode(
+                fs.device.as_ref(),
+                &mut fs.superblock,
+                FileType::Symlink,
+                Mode::Read,
+            )?;
+            new_inode.links_cnt = 1; // symlink itself
+            // Freed along with its target blocks if the link cannot be made.
+            let linked = write_link_target(fs.device.as_ref(), &mut fs.superblock, &mut new_inode, target.as_bytes())
+                .and_then(|()| flush_dependencies(fs.device.as_ref(), &fs.superblock, sync_blocks(fs.device.as_ref(), &fs.superblock, &new_inode)?))
+                .and_then(|()| dir_add_entry(
+                    fs.device.as_ref(),
+                    &mut fs.superblock,
+                    &mut parent_inode,
+                    &DirEntry::new(new_inode.id, link_name.as_bytes())?,
+                ));
+            if let Err(e) = linked {
+                free_inode(fs.device.as_ref(), &mut fs.superblock, new_inode.id)?;
+                return Err(e);
+            }
+            fs.new_files.lock().insert((new_inode.id, parent_inode_id));
+
+            Ok(new_inode.id)
+        })
     }
 
-    /// Reads the target of a symbolic link.
-    /// Returns a byte array containing the target path.
+    /// Reads the target of a symbolic link, zero padded.
+    /// Fails with `Error::PathTooLong` for targets longer than `MAX_PATH_LEN`, which
+    /// `read_link_into` reads.
     pub fn read_link(
         &mut self,
         link_name: &str,
         buf: &mut [u8; MAX_PATH_LEN],
     ) -> Result<()> {
-        let (_, inode_id) = resolve_without_last(self.device.as_ref(), &mut self.superblock, link_name)?;
-        self.read_link_by_inode_id(inode_id, buf)
+        self.read_link_into(link_name, buf).map(|_| ())
     } 
 
     pub fn read_link_by_inode_id(
@@ -420,13 +839,40 @@ impl<D: BlockDevice> FileSystem<D> {
         inode_id: u32,
         buf: &mut [u8; MAX_PATH_LEN],
     ) -> Result<()> {
-        let inode = get_inode(self.device.as_ref(), &self.superblock, inode_id)?;
-        if inode.ftype != FileType::Symlink {
-            return Err(Error::NotSymlink);
-        }
-        let path_buf = inode.get_path()?;
-        buf.copy_from_slice(path_buf);
-        Ok(())
+        self.read_link_into_by_inode_id(inode_id, buf).map(|_| ())
+    }
+
+    /// Reads the target of a symbolic link into `buf`, zero padded.
+    /// Returns the length of the target, `Error::PathTooLong` if it does not fit:
+    /// a buffer of `MAX_SYMLINK_LEN` bytes holds any target.
+    pub fn read_link_into(
+        &mut self,
+        link_name: &str,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let res = resolve_without_last(self.device.as_ref(), &mut self.superblock, link_name);
+        let (_, inode_id) = self.complete(res, Update::None)?;
+        self.read_link_into_by_inode_id(inode_id, buf)
+    }
+
+    pub fn read_link_into_by_inode_id(
+        &mut self,
+        inode_id: u32,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.run(Update::None, |fs| {
+            let inode = get_inode(fs.device.as_ref(), &fs.superblock, inode_id)?;
+            if inode.ftype != FileType::Symlink {
+                return Err(Error::NotSymlink);
+            }
+            let target = link_target(fs.device.as_ref(), &fs.superblock, &inode)?;
+            if target.len() > buf.len() {
+                return Err(Error::PathTooLong);
+            }
+            buf[..target.len()].copy_from_slice(&target);
+            buf[target.len()..].fill(0);
+            Ok(target.len())
+        })
     }
 
     pub fn root_inode_id(&self) -> u32 {
@@ -441,6 +887,11 @@ impl<D: BlockDevice> FileSystem<D> {
         Arc::clone(&self.device)
     }
 
+    /// File system usage statistics, as reported by `df`.
+    pub fn statfs(&self) -> StatFs {
+        self.superblock.statfs()
+    }
+
     pub fn dump(&self) -> String {
         alloc::format!("{:?}", self.superblock)
     }
@@ -1,12 +1,26 @@
 //! Management of reading and writing to inodes in inode table.
 
-use alloc::boxed::Box;
 use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+use core::ops::Range;
 
-use crate::{bitmap, FileType, Inode, Mode, Result, SuperBlock, BLOCK_SIZE, INODE_SIZE, NUM_DIRECT_PTRS, PTRS_PER_BLOCK};
-use crate::BlockDevice;
+use crate::{bitmap, trim_zero, write_superblock, FileType, Inode, InodeExtra, InodePtr, Mode, Result, SuperBlock, BLOCK_SIZE, INCOMPAT_LONG_SYMLINK, INODE_SIZE, MAX_PATH_LEN, MAX_SYMLINK_LEN, NUM_DIRECT_PTRS, PTRS_PER_BLOCK};
+use crate::{BlockDevice, BufferHead};
 use crate::error::FsError;
-use crate::bitmap::{alloc_data_block, free_data_block};
+use crate::bitmap::{alloc_data_block, claim_data_block, discard_blocks, free_data_block};
+use crate::file::{flush_dependencies, inode_record_blocks};
+use crate::checksum::{get_meta_block, has_data_csum, has_metadata_csum, put_meta_block, seal_inode, update_data_checksum, verify_data_block, verify_inode};
+
+/// Block of the inode table holding the given inode.
+pub(crate) fn inode_block_id(superblock: &SuperBlock, inode_id: u32) -> u32 {
+    superblock.inode_table_start + inode_id / superblock.inodes_per_block()
+}
+
+/// Byte offset of an inode record within its block of the inode table.
+fn inode_offset(superblock: &SuperBlock, inode_id: u32) -> usize {
+    (inode_id % superblock.inodes_per_block()) as usize * superblock.inode_size()
+}
 
 /// Query an inode by its ID.
 pub fn get_inode(
@@ -18,16 +32,37 @@ pub fn get_inode(
         return Err(FsError::OutOfBounds);
     }
     
-    let block_id = superblock.inode_table_start + (inode_id / (BLOCK_SIZE / INODE_SIZE) as u32);
-    let block_inner_offset = (inode_id % (BLOCK_SIZE / INODE_SIZE) as u32) * INODE_SIZE as u32;
-    let mut buf = Box::new([0u8; BLOCK_SIZE]);
-    device.read_block(block_id, buf.as_mut())?;
-    
-    let inode = unsafe {
-        core::ptr::read_unaligned(buf.as_ptr().add(block_inner_offset as usize) as *const Inode)
-    };
+    let block_id = inode_block_id(superblock, inode_id);
+    let block_inner_offset = inode_offset(superblock, inode_id);
+    let bh = device.get_block(block_id)?;
+    if has_metadata_csum(superblock) {
+        verify_inode(&bh, block_inner_offset, superblock.inode_size())?;
+    }
+    Ok(bh.read_obj::<Inode>(block_inner_offset))
+}
 
-    Ok(inode)
+/// Query the extra fields of an inode, `None` with `INODE_SIZE` records.
+/// Fields past its `extra_size` read as zero.
+pub fn get_inode_extra(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    inode_id: u32,
+) -> Result<Option<InodeExtra>> {
+    if superblock.inode_size() == INODE_SIZE {
+        return Ok(None);
+    }
+    get_inode(device, superblock, inode_id)?;
+    let bh = device.get_block(inode_block_id(superblock, inode_id))?;
+    let extra = bh.read_obj::<[u8; size_of::<InodeExtra>()]>(inode_offset(superblock, inode_id) + INODE_SIZE);
+    let extra_size = u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    let field = |offset: usize| match offset + 2 <= extra_size {
+        true => u16::from_le_bytes([extra[offset], extra[offset + 1]]),
+        false => 0,
+    };
+    Ok(Some(InodeExtra {
+        extra_size: field(offset_of!(InodeExtra, extra_size)),
+        checksum_hi: field(offset_of!(InodeExtra, checksum_hi)),
+    }))
 }
 
 /// Write an inode to inode table.
@@ -38,18 +73,79 @@ pub fn write_inode(
     superblock: &SuperBlock,
     inode: &Inode
 ) -> Result<()> {
-    let block_id = superblock.inode_table_start + (inode.id / (BLOCK_SIZE / INODE_SIZE) as u32);
-    let block_inner_offset = (inode.id % (BLOCK_SIZE / INODE_SIZE) as u32) * INODE_SIZE as u32;
-    let mut buf = Box::new([0u8; BLOCK_SIZE]);
-    device.read_block(block_id, buf.as_mut())?;
-    unsafe {
-        core::ptr::write_unaligned(
-            buf.as_mut_ptr().add(block_inner_offset as usize) as *mut Inode,
-            *inode
-        );
-    }
-    device.write_block(block_id, buf.as_ref())?;
-    Ok(())
+    // Only the inode's own bytes are touched, the rest of the block stays as cached.
+    // The extra fields are kept as they are.
+    let bh = device.get_block(inode_block_id(superblock, inode.id))?;
+    store_inode(&bh, superblock, inode);
+    device.put_block(bh)
+}
+
+/// Writes the base record of an inode into its cached inode table block, sealing the whole record.
+fn store_inode(bh: &BufferHead, superblock: &SuperBlock, inode: &Inode) {
+    let block_inner_offset = inode_offset(superblock, inode.id);
+    bh.write_obj(block_inner_offset, &Inode { checksum: 0, ..*inode });
+    if has_metadata_csum(superblock) {
+        seal_inode(bh, block_inner_offset, superblock.inode_size());
+    }
+}
+
+/// Bytes of the inode table block holding the inline data of an inode past its pointer area.
+fn inline_tail(superblock: &SuperBlock, inode_id: u32) -> Range<usize> {
+    let start = inode_offset(superblock, inode_id) + INODE_SIZE + superblock.inode_extra_size as usize;
+    start..start + superblock.max_inline_size().saturating_sub(MAX_PATH_LEN)
+}
+
+/// Reads the contents of an inode with inline data.
+pub(crate) fn read_inline(device: &impl BlockDevice, superblock: &SuperBlock, inode: &Inode) -> Result<Vec<u8>> {
+    let len = inode.size as usize;
+    if len > superblock.max_inline_size() {
+        return Err(FsError::Corrupted);
+    }
+    let mut data = inode.get_inline_data()?[..len.min(MAX_PATH_LEN)].to_vec();
+    if len > MAX_PATH_LEN {
+        let tail = inline_tail(superblock, inode.id);
+        let bh = device.get_block(inode_block_id(superblock, inode.id))?;
+        data.extend_from_slice(&bh.lock()[tail.start..tail.start + len - MAX_PATH_LEN]);
+    }
+    Ok(data)
+}
+
+/// Replaces the contents of an inode without data blocks by `data`, stored inline,
+/// and writes the inode with its new size.
+pub(crate) fn write_inline(device: &impl BlockDevice, superblock: &SuperBlock, inode: &mut Inode, data: &[u8]) -> Result<()> {
+    if data.len() > superblock.max_inline_size() || inode.blocks != 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    let (head, rest) = data.split_at(data.len().min(MAX_PATH_LEN));
+    let area = inode.get_inline_data_mut()?;
+    area.fill(0);
+    area[..head.len()].copy_from_slice(head);
+    inode.size = data.len() as u64;
+
+    // The tail and the record go together, so that the record is never sealed without its tail.
+    let tail = inline_tail(superblock, inode.id);
+    let bh = device.get_block(inode_block_id(superblock, inode.id))?;
+    {
+        let mut block = bh.lock();
+        block[tail.clone()].fill(0);
+        block[tail.start..tail.start + rest.len()].copy_from_slice(rest);
+    }
+    store_inode(&bh, superblock, inode);
+    device.put_block(bh)
+}
+
+/// Resets the extra fields of a new inode, to those of the superblock's `inode_extra_size`.
+fn init_inode_extra(device: &impl BlockDevice, superblock: &SuperBlock, inode_id: u32) -> Result<()> {
+    let extra_area = superblock.inode_size() - INODE_SIZE;
+    if extra_area == 0 {
+        return Ok(());
+    }
+    let offset = inode_offset(superblock, inode_id) + INODE_SIZE;
+    let bh = device.get_block(inode_block_id(superblock, inode_id))?;
+    bh.lock()[offset..offset + extra_area].fill(0);
+    let extra_size = (superblock.inode_extra_size as usize).min(extra_area) as u16;
+    bh.write_obj(offset, &extra_size.to_le_bytes());
+    device.put_block(bh)
 }
 
 /// Allocates a new inode with the given file type and mode.
@@ -64,10 +160,101 @@ pub fn alloc_inode(
 
     // Superblock already updated by alloc_inode_id.
     let inode = Inode::new(ftype, mode, id);
+    init_inode_extra(device, superblock, id)?;
     write_inode(device, superblock, &inode)?;
     Ok(inode)
 }
 
+/// Stores the target of a new symlink and writes its inode: in the inode if it fits in
+/// `MAX_PATH_LEN` bytes, in data blocks otherwise, enabling `INCOMPAT_LONG_SYMLINK`.
+pub(crate) fn write_link_target(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    inode: &mut Inode,
+    target: &[u8],
+) -> Result<()> {
+    if target.len() > MAX_SYMLINK_LEN {
+        return Err(FsError::PathTooLong);
+    }
+    inode.size = target.len() as u64;
+    if target.len() <= MAX_PATH_LEN {
+        inode.get_path_mut()?[..target.len()].copy_from_slice(target);
+        return write_inode(device, superblock, inode);
+    }
+
+    if !superblock.has_incompat(INCOMPAT_LONG_SYMLINK) {
+        superblock.feature_incompat |= INCOMPAT_LONG_SYMLINK;
+        write_superblock(device, superblock)?;
+    }
+    let mut block_ids = Vec::new();
+    let stored = target.chunks(BLOCK_SIZE).try_for_each(|chunk| {
+        let block_id = alloc_data_block(device, superblock)?;
+        block_ids.push(block_id);
+        let bh = device.get_block(block_id)?;
+        bh.lock()[..chunk.len()].copy_from_slice(chunk);
+        bh.mark_dirty();
+        put_meta_block(device, superblock, bh)
+    }).and_then(|()| {
+        // The target is in place before the inode refers to it.
+        inode.inode_ptr = InodePtr::ZERO;
+        inode.blocks = block_ids.len() as u32;
+        for (ptr, &block_id) in inode.get_block_ptrs_mut()?.direct.iter_mut().zip(&block_ids) {
+            *ptr = Some(block_id);
+        }
+        write_inode(device, superblock, inode)
+    });
+    // Blocks of a target not stored whole are freed, the inode does not refer to them.
+    if let Err(e) = stored {
+        for &block_id in block_ids.iter() {
+            free_data_block(device, superblock, block_id)?;
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Reads the target of a symlink, from the inode or from its data blocks.
+pub fn link_target(device: &impl BlockDevice, superblock: &SuperBlock, inode: &Inode) -> Result<Vec<u8>> {
+    if !inode.is_slow_symlink() {
+        return Ok(trim_zero(inode.get_path()?).to_vec());
+    }
+    let len = inode.size as usize;
+    if len > MAX_SYMLINK_LEN || len.div_ceil(BLOCK_SIZE) != inode.blocks as usize {
+        return Err(FsError::Corrupted);
+    }
+    let mut target = Vec::with_capacity(len);
+    for ptr in inode.get_block_ptrs()?.direct.iter().take(inode.blocks as usize) {
+        let block_id = check_data_block(superblock, ptr.ok_or(FsError::Corrupted)?)?;
+        let data = get_meta_block(device, superblock, block_id)?.read_obj::<[u8; BLOCK_SIZE]>(0);
+        let n = (len - target.len()).min(BLOCK_SIZE);
+        target.extend_from_slice(&data[..n]);
+    }
+    Ok(target)
+}
+
+/// Collects the data blocks of an inode, followed by its indirect block if any.
+pub(crate) fn inode_blocks(device: &impl BlockDevice, inode: &Inode) -> Result<Vec<u32>> {
+    let mut block_ids = Vec::new();
+    if inode.has_inline_data() {
+        return Ok(block_ids);
+    }
+    match inode.ftype {
+        // Special files own no data blocks, and fast symlinks keep their target in the inode.
+        FileType::Special => {},
+        FileType::Symlink if !inode.is_slow_symlink() => {},
+        _ => {
+            let block_ptrs = inode.get_block_ptrs()?;
+            block_ids.extend(block_ptrs.direct.iter().flatten());
+            if let Some(indirect_ptr) = block_ptrs.indirect {
+                let ptrs = device.get_block(indirect_ptr)?.read_obj::<[u32; PTRS_PER_BLOCK]>(0);
+                block_ids.extend(ptrs.iter().filter(|&&block_id| block_id != 0));
+                block_ids.push(indirect_ptr);
+            }
+        }
+    }
+    Ok(block_ids)
+}
+
 /// Frees an inode and all its data blocks, clearing according bitmap entry.
 /// This function does not remove the inode from the directory entries.
 /// Make sure to remove the inode from the directory entries before calling this function.
@@ -78,53 +265,36 @@ pub fn free_inode(
     inode_id: u32
 ) -> Result<Inode> {
     let inode = get_inode(device, superblock, inode_id)?;
-
-    match inode.ftype {
-        FileType::Special => unimplemented!(),
-        FileType::Symlink => {
-            // Symlinks do not have data blocks, for now.
-            // So do nothing here.
-        },
-        _ => {
-            //Direct blocks
-            for block_id in inode.get_block_ptrs()?.direct.iter() {
-                if let Some(block_id) = block_id {
-                    free_data_block(device, superblock, *block_id)?;
-                }
-            }
-
-            //Indirect block
-            if let Some(indirect_ptr) = inode.get_block_ptrs()?.indirect {
-                let mut ptr_buf = Box::new([0u8; BLOCK_SIZE]);
-                device.read_block(indirect_ptr, ptr_buf.as_mut())?;
-                let ptrs = unsafe {
-                    core::slice::from_raw_parts_mut(
-                        ptr_buf.as_mut_ptr() as *mut u32,
-                        PTRS_PER_BLOCK
-                    )
-                };
-                for &block_id in ptrs.iter() {
-                    if block_id != 0 {
-                        free_data_block(device, superblock, block_id)?;
-                    }
-                }
-                free_data_block(device, superblock, indirect_ptr)?;
-            }
-        }
-    }
-
-    bitmap::free_inode_id(device, superblock, inode_id)?;
+    // Blocks to free, also discarded in runs at the end.
+    let mut freed = inode_blocks(device, &inode)?;
 
     // Free inode record in inode table.
+    // The record goes first: a crash before the bitmaps are updated only leaks the blocks,
+    // while freed blocks still referenced by the inode could be handed out twice.
     write_inode(device, superblock, &Inode {
         id: inode_id,
         ..Inode::ZERO
     })?;
+    flush_dependencies(device, superblock, inode_record_blocks(superblock, inode_id).to_vec())?;
+
+    for &block_id in freed.iter() {
+        free_data_block(device, superblock, block_id)?;
+    }
+    bitmap::free_inode_id(device, superblock, inode_id)?;
+    discard_blocks(device, &mut freed);
 
     Ok(inode)
 }
 
 
+/// Checks that a block pointer read from disk lies in the data region.
+fn check_data_block(superblock: &SuperBlock, block_id: u32) -> Result<u32> {
+    if block_id < superblock.data_start || block_id >= superblock.num_blocks {
+        return Err(FsError::Corrupted);
+    }
+    Ok(block_id)
+}
+
 /// Block map. Maps a file offset to a block ID in the filesystem.
 /// The offset is required to be divided by BLOCK_SIZE.
 /// Would not manage the size of inode, which is caller's responsibility.
@@ -135,6 +305,20 @@ pub fn bmap(
     file_offset: u64,
     create: bool,
 ) -> Result<u32> {
+    map_block(device, superblock, inode, file_offset, create, true)
+}
+
+/// Like `bmap`, but data blocks allocated with `create` are only zeroed if `zero` is set.
+/// Indirect blocks are always zeroed.
+pub(crate) fn map_block(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    inode: &mut Inode,
+    file_offset: u64,
+    create: bool,
+    zero: bool,
+) -> Result<u32> {
+    let alloc = if zero { alloc_data_block } else { claim_data_block };
     if file_offset % BLOCK_SIZE as u64 != 0 {
         return Err(FsError::InvalidArgument);
     }
@@ -142,6 +326,10 @@ pub fn bmap(
     if inode.ftype != FileType::Regular && inode.ftype != FileType::Directory {
         return Err(FsError::InvalidFileType);
     }
+    // The pointer area holds the contents, which must be moved to a block first.
+    if inode.has_inline_data() {
+        return Err(FsError::InvalidArgument);
+    }
 
     let block_offset = file_offset / BLOCK_SIZE as u64;
 
@@ -149,10 +337,10 @@ pub fn bmap(
     if block_offset < NUM_DIRECT_PTRS as u64 {
         let block_id = match inode.get_block_ptrs().unwrap().direct[block_offset as usize] {
             Some(block_id) => {
-                block_id
+                check_data_block(superblock, block_id)?
             },
             None if create => {
-                let block_id = alloc_data_block(device, superblock)?;
+                let block_id = alloc(device, superblock)?;
                 inode.get_block_ptrs_mut().unwrap().direct[block_offset as usize] = Some(block_id);
                 inode.blocks += 1;
                 write_inode(device, superblock, &inode)?;
@@ -168,7 +356,7 @@ pub fn bmap(
     if indirect_offset < PTRS_PER_BLOCK as u64 {
         let indirect_block_id =  match inode.get_block_ptrs().unwrap().indirect {
             Some(indirect_block_id) => {
-                indirect_block_id
+                check_data_block(superblock, indirect_block_id)?
             },
             None if create => {
                 let indirect_block_id = alloc_data_block(device, superblock)?;
@@ -178,32 +366,63 @@ pub fn bmap(
             _ => return Err(FsError::OutOfBounds),
         };
 
-        let mut indirect_ptr_buf = Box::new([0u8; BLOCK_SIZE]);
-        device.read_block(indirect_block_id, indirect_ptr_buf.as_mut())?;
-
-        let ptrs = unsafe {
-            core::slice::from_raw_parts_mut(
-                indirect_ptr_buf.as_mut_ptr() as *mut u32,
-                PTRS_PER_BLOCK
-            )
-        };
-
-        let mut data_block_id = ptrs[indirect_offset as usize];
+        let ptr_offset = indirect_offset as usize * size_of::<u32>();
+        let indirect_bh = device.get_block(indirect_block_id)?;
+        if has_data_csum(superblock) {
+            let data = indirect_bh.read_obj::<[u8; BLOCK_SIZE]>(0);
+            verify_data_block(device, superblock, indirect_block_id, &data)?;
+        }
+        let mut data_block_id = indirect_bh.read_obj::<u32>(ptr_offset);
+        drop(indirect_bh);
         if data_block_id == 0 {
             if !create {
                 return Err(FsError::OutOfBounds);
             }
-            data_block_id = alloc_data_block(device, superblock)?;
-            ptrs[indirect_offset as usize] = data_block_id;
+            data_block_id = alloc(device, superblock)?;
             inode.blocks += 1;
             write_inode(device, superblock, &inode)?;
             // Write back the updated indirect block
-            device.write_block(indirect_block_id, indirect_ptr_buf.as_ref())?;
+            let indirect_bh = device.get_block(indirect_block_id)?;
+            indirect_bh.write_obj(ptr_offset, &data_block_id);
+            let data = indirect_bh.read_obj::<[u8; BLOCK_SIZE]>(0);
+            device.put_block(indirect_bh)?;
+            update_data_checksum(device, superblock, indirect_block_id, &data)?;
         }
 
-        return Ok(data_block_id);
+        return check_data_block(superblock, data_block_id);
     } else {
         return Err(FsError::FileTooLarge);
     }
 }
 
+/// Detaches the data block at the given offset from a file, leaving a hole, and returns it.
+/// The block stays allocated, for the caller to free once the inode no longer refers to it.
+pub(crate) fn unmap_block(
+    device: &impl BlockDevice,
+    superblock: &SuperBlock,
+    inode: &mut Inode,
+    file_offset: u64,
+) -> Result<u32> {
+    let block_offset = (file_offset / BLOCK_SIZE as u64) as usize;
+    let block_ptrs = inode.get_block_ptrs_mut()?;
+    let block_id = if block_offset < NUM_DIRECT_PTRS {
+        block_ptrs.direct[block_offset].take().ok_or(FsError::OutOfBounds)?
+    } else {
+        let indirect_block_id = block_ptrs.indirect.ok_or(FsError::OutOfBounds)?;
+        let ptr_offset = (block_offset - NUM_DIRECT_PTRS) * size_of::<u32>();
+        let indirect_bh = device.get_block(check_data_block(superblock, indirect_block_id)?)?;
+        let block_id = indirect_bh.read_obj::<u32>(ptr_offset);
+        if block_id == 0 {
+            return Err(FsError::OutOfBounds);
+        }
+        indirect_bh.write_obj(ptr_offset, &0u32);
+        let data = indirect_bh.read_obj::<[u8; BLOCK_SIZE]>(0);
+        device.put_block(indirect_bh)?;
+        update_data_checksum(device, superblock, indirect_block_id, &data)?;
+        block_id
+    };
+    inode.blocks -= 1;
+    write_inode(device, superblock, inode)?;
+    Ok(block_id)
+}
+
@@ -10,7 +10,7 @@
 //! 
 //! Muon's 5-layered hierarchy (from bottom to top):
 //! 1. Block Device: Abstraction for low level devices.            | User implemented (hardware-specific)
-//! 2. Cache: Optional caching layer for performance.              | User implemented (sync, strategy, etc.)
+//! 2. Cache: Optional caching layer for performance.              | User implemented, or built-in LRU / CLOCK / BufferCache
 //! 3. Inode: Represents file metadata and operations.             | Fs implemented
 //! 4. Directory: Manages directory entries and structure.         | Fs implemented
 //! 5. File: Represents file operations and data access.           | Fs implemented
@@ -20,11 +20,18 @@
 
 // Users of this crate must enable the `alloc` feature for heap allocations.
 extern crate alloc;
+// Hosted environments get ready-made block devices with the `std` feature.
+#[cfg(feature = "std")]
+extern crate std;
 
 mod config;
 mod block_dev;
+mod buffer;
 mod cache;
+mod caches;
+mod sync;
 mod structs;
+mod checksum;
 mod bitmap;
 mod superblock;
 mod inode;
@@ -32,17 +39,28 @@ mod directory;
 mod path;
 mod file;
 mod fs;
+mod resize;
+mod tar;
 mod error;
+#[cfg(feature = "std")]
+mod devices;
 
 pub use block_dev::BlockDevice;
+pub use buffer::*;
 pub use config::*;
 pub use superblock::*;
+pub use checksum::{crc32c, crc32c_append, scrub, ScrubError, ScrubReport};
 pub use structs::*;
 pub use inode::*;
 pub use path::*;
 pub use directory::*;
 pub use file::*;
 pub use fs::*;
+pub use resize::*;
+pub use tar::*;
 pub use error::FsError as Error;
 pub use error::Result;
-pub use cache::*;
\ No newline at end of file
+pub use cache::*;
+pub use caches::*;
+#[cfg(feature = "std")]
+pub use devices::*;
@@ -2,7 +2,7 @@
 
 use alloc::{boxed::Box, collections::vec_deque::VecDeque, string::{String, ToString}, vec::Vec};
 
-use crate::{directory::dir_lookup, get_inode, trim_zero, BlockDevice, Error, FileType, Result, SuperBlock, DOTDOT_NAME, DOT_NAME, ROOT_INODE_ID, SYMLOOP_MAX};
+use crate::{directory::dir_lookup, get_inode, link_target, trim_zero, BlockDevice, Error, FileType, Result, SuperBlock, DOTDOT_NAME, DOT_NAME, ROOT_INODE_ID, SYMLOOP_MAX};
 
 
 /// Resolves a path to inode ids.
@@ -174,8 +174,8 @@ pub fn canonicalize(
             }
 
             link_depth += 1;
-            let sym_target = trim_zero(next_inode.get_path()?);
-            let sym_target_str = String::from_utf8_lossy(sym_target).to_string();
+            let sym_target = link_target(device, superblock, &next_inode)?;
+            let sym_target_str = String::from_utf8_lossy(&sym_target).to_string();
             if sym_target_str.starts_with("/") {
                 // Absolute symlink
                 canonical_components.clear();
@@ -0,0 +1,418 @@
+//! Resizing of a file system in place.
+//!
+//! Growing extends the data region onto blocks added at the end of the device, while mounted.
+//! File data never moves: the data bitmap and the checksum table cover a fixed number of blocks,
+//! so when the added blocks exceed them they are rebuilt larger at the start of the added blocks.
+//! Old copies relocated by an earlier grow are freed; those in front of the data region stay
+//! unused, as the data region never moves.
+//!
+//! Shrinking works on an unmounted file system: data blocks past the new end are moved to free
+//! blocks below it and the pointers to them rewritten. The data bitmap shrinks to fit the new
+//! data region: in place if in front of it, the blocks it frees there staying unused as the data
+//! region never moves. A data bitmap or checksum table relocated by growing is rebuilt to fit,
+//! the rest of the metadata in front of the data region keeps its size.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::bitmap::{allocated_inode_ids, free_data_block};
+use crate::checksum::{crc32c, get_meta_block, has_data_csum, has_metadata_csum, set_block_checksum};
+use crate::error::FsError;
+use crate::superblock::commit_superblock;
+use crate::{config::*, get_inode, read_superblock, write_inode, BlockDevice, Result, SuperBlock};
+
+const BITS_PER_BLOCK: u32 = BLOCK_SIZE as u32 * 8;
+
+fn test_bit(bitmap: &[u8], item: u32) -> bool {
+    bitmap[item as usize / 8] & (1 << (item % 8)) != 0
+}
+
+fn assign_bit(bitmap: &mut [u8], item: u32, value: bool) {
+    if value {
+        bitmap[item as usize / 8] |= 1 << (item % 8);
+    } else {
+        bitmap[item as usize / 8] &= !(1 << (item % 8));
+    }
+}
+
+/// Records a checksum in a checksum table loaded in memory.
+fn set_table_entry(table: &mut [u8], block_id: u32, checksum: u32) {
+    let offset = block_id as usize * size_of::<u32>();
+    table[offset..offset + size_of::<u32>()].copy_from_slice(&checksum.to_ne_bytes());
+}
+
+fn table_entry(table: &[u8], block_id: u32) -> u32 {
+    let offset = block_id as usize * size_of::<u32>();
+    u32::from_ne_bytes(table[offset..offset + size_of::<u32>()].try_into().unwrap())
+}
+
+/// Reads `count` consecutive blocks.
+fn read_region(device: &impl BlockDevice, start_block_id: u32, count: u32) -> Result<Vec<u8>> {
+    let mut region = Vec::with_capacity(count as usize * BLOCK_SIZE);
+    for i in 0..count {
+        region.extend_from_slice(&device.get_block(start_block_id + i)?.read_obj::<[u8; BLOCK_SIZE]>(0));
+    }
+    Ok(region)
+}
+
+/// Reads the data bitmap, verifying its checksums.
+fn read_data_bitmap(device: &impl BlockDevice, superblock: &SuperBlock) -> Result<Vec<u8>> {
+    let mut bitmap = Vec::with_capacity(superblock.data_bitmap_blocks as usize * BLOCK_SIZE);
+    for i in 0..superblock.data_bitmap_blocks {
+        let bh = get_meta_block(device, superblock, superblock.data_bitmap_start + i)?;
+        bitmap.extend_from_slice(&bh.read_obj::<[u8; BLOCK_SIZE]>(0));
+    }
+    Ok(bitmap)
+}
+
+/// Writes whole blocks, as many as `data` holds.
+fn write_region(device: &impl BlockDevice, start_block_id: u32, data: &[u8]) -> Result<()> {
+    for (i, block) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+        let bh = device.new_block(start_block_id + i as u32)?;
+        bh.write_obj(0, &<[u8; BLOCK_SIZE]>::try_from(block).unwrap());
+        device.put_block(bh)?;
+    }
+    Ok(())
+}
+
+/// Grows the file system to `new_num_blocks`, which must not exceed the device.
+///
+/// Everything the grown file system uses beyond the blocks of the current one is written first,
+/// and the new superblock is committed last, so a crash leaves either size intact.
+/// The last superblock backup moves to the new last block, the middle one stays.
+pub fn grow(
+    device: &impl BlockDevice,
+    superblock: &mut SuperBlock,
+    new_num_blocks: u32,
+) -> Result<()> {
+    let old = *superblock;
+    if new_num_blocks < old.num_blocks {
+        return Err(FsError::InvalidArgument);
+    }
+    if new_num_blocks == old.num_blocks {
+        return Ok(());
+    }
+    if new_num_blocks as usize > device.num_blocks() {
+        return Err(FsError::OutOfBounds);
+    }
+
+    let mut new = old;
+    new.num_blocks = new_num_blocks;
+    // Next added block free for relocated metadata.
+    let mut next_block_id = old.num_blocks;
+
+    // Data bitmap, relocated if too small to cover the new data region.
+    let old_items = old.num_blocks - old.data_start;
+    let new_items = new_num_blocks - new.data_start;
+    let mut bitmap = read_data_bitmap(device, &old)?;
+    let bitmap_relocated = old.data_bitmap_blocks * BITS_PER_BLOCK < new_items;
+    if bitmap_relocated {
+        new.data_bitmap_start = next_block_id;
+        new.data_bitmap_blocks = new_items.div_ceil(BITS_PER_BLOCK);
+        next_block_id += new.data_bitmap_blocks;
+        bitmap.resize(new.data_bitmap_blocks as usize * BLOCK_SIZE, 0);
+    }
+
+    // Checksum table, relocated if too small to cover the new blocks.
+    let mut table = Vec::new();
+    let table_relocated = has_metadata_csum(&old) && old.csum_table_blocks * (CSUMS_PER_BLOCK as u32) < new_num_blocks;
+    if table_relocated {
+        table = read_region(device, old.csum_table_start, old.csum_table_blocks)?;
+        new.csum_table_start = next_block_id;
+        new.csum_table_blocks = new_num_blocks.div_ceil(CSUMS_PER_BLOCK as u32);
+        next_block_id += new.csum_table_blocks;
+        table.resize(new.csum_table_blocks as usize * BLOCK_SIZE, 0);
+    }
+
+    // Blocks taken out of the added ones: relocated metadata, and the last superblock backup.
+    let backups = old.has_ro_compat(RO_COMPAT_BACKUP_SB);
+    if next_block_id + backups as u32 > new_num_blocks {
+        return Err(FsError::OutOfSpace);
+    }
+    let mut used: Vec<u32> = (old.num_blocks..next_block_id).collect();
+    let [old_middle_backup, old_last_backup] = old.backup_locations();
+    if backups {
+        new.backup_blocks = [old_middle_backup, new_num_blocks - 1];
+        used.push(new_num_blocks - 1);
+    }
+    new.free_blocks = old.free_blocks + (new_num_blocks - old.num_blocks) - used.len() as u32;
+
+    // Bits past the current data region should be clear already, but are not relied upon.
+    for item in old_items..new_items {
+        assign_bit(&mut bitmap, item, false);
+    }
+    for &block_id in &used {
+        assign_bit(&mut bitmap, block_id - new.data_start, true);
+    }
+
+    // Written in place only where changed, all of it when relocated.
+    let changed = |i: u32| {
+        let first = i * BITS_PER_BLOCK;
+        bitmap_relocated || (first < new_items && first + BITS_PER_BLOCK > old_items)
+    };
+    for i in (0..new.data_bitmap_blocks).filter(|&i| changed(i)) {
+        let block_id = new.data_bitmap_start + i;
+        let data: [u8; BLOCK_SIZE] = bitmap[i as usize * BLOCK_SIZE..(i as usize + 1) * BLOCK_SIZE].try_into().unwrap();
+        let bh = device.new_block(block_id)?;
+        bh.write_obj(0, &data);
+        device.put_block(bh)?;
+
+        if table_relocated {
+            set_table_entry(&mut table, block_id, crc32c(&data));
+            // Blocks of the current file system must stay valid until the commit.
+            if block_id < old.num_blocks {
+                set_block_checksum(device, &old, block_id, &data)?;
+            }
+        } else if has_metadata_csum(&new) {
+            set_block_checksum(device, &new, block_id, &data)?;
+        }
+    }
+
+    write_region(device, new.csum_table_start, &table)?;
+    commit_superblock(device, &new)?;
+    *superblock = new;
+
+    // Released only now, the current file system kept writing its backup there.
+    if backups && old_last_backup != 0 {
+        // Zeroed, so that the stale copy is never taken for a backup.
+        let bh = device.new_block(old_last_backup)?;
+        device.put_block(bh)?;
+        free_data_block(device, superblock, old_last_backup)?;
+    }
+    // Relocated copies in the data region are freed as well, those in front of it stay unused.
+    if bitmap_relocated && old.data_bitmap_start >= old.data_start {
+        for block_id in old.data_bitmap_start..old.data_bitmap_start + old.data_bitmap_blocks {
+            free_data_block(device, superblock, block_id)?;
+        }
+    }
+    if table_relocated && old.csum_table_start >= old.data_start {
+        for block_id in old.csum_table_start..old.csum_table_start + old.csum_table_blocks {
+            free_data_block(device, superblock, block_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of `shrink`, or of its dry run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkReport {
+    /// Data blocks moved below the new end.
+    pub blocks_moved: u32,
+    /// Free blocks left in the shrunk file system.
+    pub free_blocks: u32,
+}
+
+/// Shrinks the unmounted file system on `device` to `target_blocks`.
+///
+/// With `dry_run` nothing is written: the report tells what shrinking would do,
+/// and `Error::OutOfSpace` that the target cannot be reached, as for the actual shrink.
+/// The file system must be clean. It is marked dirty while being shrunk, so that an
+/// interrupted shrink leaves it to be checked.
+pub fn shrink(device: &impl BlockDevice, target_blocks: u32, dry_run: bool) -> Result<ShrinkReport> {
+    let old = read_superblock(device)?;
+    if old.state == FS_STATE_DIRTY {
+        return Err(FsError::NeedsCheck);
+    }
+    if target_blocks > old.num_blocks || target_blocks <= old.data_start {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let plan = plan_shrink(device, &old, target_blocks)?;
+    let report = ShrinkReport {
+        blocks_moved: plan.moves.len() as u32,
+        free_blocks: plan.superblock.free_blocks,
+    };
+    if dry_run || target_blocks == old.num_blocks {
+        return Ok(report);
+    }
+    if device.is_read_only() || old.unsupported_ro_compat() != 0 {
+        return Err(FsError::ReadOnly);
+    }
+    apply_shrink(device, &old, &plan)?;
+    Ok(report)
+}
+
+struct ShrinkPlan {
+    /// The shrunk superblock.
+    superblock: SuperBlock,
+    /// The new data bitmap.
+    bitmap: Vec<u8>,
+    /// Data blocks past the new end, mapped to their new location.
+    moves: BTreeMap<u32, u32>,
+}
+
+/// Finds `count` consecutive free blocks in the bitmap between `start` and `end`, and takes them.
+fn take_free_run(bitmap: &mut [u8], start: u32, end: u32, count: u32) -> Option<u32> {
+    let mut run_start = start;
+    for item in start..end {
+        if test_bit(bitmap, item) {
+            run_start = item + 1;
+        } else if item + 1 - run_start == count {
+            (run_start..=item).for_each(|item| assign_bit(bitmap, item, true));
+            return Some(run_start);
+        }
+    }
+    None
+}
+
+/// Lays out the shrunk file system in memory.
+fn plan_shrink(device: &impl BlockDevice, old: &SuperBlock, target_blocks: u32) -> Result<ShrinkPlan> {
+    let mut new = *old;
+    new.num_blocks = target_blocks;
+    let data_start = old.data_start;
+    let old_items = old.num_blocks - data_start;
+    let new_items = target_blocks - data_start;
+    let mut bitmap = read_data_bitmap(device, old)?;
+
+    // Metadata in the data region is laid out anew, the backups as well.
+    let bitmap_in_data = old.data_bitmap_start >= data_start;
+    let table_in_data = has_metadata_csum(old) && old.csum_table_start >= data_start;
+    let mut released = old.backup_block_ids();
+    if bitmap_in_data {
+        released.extend(old.data_bitmap_start..old.data_bitmap_start + old.data_bitmap_blocks);
+    }
+    if table_in_data {
+        released.extend(old.csum_table_start..old.csum_table_start + old.csum_table_blocks);
+    }
+    for block_id in released {
+        assign_bit(&mut bitmap, block_id - data_start, false);
+    }
+
+    // The last block is kept for the last backup.
+    let backups = old.has_ro_compat(RO_COMPAT_BACKUP_SB);
+    let limit = new_items - backups as u32;
+    let to_move: Vec<u32> = (limit..old_items).filter(|&item| test_bit(&bitmap, item)).collect();
+    for &item in &to_move {
+        assign_bit(&mut bitmap, item, false);
+    }
+
+    // Kept where it is if below the new end, and reserved before relocated metadata is placed.
+    let [old_middle, _] = old.backup_locations();
+    let keep_middle = backups && old_middle != 0 && old_middle < target_blocks - 1;
+    if keep_middle {
+        assign_bit(&mut bitmap, old_middle - data_start, true);
+    }
+
+    // A data bitmap in front of the data region shrinks in place, the blocks it no longer
+    // needs stay unused there.
+    new.data_bitmap_blocks = new_items.div_ceil(BITS_PER_BLOCK);
+    if bitmap_in_data {
+        let start = take_free_run(&mut bitmap, 0, limit, new.data_bitmap_blocks).ok_or(FsError::OutOfSpace)?;
+        new.data_bitmap_start = data_start + start;
+    }
+    if table_in_data {
+        new.csum_table_blocks = target_blocks.div_ceil(CSUMS_PER_BLOCK as u32);
+        let start = take_free_run(&mut bitmap, 0, limit, new.csum_table_blocks).ok_or(FsError::OutOfSpace)?;
+        new.csum_table_start = data_start + start;
+    }
+    if backups {
+        let middle = if keep_middle {
+            old_middle
+        } else {
+            let item = take_free_run(&mut bitmap, limit / 2, limit, 1)
+                .or_else(|| take_free_run(&mut bitmap, 0, limit, 1))
+                .ok_or(FsError::OutOfSpace)?;
+            data_start + item
+        };
+        assign_bit(&mut bitmap, new_items - 1, true);
+        new.backup_blocks = [middle, target_blocks - 1];
+    }
+
+    // Lowest free blocks first.
+    let mut moves = BTreeMap::new();
+    let mut next = 0;
+    for item in to_move {
+        let dest = take_free_run(&mut bitmap, next, limit, 1).ok_or(FsError::OutOfSpace)?;
+        moves.insert(data_start + item, data_start + dest);
+        next = dest + 1;
+    }
+
+    bitmap.truncate(new.data_bitmap_blocks as usize * BLOCK_SIZE);
+    let used: u32 = bitmap.iter().map(|byte| byte.count_ones()).sum();
+    new.free_blocks = new_items - used;
+    Ok(ShrinkPlan { superblock: new, bitmap, moves })
+}
+
+/// Moves the blocks, rewrites the pointers to them and commits the shrunk superblock.
+fn apply_shrink(device: &impl BlockDevice, old: &SuperBlock, plan: &ShrinkPlan) -> Result<()> {
+    let new = &plan.superblock;
+    commit_superblock(device, &SuperBlock { state: FS_STATE_DIRTY, ..*old })?;
+
+    // Kept in memory until the end, the checksums of moved blocks move along.
+    let mut table = Vec::new();
+    if has_metadata_csum(old) {
+        table = read_region(device, old.csum_table_start, old.csum_table_blocks)?;
+    }
+    let mut buf = [0u8; BLOCK_SIZE];
+    for (&src, &dest) in &plan.moves {
+        device.read_block(src, &mut buf)?;
+        device.write_block(dest, &buf)?;
+        if !table.is_empty() {
+            let checksum = table_entry(&table, src);
+            set_table_entry(&mut table, dest, checksum);
+        }
+    }
+
+    for inode_id in allocated_inode_ids(device, old)? {
+        let mut inode = get_inode(device, old, inode_id)?;
+        if inode.has_inline_data() {
+            continue;
+        }
+        let Ok(block_ptrs) = inode.get_block_ptrs_mut() else {
+            continue;
+        };
+        let mut changed = false;
+        for ptr in block_ptrs.direct.iter_mut().flatten() {
+            if let Some(&dest) = plan.moves.get(ptr) {
+                *ptr = dest;
+                changed = true;
+            }
+        }
+        if let Some(indirect) = block_ptrs.indirect.as_mut() {
+            if let Some(&dest) = plan.moves.get(indirect) {
+                *indirect = dest;
+                changed = true;
+            }
+            let mut ptrs = device.get_block(*indirect)?.read_obj::<[u32; PTRS_PER_BLOCK]>(0);
+            let mut ptrs_changed = false;
+            for ptr in ptrs.iter_mut() {
+                if let Some(&dest) = plan.moves.get(ptr) {
+                    *ptr = dest;
+                    ptrs_changed = true;
+                }
+            }
+            if ptrs_changed {
+                let bh = device.get_block(*indirect)?;
+                bh.write_obj(0, &ptrs);
+                let data = bh.read_obj::<[u8; BLOCK_SIZE]>(0);
+                device.put_block(bh)?;
+                if has_data_csum(old) {
+                    set_table_entry(&mut table, *indirect, crc32c(&data));
+                }
+            }
+        }
+        if changed {
+            write_inode(device, old, &inode)?;
+        }
+    }
+
+    write_region(device, new.data_bitmap_start, &plan.bitmap)?;
+    if !table.is_empty() {
+        for (i, data) in plan.bitmap.chunks_exact(BLOCK_SIZE).enumerate() {
+            set_table_entry(&mut table, new.data_bitmap_start + i as u32, crc32c(data));
+        }
+        table.truncate(new.csum_table_blocks as usize * BLOCK_SIZE);
+        write_region(device, new.csum_table_start, &table)?;
+    }
+
+    // Stale copies, which could be taken for backups otherwise, unless the blocks were reused.
+    for block_id in old.backup_block_ids() {
+        let item = block_id - old.data_start;
+        if !new.backup_block_ids().contains(&block_id) && (block_id >= new.num_blocks || !test_bit(&plan.bitmap, item)) {
+            let bh = device.new_block(block_id)?;
+            device.put_block(bh)?;
+        }
+    }
+    commit_superblock(device, new)
+}
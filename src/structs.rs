@@ -26,7 +26,114 @@ pub struct SuperBlock {
     pub inode_table_blocks: u32, // Size of the inode table in blocks
     pub data_start: u32, // Block number where data blocks start
 
-    // pub reserved: [u8; 456],
+    pub state: u32,             // FS_STATE_CLEAN, or FS_STATE_DIRTY while mounted read-write
+    pub mount_count: u32,       // Read-write mounts since the last check
+    pub max_mount_count: u32,   // Mounts allowed between checks, 0 for no limit
+    pub(crate) _pad: u32,       // Keeps the times 8-byte aligned without a padding hole, zero
+    pub last_mount_time: u64,   // Seconds since the Unix epoch, 0 if unknown
+    pub last_check_time: u64,   // Seconds since the Unix epoch, 0 if never checked
+
+    pub feature_ro_compat: u32, // RO_COMPAT_* features, drivers not knowing them may only mount read-only
+    pub csum_table_start: u32,  // Block number where the checksum table starts, with RO_COMPAT_METADATA_CSUM
+    pub csum_table_blocks: u32, // Size of the checksum table in blocks
+    pub checksum: u32,          // CRC32C of the other fields, with RO_COMPAT_METADATA_CSUM
+
+    // Fields appended with revision 1, zero on images of revision 0.
+    pub rev_level: u32,         // On-disk format revision, 0 on images from before it was recorded
+    pub minor_rev_level: u32,   // Backward compatible changes within the revision
+    pub feature_compat: u32,    // COMPAT_* features, ignored by drivers not knowing them
+    pub feature_incompat: u32,  // INCOMPAT_* features, drivers not knowing them must not mount
+    pub uuid: [u8; 16],         // Version 4 UUID generated at format time, nil on images from before
+    pub label: [u8; MAX_LABEL_LEN], // Volume label, zero padded
+    pub backup_blocks: [u32; 2], // Blocks holding superblock backups with RO_COMPAT_BACKUP_SB, [0, 0] on images from before
+    pub inode_size: u32,        // Bytes per inode record, 0 on images from before it was recorded (INODE_SIZE)
+    pub inode_extra_size: u32,  // Bytes of extra fields new inodes get, see InodeExtra, 0 with INODE_SIZE records
+
+    // pub reserved: [u8; 328],
+}
+
+/// Why a file system check is due, see `SuperBlock::check_due`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckReason {
+    /// The last read-write session did not end with `unmount`, or detected corruption.
+    NotCleanlyUnmounted,
+    /// Mounted `max_mount_count` times since the last check.
+    MaxMountCount,
+    /// The primary superblock is damaged, the file system was mounted from a backup.
+    DamagedSuperBlock,
+}
+
+/// A 128-bit volume UUID.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// The nil UUID, of file systems formatted before UUIDs were generated.
+    pub const NIL: Self = Uuid([0; 16]);
+
+    /// Makes a version 4 (random) UUID out of random bytes.
+    pub fn from_random_bytes(mut bytes: [u8; 16]) -> Self {
+        bytes[6] = (bytes[6] & 0x0f) | 0x40; // Version 4
+        bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+        Uuid(bytes)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Self::NIL
+    }
+}
+
+impl core::fmt::Display for Uuid {
+    /// Hyphenated lowercase form, e.g. `0b3f2c1e-7d4a-4e5b-9c8d-1a2b3c4d5e6f`.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Identity of a file system, see `probe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeId {
+    pub uuid: Uuid,
+    /// Volume label, zero padded.
+    pub label: [u8; MAX_LABEL_LEN],
+}
+
+impl VolumeId {
+    /// The label without its padding.
+    pub fn label(&self) -> &[u8] {
+        trim_zero(&self.label)
+    }
+}
+
+/// File system usage statistics, see `FileSystem::statfs`.
+///
+/// Counts are in blocks of `block_size` bytes. Muon has no fragments, so `fragment_size` equals the
+/// block size and the fragment counts equal the block counts; they are kept for `statvfs`-style callers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub block_size: u32,
+    pub fragment_size: u32,
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    /// Free blocks available to unprivileged users. Muon reserves none, so this equals `free_blocks`.
+    pub available_blocks: u32,
+    pub total_fragments: u32,
+    pub free_fragments: u32,
+    pub total_inodes: u32,
+    pub free_inodes: u32,
+    pub available_inodes: u32,
+    pub max_name_len: u32,
+    /// Bytes of the superblock and its backups, bitmaps, inode table and checksum table,
+    /// including a data bitmap or checksum table relocated into the data region.
+    pub metadata_bytes: u64,
+    /// Bytes of the allocated data blocks, including indirect blocks.
+    pub data_bytes: u64,
 }
 
 #[repr(u8)]
@@ -70,6 +177,21 @@ impl Mode {
     pub fn is_empty(&self) -> bool {
         *self == Mode::None
     }
+
+    /// Converts raw permission bits back to a mode.
+    /// Returns None for combinations that have no corresponding mode (e.g. write and execute only).
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0b000 => Some(Mode::None),
+            0b001 => Some(Mode::Read),
+            0b010 => Some(Mode::Write),
+            0b100 => Some(Mode::Execute),
+            0b011 => Some(Mode::RW),
+            0b101 => Some(Mode::RE),
+            0b111 => Some(Mode::RWE),
+            _ => None,
+        }
+    }
 }
 
 impl core::fmt::Debug for Mode {
@@ -100,6 +222,7 @@ pub struct BlockPtr {
 pub union InodePtr {
     block_ptr: BlockPtr,    // Normal inode with direct and indirect pointers
     path: [u8; MAX_PATH_LEN], // Symlink inode with a path
+    data: [u8; MAX_PATH_LEN], // Regular file or directory with inline data, see SuperBlock::max_inline_size
 }
 
 impl core::fmt::Debug for InodePtr {
@@ -124,6 +247,9 @@ impl InodePtr {
 pub struct Inode {
     pub ftype: FileType,
     pub mode: Mode,
+    /// Low 16 bits of the CRC32C of the inode record, with RO_COMPAT_METADATA_CSUM.
+    /// Records of `INODE_SIZE` bytes have no room for the rest, see `InodeExtra::checksum_hi`.
+    pub checksum: u16,
     pub id: u32,
     /// Number of data blocks, excluding the block used to contain indirect pointers.
     pub blocks: u32,
@@ -133,10 +259,22 @@ pub struct Inode {
     pub size: u64,
 }
 
+/// Extra fields of inode records larger than `INODE_SIZE`, right after the base record.
+/// Bytes of the record past the fields in use read as zero, for fields added later.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InodeExtra {
+    /// Bytes of extra fields in use, starting with this one.
+    pub extra_size: u16,
+    /// High 16 bits of the CRC32C of the inode record, with RO_COMPAT_METADATA_CSUM.
+    pub checksum_hi: u16,
+}
+
 impl Inode {
     pub const ZERO: Self = Self {
         ftype: FileType::Regular,
         mode: Mode::None,
+        checksum: 0,
         id: 0,
         blocks: 0,
         links_cnt: 0,
@@ -148,6 +286,7 @@ impl Inode {
         Self {
             ftype,
             mode,
+            checksum: 0,
             id,
             blocks: 0,
             links_cnt: 0,
@@ -170,12 +309,17 @@ impl Inode {
         self.ftype == FileType::Symlink
     }
 
+    /// Whether the inode is a symlink with its target in data blocks, as it is longer than `MAX_PATH_LEN`.
+    pub fn is_slow_symlink(&self) -> bool {
+        self.ftype == FileType::Symlink && self.blocks > 0
+    }
+
     pub fn is_special(&self) -> bool {
         self.ftype == FileType::Special
     }
 
     pub fn get_block_ptrs(&self) -> Result<&BlockPtr> {
-        if self.ftype != FileType::Regular && self.ftype != FileType::Directory {
+        if self.ftype != FileType::Regular && self.ftype != FileType::Directory && !self.is_slow_symlink() {
             return Err(Error::InvalidFileType);
         }
         unsafe {
@@ -184,17 +328,47 @@ impl Inode {
     }
     
     pub fn get_block_ptrs_mut(&mut self) -> Result<&mut BlockPtr> {
-        if self.ftype != FileType::Regular && self.ftype != FileType::Directory {
+        if self.ftype != FileType::Regular && self.ftype != FileType::Directory && !self.is_slow_symlink() {
             return Err(Error::InvalidFileType);
         }
         unsafe {
             Ok(&mut self.inode_ptr.block_ptr)
         }
     }
+
+    /// Whether the contents of a regular file or directory are stored in the inode itself,
+    /// with INCOMPAT_INLINE_DATA: it has contents but no data blocks.
+    pub fn has_inline_data(&self) -> bool {
+        (self.ftype == FileType::Regular || self.ftype == FileType::Directory) && self.blocks == 0 && self.size > 0
+    }
+
+    /// The start of the inline data, the rest is stored past the extra fields of the record.
+    pub fn get_inline_data(&self) -> Result<&[u8; MAX_PATH_LEN]> {
+        if self.ftype != FileType::Regular && self.ftype != FileType::Directory {
+            return Err(Error::InvalidFileType);
+        }
+        unsafe {
+            Ok(&self.inode_ptr.data)
+        }
+    }
+
+    pub fn get_inline_data_mut(&mut self) -> Result<&mut [u8; MAX_PATH_LEN]> {
+        if self.ftype != FileType::Regular && self.ftype != FileType::Directory {
+            return Err(Error::InvalidFileType);
+        }
+        unsafe {
+            Ok(&mut self.inode_ptr.data)
+        }
+    }
+
+    /// The target of a symlink short enough to be stored in the inode, see `link_target`.
     pub fn get_path(&self) -> Result<&[u8; MAX_PATH_LEN]> {
         if self.ftype != FileType::Symlink {
             return Err(Error::NotSymlink);
         }
+        if self.is_slow_symlink() {
+            return Err(Error::InvalidFileType);
+        }
         unsafe {
             Ok(&self.inode_ptr.path)
         }
@@ -204,6 +378,9 @@ impl Inode {
         if self.ftype != FileType::Symlink {
             return Err(Error::NotSymlink);
         }
+        if self.is_slow_symlink() {
+            return Err(Error::InvalidFileType);
+        }
         unsafe {
             Ok(&mut self.inode_ptr.path)
         }
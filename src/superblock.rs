@@ -1,18 +1,75 @@
-use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
 
-use crate::{error::FsError, BlockDevice, SuperBlock};
-use crate::{config::*, write_inode, Inode, Mode, Result};
+use crate::{error::FsError, BlockDevice, BufferHead, CheckReason, StatFs, SuperBlock, Uuid, VolumeId};
+use crate::{config::*, write_inode, Inode, InodeExtra, Mode, Result};
+use crate::checksum::{has_metadata_csum, superblock_checksum};
 
 
 pub fn read_superblock(device: &impl BlockDevice) -> Result<SuperBlock> {
-    let mut buf = Box::new([0u8; BLOCK_SIZE]);
-    
-    device.read_block(SUPERBLOCK_ID as u32, &mut buf);
-    
-    let superblock: SuperBlock = unsafe {
-        core::ptr::read_unaligned(buf.as_ptr() as *const SuperBlock)
-    };
-    
+    let superblock = device.get_block(SUPERBLOCK_ID as u32)?.read_obj::<SuperBlock>(0);
+    let superblock = validate_superblock(superblock)?;
+    check_features(&superblock)?;
+    Ok(superblock)
+}
+
+/// Reads the primary superblock, or a backup if the primary is damaged.
+/// Returns the block ID of the backup used, if any.
+pub fn read_superblock_or_backup(device: &impl BlockDevice) -> Result<(SuperBlock, Option<u32>)> {
+    let (superblock, backup) = read_intact_superblock(device)?;
+    check_features(&superblock)?;
+    Ok((superblock, backup))
+}
+
+/// Identifies the file system on a device without mounting it, from its superblock or a backup.
+/// Also works for file systems using features this driver does not know.
+pub fn probe(device: &impl BlockDevice) -> Result<VolumeId> {
+    let (superblock, _) = read_intact_superblock(device)?;
+    Ok(superblock.volume_id())
+}
+
+/// Reads the primary superblock, or a backup if the primary is damaged, without checking features.
+fn read_intact_superblock(device: &impl BlockDevice) -> Result<(SuperBlock, Option<u32>)> {
+    let superblock = device.get_block(SUPERBLOCK_ID as u32)?.read_obj::<SuperBlock>(0);
+    match validate_superblock(superblock) {
+        Ok(superblock) => Ok((superblock, None)),
+        Err(e) if e.is_corruption() => match find_backup_superblock(device) {
+            Ok((superblock, block_id)) => Ok((superblock, Some(block_id))),
+            Err(_) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Looks for a valid backup of the superblock, returning it with its block ID.
+/// Only the known backup locations are checked, the last one first: those recorded in the
+/// primary superblock, if they can still be read, then the last block of the device,
+/// where `format` puts one.
+pub fn find_backup_superblock(device: &impl BlockDevice) -> Result<(SuperBlock, u32)> {
+    let num_blocks = device.num_blocks() as u32;
+    let primary = device.get_block(SUPERBLOCK_ID)?.read_obj::<SuperBlock>(0);
+    let mut candidates = Vec::new();
+    if primary.magic == MAGIC {
+        candidates.extend(primary.backup_block_ids().into_iter().rev());
+    }
+    candidates.push(num_blocks - 1);
+    let mut buf = [0u8; BLOCK_SIZE];
+    for block_id in candidates {
+        if block_id == SUPERBLOCK_ID || block_id >= num_blocks {
+            continue;
+        }
+        device.read_block(block_id, &mut buf)?;
+        let candidate = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const SuperBlock) };
+        if let Ok(superblock) = validate_superblock(candidate)
+            && superblock.backup_block_ids().contains(&block_id)
+        {
+            return Ok((superblock, block_id));
+        }
+    }
+    Err(FsError::InvalidSuperBlock)
+}
+
+fn validate_superblock(superblock: SuperBlock) -> Result<SuperBlock> {
     // Here we simply check the magic number and block size, for conceptual purposes.
     if superblock.magic != MAGIC {
         return Err(FsError::InvalidSuperBlock);
@@ -20,29 +77,236 @@ pub fn read_superblock(device: &impl BlockDevice) -> Result<SuperBlock> {
     if superblock.block_size != BLOCK_SIZE as u32 {
         return Err(FsError::InvalidSuperBlock);
     }
+    if has_metadata_csum(&superblock) && superblock.checksum != superblock_checksum(&superblock) {
+        return Err(FsError::ChecksumMismatch);
+    }
+    // Inode offsets are derived from it.
+    if superblock.inode_size != 0 && !valid_inode_size(superblock.inode_size) {
+        return Err(FsError::InvalidSuperBlock);
+    }
+    if superblock.inode_extra_size as usize > superblock.inode_size() - INODE_SIZE {
+        return Err(FsError::InvalidSuperBlock);
+    }
 
     Ok(superblock)
 }
 
+fn valid_inode_size(inode_size: u32) -> bool {
+    inode_size.is_power_of_two() && (INODE_SIZE as u32..=MAX_INODE_SIZE as u32).contains(&inode_size)
+}
+
+/// Refuses newer revisions and unknown incompatible features.
+/// Unknown read-only compatible features are left to the mount, which forces read-only.
+fn check_features(superblock: &SuperBlock) -> Result<()> {
+    if superblock.rev_level > MUON_REV || superblock.unsupported_incompat() != 0 {
+        return Err(FsError::UnsupportedFeature);
+    }
+    Ok(())
+}
+
+/// The superblock as stored on the device, with its checksum updated.
+fn sealed(superblock: &SuperBlock) -> SuperBlock {
+    let mut sealed = *superblock;
+    if has_metadata_csum(superblock) {
+        sealed.checksum = superblock_checksum(superblock);
+    }
+    sealed
+}
+
 /// Updates the superblock on the device.
+/// Its backups are left alone, they are refreshed at sync and unmount.
 pub fn write_superblock(device: &impl BlockDevice, superblock: &SuperBlock) -> Result<()> {
-    let mut buf = Box::new([0u8; BLOCK_SIZE]);
-    unsafe {
-        core::ptr::write_unaligned(
-            buf.as_mut_ptr() as *mut SuperBlock,
-            *superblock
-        );
-    }
-    device.write_block(SUPERBLOCK_ID as u32, buf.as_ref())?;
+    let bh = device.new_block(SUPERBLOCK_ID)?;
+    bh.write_obj(0, &sealed(superblock));
+    device.put_block(bh)
+}
+
+/// Updates the superblock on the device along with its backups, e.g. at sync.
+pub fn write_superblock_with_backups(device: &impl BlockDevice, superblock: &SuperBlock) -> Result<()> {
+    let sealed = sealed(superblock);
+    for block_id in core::iter::once(SUPERBLOCK_ID).chain(superblock.backup_block_ids()) {
+        // The superblock owns its whole block, no need to read it first.
+        let bh = device.new_block(block_id)?;
+        bh.write_obj(0, &sealed);
+        device.put_block(bh)?;
+    }
     Ok(())
 }
 
+/// Writes the superblock and its backups to stable storage, after every write issued before them.
+/// Used where the superblock acts as a commit record, e.g. at unmount.
+pub fn commit_superblock(device: &impl BlockDevice, superblock: &SuperBlock) -> Result<()> {
+    device.barrier()?;
+    let bh = BufferHead::new(SUPERBLOCK_ID);
+    bh.write_obj(0, &sealed(superblock));
+    for block_id in core::iter::once(SUPERBLOCK_ID).chain(superblock.backup_block_ids()) {
+        device.write_block_fua(block_id, &bh.lock())?;
+    }
+    Ok(())
+}
+
+/// Encodes a volume label for the superblock.
+/// Labels are limited to `MAX_LABEL_LEN` bytes, and cannot contain NUL characters.
+pub(crate) fn encode_label(label: &str) -> Result<[u8; MAX_LABEL_LEN]> {
+    if label.len() > MAX_LABEL_LEN || label.contains('\0') {
+        return Err(FsError::InvalidArgument);
+    }
+    let mut encoded = [0u8; MAX_LABEL_LEN];
+    encoded[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(encoded)
+}
+
+/// Backups of a new file system: in the middle and at the end of the data region.
+fn default_backup_blocks(data_start: u32, num_blocks: u32) -> [u32; 2] {
+    [data_start + (num_blocks - data_start) / 2, num_blocks - 1]
+}
+
+/// Records a completed file system check on an unmounted device:
+/// the file system is marked clean and its mount count is reset.
+/// `time` is the current time in seconds since the Unix epoch, 0 if unknown.
+/// A damaged primary superblock is restored from a backup.
+pub fn mark_checked(device: &impl BlockDevice, time: u64) -> Result<()> {
+    let (mut superblock, _) = read_superblock_or_backup(device)?;
+    superblock.state = FS_STATE_CLEAN;
+    superblock.mount_count = 0;
+    superblock.last_check_time = time;
+    commit_superblock(device, &superblock)
+}
+
 impl SuperBlock {
+    /// Usage statistics, derived from the layout and the free counts.
+    pub fn statfs(&self) -> StatFs {
+        let block_size = self.block_size as u64;
+        let metadata_blocks = self.data_start + self.data_region_metadata_blocks();
+        StatFs {
+            block_size: self.block_size,
+            fragment_size: self.block_size,
+            total_blocks: self.num_blocks,
+            free_blocks: self.free_blocks,
+            available_blocks: self.free_blocks,
+            total_fragments: self.num_blocks,
+            free_fragments: self.free_blocks,
+            total_inodes: self.num_inodes,
+            free_inodes: self.free_inodes,
+            available_inodes: self.free_inodes,
+            max_name_len: MAX_FILE_NAME_LEN as u32,
+            metadata_bytes: metadata_blocks as u64 * block_size,
+            data_bytes: (self.num_blocks - self.free_blocks).saturating_sub(metadata_blocks) as u64 * block_size,
+        }
+    }
+
+    /// Blocks of the data region holding metadata rather than file data: the superblock
+    /// backups, and the data bitmap and checksum table once relocated there by `grow`.
+    pub(crate) fn data_region_metadata_blocks(&self) -> u32 {
+        let mut blocks = self.backup_block_ids().len() as u32;
+        if self.data_bitmap_start >= self.data_start {
+            blocks += self.data_bitmap_blocks;
+        }
+        if self.has_ro_compat(RO_COMPAT_METADATA_CSUM) && self.csum_table_start >= self.data_start {
+            blocks += self.csum_table_blocks;
+        }
+        blocks
+    }
+
+    pub fn volume_id(&self) -> VolumeId {
+        VolumeId { uuid: Uuid(self.uuid), label: self.label }
+    }
+
+    /// Whether all of the given COMPAT_* features are enabled.
+    pub fn has_compat(&self, features: u32) -> bool {
+        self.feature_compat & features == features
+    }
+
+    /// Whether all of the given INCOMPAT_* features are enabled.
+    pub fn has_incompat(&self, features: u32) -> bool {
+        self.feature_incompat & features == features
+    }
+
+    /// Whether all of the given RO_COMPAT_* features are enabled.
+    pub fn has_ro_compat(&self, features: u32) -> bool {
+        self.feature_ro_compat & features == features
+    }
+
+    /// Incompatible features this driver does not know, the file system cannot be mounted if any.
+    pub fn unsupported_incompat(&self) -> u32 {
+        self.feature_incompat & !SUPPORTED_INCOMPAT
+    }
+
+    /// Read-only compatible features this driver does not know,
+    /// the file system can only be mounted read-only if any.
+    pub fn unsupported_ro_compat(&self) -> u32 {
+        self.feature_ro_compat & !SUPPORTED_RO_COMPAT
+    }
+
+    /// Blocks holding copies of the superblock, reserved in the data bitmap, as recorded in
+    /// `backup_blocks`: the middle block of the data region at format time and the last block
+    /// of the file system. None on images without `RO_COMPAT_BACKUP_SB`, i.e. older or small ones
+    /// (less than `BACKUP_SB_MIN_DATA_BLOCKS` data blocks).
+    pub fn backup_block_ids(&self) -> Vec<u32> {
+        if !self.has_ro_compat(RO_COMPAT_BACKUP_SB) {
+            return Vec::new();
+        }
+        self.backup_locations().iter().copied().filter(|&block_id| block_id != 0).collect()
+    }
+
+    /// `backup_blocks`, or the locations backups were kept at before they were recorded
+    /// (`[0, 0]` with `RO_COMPAT_BACKUP_SB`), which are those of a new file system.
+    pub(crate) fn backup_locations(&self) -> [u32; 2] {
+        match self.backup_blocks {
+            [0, 0] if self.has_ro_compat(RO_COMPAT_BACKUP_SB) => default_backup_blocks(self.data_start, self.num_blocks),
+            recorded => recorded,
+        }
+    }
+
+    /// Whether the file system should be checked before it is mounted read-write.
+    /// Images from before the state was recorded (state 0) are taken as clean.
+    pub fn check_due(&self) -> Option<CheckReason> {
+        if self.state == FS_STATE_DIRTY {
+            return Some(CheckReason::NotCleanlyUnmounted);
+        }
+        if self.max_mount_count > 0 && self.mount_count >= self.max_mount_count {
+            return Some(CheckReason::MaxMountCount);
+        }
+        None
+    }
+
+    /// Bytes per inode record.
+    pub fn inode_size(&self) -> usize {
+        match self.inode_size {
+            0 => INODE_SIZE,
+            size => size as usize,
+        }
+    }
+
+    pub fn inodes_per_block(&self) -> u32 {
+        (BLOCK_SIZE / self.inode_size()) as u32
+    }
+
+    /// Largest contents of a regular file or directory stored in its inode, 0 without
+    /// `INCOMPAT_INLINE_DATA`: the pointer area, followed by the bytes of the record past
+    /// its extra fields. Directories need room for '.' and '..', so `INODE_SIZE` records
+    /// only inline files.
+    pub fn max_inline_size(&self) -> usize {
+        if !self.has_incompat(INCOMPAT_INLINE_DATA) {
+            return 0;
+        }
+        MAX_PATH_LEN + self.inode_size() - INODE_SIZE - self.inode_extra_size as usize
+    }
+
     /// Calculates the layout of the filesystem and initializes the superblock.
     pub fn new(num_blocks: u32, num_inodes: u32) -> Result<Self> {
+        Self::with_inode_size(num_blocks, num_inodes, INODE_SIZE as u32)
+    }
+
+    /// Same as `new`, with inode records of `inode_size` bytes: a power of two from `INODE_SIZE`
+    /// to `MAX_INODE_SIZE`. Records larger than `INODE_SIZE` hold `InodeExtra` fields.
+    pub fn with_inode_size(num_blocks: u32, num_inodes: u32, inode_size: u32) -> Result<Self> {
         if num_blocks == 0 || num_inodes == 0 {
             return Err(FsError::InvalidSuperBlock);
         }
+        if !valid_inode_size(inode_size) {
+            return Err(FsError::InvalidArgument);
+        }
 
         let data_bitmap_start = SUPERBLOCK_ID as u32 + 1;
         // Not a precise calculation, for data region actually starts after superblock, 2 bitmaps and inode table, but enough.
@@ -52,15 +316,31 @@ impl SuperBlock {
         let inode_bitmap_blocks = (num_inodes + 7) / 8;
         
         let inode_table_start = inode_bitmap_start + inode_bitmap_blocks;
-        let inodes_per_block = (BLOCK_SIZE / INODE_SIZE) as u32;
+        let inodes_per_block = BLOCK_SIZE as u32 / inode_size;
         let inode_table_blocks = (num_inodes + inodes_per_block - 1) / inodes_per_block;
 
-        let data_start = inode_table_start + inode_table_blocks;
+        // One checksum per block, for bitmap and directory blocks.
+        let csum_table_start = inode_table_start + inode_table_blocks;
+        let csum_table_blocks = num_blocks.div_ceil(CSUMS_PER_BLOCK as u32);
+
+        let data_start = csum_table_start + csum_table_blocks;
         // Simple sanity check for the number of blocks.
         if num_blocks <= data_start {
             return Err(FsError::InvalidSuperBlock);
         }
         let free_blocks = num_blocks - data_start;
+        let mut feature_ro_compat = RO_COMPAT_METADATA_CSUM;
+        let mut feature_incompat = 0;
+        let mut inode_extra_size = 0;
+        if inode_size > INODE_SIZE as u32 {
+            feature_incompat |= INCOMPAT_INODE_SIZE;
+            inode_extra_size = size_of::<InodeExtra>() as u32;
+        }
+        let mut backup_blocks = [0; 2];
+        if free_blocks >= BACKUP_SB_MIN_DATA_BLOCKS {
+            feature_ro_compat |= RO_COMPAT_BACKUP_SB;
+            backup_blocks = default_backup_blocks(data_start, num_blocks);
+        }
         
         Ok(SuperBlock { 
             magic: MAGIC, 
@@ -77,6 +357,25 @@ impl SuperBlock {
             inode_table_start, 
             inode_table_blocks, 
             data_start, 
+            state: FS_STATE_CLEAN,
+            mount_count: 0,
+            max_mount_count: 0,
+            _pad: 0,
+            last_mount_time: 0,
+            last_check_time: 0,
+            rev_level: MUON_REV,
+            minor_rev_level: 0,
+            feature_compat: 0,
+            feature_incompat,
+            feature_ro_compat,
+            csum_table_start,
+            csum_table_blocks,
+            uuid: [0; 16],
+            label: [0; MAX_LABEL_LEN],
+            backup_blocks,
+            inode_size,
+            inode_extra_size,
+            checksum: 0,
         })
     }
 }
\ No newline at end of file
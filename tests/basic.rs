@@ -5,7 +5,7 @@ use std::sync::{Arc, Mutex};
 
 mod common;
 
-use common::RamDisk;
+use muon::RamDevice;
 use muon::get_inode;
 use muon::write_inode;
 use muon::BlockDevice;
@@ -16,13 +16,14 @@ use muon::Inode;
 use muon::Mode;
 use muon::SuperBlock;
 use muon::BLOCK_SIZE;
+use muon::DIR_ENTRY_SIZE;
 use muon::NUM_DIRECT_PTRS;
 
 
 
 #[test]
 fn test_superblock() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let superblock = SuperBlock::new(64, 80).unwrap();
     muon::write_superblock(&rd, &superblock).unwrap();
     let read_superblock = muon::read_superblock(&rd).unwrap();
@@ -31,7 +32,7 @@ fn test_superblock() {
 
 #[test]
 fn test_inode() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let superblock = SuperBlock::new(64, 80).unwrap();
     muon::write_superblock(&rd, &superblock).unwrap();
     let inode = Inode::new(FileType::Regular, Mode::Execute, 3);
@@ -41,14 +42,100 @@ fn test_inode() {
 
 #[test]
 fn test_init_fs() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     log!("{}", fs.dump());
 }
 
+#[test]
+fn test_statfs() {
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd, 64, 16).unwrap();
+    let sb = *fs.superblock();
+    let before = fs.statfs();
+    log!("{:?}", before);
+    assert_eq!(before.block_size, BLOCK_SIZE as u32);
+    assert_eq!(before.total_blocks, 64);
+    assert_eq!(before.total_inodes, 16);
+    assert_eq!(before.free_inodes, sb.free_inodes);
+    assert_eq!(before.max_name_len, muon::MAX_FILE_NAME_LEN as u32);
+    let metadata_blocks = sb.data_start as usize + sb.backup_block_ids().len();
+    assert_eq!(before.metadata_bytes, (metadata_blocks * BLOCK_SIZE) as u64);
+
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, &[1u8; 3 * BLOCK_SIZE]).unwrap();
+    let after = fs.statfs();
+    assert_eq!(after.free_blocks, before.free_blocks - 3);
+    assert_eq!(after.available_blocks, after.free_blocks);
+    assert_eq!(after.free_fragments, after.free_blocks);
+    assert_eq!(after.free_inodes, before.free_inodes - 1);
+    assert_eq!(after.data_bytes, before.data_bytes + 3 * BLOCK_SIZE as u64);
+    assert_eq!(after.metadata_bytes + after.data_bytes + (after.free_blocks as usize * BLOCK_SIZE) as u64, (64 * BLOCK_SIZE) as u64);
+}
+
+#[test]
+fn test_inode_size() {
+    let rd = Arc::new(RamDevice::new(256));
+    let options = muon::FormatOptions { inode_size: 256, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 256, 32, options).unwrap();
+    let sb = *fs.superblock();
+    assert_eq!(sb.inode_size(), 256);
+    assert_eq!(sb.inode_table_blocks, 16);
+    assert!(sb.has_incompat(muon::INCOMPAT_INODE_SIZE));
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/dir/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/dir/file", 0, b"larger inodes").unwrap();
+    let (inode_id, _) = fs.lookup("/dir/file").unwrap();
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let mut buf = [0u8; 13];
+    fs.fread("/dir/file", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"larger inodes");
+    let extra = muon::get_inode_extra(rd.as_ref(), fs.superblock(), inode_id).unwrap().unwrap();
+    assert_eq!(extra.extra_size as usize, std::mem::size_of::<muon::InodeExtra>());
+    assert_ne!(extra.checksum_hi, 0);
+    // Neighbours in the same block are told apart.
+    assert_eq!(fs.get_inode(inode_id).unwrap().id, inode_id);
+    assert_eq!(fs.get_inode(inode_id - 1).unwrap().id, inode_id - 1);
+
+    for size in [64, 200, 1024] {
+        let options = muon::FormatOptions { inode_size: size, ..Default::default() };
+        let res = FileSystem::format_with_options(Arc::new(RamDevice::new(256)), 256, 32, options);
+        assert_eq!(res.err(), Some(Error::InvalidArgument));
+    }
+}
+
+#[test]
+fn test_inode_size_of_older_images() {
+    // Images from before the inode size was recorded have INODE_SIZE records.
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    assert!(!fs.superblock().has_incompat(muon::INCOMPAT_INODE_SIZE));
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, b"data").unwrap();
+    let (inode_id, _) = fs.lookup("/file").unwrap();
+    fs.unmount().unwrap();
+    let mut sb = muon::read_superblock(rd.as_ref()).unwrap();
+    sb.inode_size = 0;
+    muon::write_superblock(rd.as_ref(), &sb).unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.superblock().inode_size(), muon::INODE_SIZE);
+    let mut buf = [0u8; 4];
+    fs.fread("/file", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    assert_eq!(muon::get_inode_extra(rd.as_ref(), fs.superblock(), inode_id).unwrap(), None);
+    fs.unmount().unwrap();
+
+    sb.inode_size = 96;
+    muon::write_superblock(rd.as_ref(), &sb).unwrap();
+    assert_eq!(FileSystem::mount(rd).err(), Some(Error::InvalidSuperBlock));
+}
+
 #[test]
 fn test_root_dir() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let root_inode_id = fs.root_inode_id();
     let root_inode = fs.get_inode(root_inode_id).unwrap();
@@ -65,7 +152,7 @@ fn test_root_dir() {
 
 #[test]
 fn test_create_file() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let file_inode_id = fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
     let file_inode = fs.get_inode(file_inode_id).unwrap();
@@ -98,7 +185,7 @@ fn test_create_file() {
 
 #[test]
 fn test_lookup() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
     let entries = fs.read_dir("/").unwrap();
@@ -122,7 +209,7 @@ fn test_lookup() {
 
 #[test]
 fn test_remove_file() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
     let entries = fs.read_dir("/").unwrap();
@@ -149,7 +236,7 @@ fn test_remove_file() {
 #[test]
 fn test_remove_2() {
     // Create a bunch of files and test removal.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     for i in 0..10 {
         let file_name = format!("/file_{}.txt", i);
@@ -172,7 +259,7 @@ fn test_remove_2() {
 #[test]
 fn test_resource_release() {
     // Test inode releasing.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let sb = fs.superblock();
     let num_inodes = sb.num_inodes;
@@ -189,7 +276,7 @@ fn test_resource_release() {
 
 #[test]
 fn test_mkdir() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let dir_inode_id = fs.creat("/test_dir", FileType::Directory, Mode::RW).unwrap();
     
@@ -224,7 +311,7 @@ fn test_mkdir() {
 
 #[test]
 fn test_rmdir() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     fs.creat("/test_dir", FileType::Directory, Mode::RW).unwrap();
     
@@ -248,12 +335,22 @@ fn test_rmdir() {
     if let Err(e) = result {
         println!("Expected error: {:?}", e);
     }
+
+    // A directory without its '..' entry is damaged, not empty.
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd.clone(), 64, 80).unwrap();
+    fs.creat("/test_dir3", FileType::Directory, Mode::RW).unwrap();
+    let (inode_id, _) = fs.lookup("/test_dir3").unwrap();
+    let mut inode = fs.get_inode(inode_id).unwrap();
+    inode.size = DIR_ENTRY_SIZE as u64;
+    write_inode(rd.as_ref(), fs.superblock(), &inode).unwrap();
+    assert_eq!(fs.remove("/test_dir3", FileType::Directory).unwrap_err(), Error::Corrupted);
 }
 
 #[test]
 fn test_mkdir_2() {
     // Test creating a directory inside another directory.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     fs.creat("/parent_dir", FileType::Directory, Mode::RW).unwrap();
     
@@ -300,7 +397,7 @@ fn test_mkdir_2() {
 #[test]
 fn test_resource_release_2() {
     // Test inode releasing with directories.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let free_blocks = fs.superblock().free_blocks;
     let sb = fs.superblock();
@@ -334,7 +431,7 @@ fn test_resource_release_2() {
 
 #[test]
 fn test_file_rw() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let file_inode_id = fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
     let mut file_inode = fs.get_inode(file_inode_id).unwrap();
@@ -355,7 +452,7 @@ fn test_file_rw() {
 #[test]
 fn test_file_rw_2() {
     // test reading and writing to a file with multiple blocks.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     let file_inode_id = fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
     let mut file_inode = fs.get_inode(file_inode_id).unwrap();
@@ -412,7 +509,7 @@ fn test_file_rw_2() {
 #[test]
 fn test_file_rw_3() {
     // Test holes in files.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     log!("{:?}", fs.dump());
     // Read and write in a directory.
@@ -455,9 +552,49 @@ fn test_file_rw_3() {
     log!("After removing test_dir {}", fs.dump());
 }
 
+#[test]
+fn test_file_read_past_end() {
+    // Reads reaching past the end of the file stop at it instead of underflowing.
+    let rd = RamDevice::new(64);
+    let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
+    fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
+    let data = vec![3u8; BLOCK_SIZE + 10];
+    fs.fwrite("/test.txt", 0, &data).unwrap();
+
+    let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+    assert_eq!(fs.fread("/test.txt", BLOCK_SIZE, &mut buf).unwrap(), 10);
+    assert_eq!(fs.fread("/test.txt", 5, &mut buf).unwrap(), BLOCK_SIZE + 5);
+    assert_eq!(fs.fread("/test.txt", BLOCK_SIZE + 10, &mut buf).unwrap_err(), Error::EOF(Some(0)));
+    assert_eq!(fs.fread("/test.txt", 3 * BLOCK_SIZE, &mut buf).unwrap_err(), Error::EOF(Some(0)));
+}
+
+#[test]
+fn test_file_rw_unaligned() {
+    let rd = Arc::new(RamDevice::new(128));
+    let mut fs = FileSystem::format(rd, 128, 32).unwrap();
+    fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
+
+    // Spans partial head and tail blocks around a run of whole blocks, past the direct pointers.
+    let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let offset = BLOCK_SIZE / 2 + 3;
+    let bytes_written = fs.fwrite("/test.txt", offset, &data).unwrap();
+    assert_eq!(bytes_written, data.len());
+
+    let mut buf = vec![0u8; data.len()];
+    let bytes_read = fs.fread("/test.txt", offset, &mut buf).unwrap();
+    assert_eq!(bytes_read, data.len());
+    assert_eq!(buf, data);
+
+    // Reads are cut at the end of the file.
+    let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+    let bytes_read = fs.fread("/test.txt", offset + data.len() - 100, &mut buf).unwrap();
+    assert_eq!(bytes_read, 100);
+    assert_eq!(&buf[..100], &data[data.len() - 100..]);
+}
+
 #[test]
 fn test_mount() {
-    let rd = Arc::new(RamDisk::new(64));
+    let rd = Arc::new(RamDevice::new(64));
     let mut fs = FileSystem::format(rd.clone(), 64, 80).unwrap();
     // Make some changes to the device.
     fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
@@ -477,7 +614,7 @@ fn test_mount() {
 
 #[test]
 fn test_hard_link() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
 
     log!("File System initialized: {}", fs.dump());
@@ -536,7 +673,7 @@ fn test_hard_link() {
 
 #[test]
 fn test_lookup_dot() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     
     // Create a directory and a file inside it.
@@ -563,7 +700,7 @@ fn test_lookup_dot() {
 
 #[test]
 fn test_lookup_dotdot() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
     
     // Create a directory and a file inside it.
@@ -599,7 +736,7 @@ fn test_lookup_dotdot() {
 
 #[test]
 fn test_symlink() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
 
     // Create a file and a symlink to it.
@@ -616,7 +753,7 @@ fn test_symlink() {
 
 #[test]
 fn test_symlink_2() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
 
     // Test symlinks as intermediate steps in paths.
@@ -662,7 +799,7 @@ fn test_symlink_2() {
 #[test]
 fn test_symlink_3() {
     // More complex symlink scenarios.
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let mut fs = FileSystem::format(Arc::new(rd), 64, 80).unwrap();
 
     fs.creat("/a", FileType::Directory, Mode::RW).unwrap();
@@ -785,5 +922,100 @@ fn test_symlink_3() {
     }
     log!("File System after cleaning up: {}", fs.dump());
     assert_eq!(fs.superblock().free_inodes, fs.superblock().num_inodes - 2, "All inodes should be released except root and placeholder");
-    assert_eq!(fs.superblock().free_blocks, 24 - 1, "All blocks should be released except root block");
-}
\ No newline at end of file
+    assert_eq!(fs.superblock().free_blocks, 23 - 1, "All blocks should be released except root block");
+}
+
+#[test]
+fn test_long_symlink() {
+    let rd = Arc::new(RamDevice::new(256));
+    let mut fs = FileSystem::format(rd.clone(), 256, 32).unwrap();
+    let free = fs.superblock().free_blocks;
+
+    // A deep directory tree, whose path is longer than MAX_PATH_LEN.
+    let mut dir = String::new();
+    while dir.len() <= muon::MAX_PATH_LEN {
+        dir.push_str("/a_rather_long_directory_name");
+        fs.creat(&dir, FileType::Directory, Mode::RW).unwrap();
+    }
+    let file = format!("{}/file.txt", dir);
+    fs.creat(&file, FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite(&file, 0, b"deep").unwrap();
+    let free_before_links = fs.superblock().free_blocks;
+
+    assert!(!fs.superblock().has_incompat(muon::INCOMPAT_LONG_SYMLINK));
+    let inode_id = fs.symlink(&file, "/deep").unwrap();
+    assert!(fs.superblock().has_incompat(muon::INCOMPAT_LONG_SYMLINK));
+    let inode = fs.get_inode(inode_id).unwrap();
+    assert!(inode.is_slow_symlink());
+    assert_eq!((inode.size as usize, inode.blocks), (file.len(), 1));
+
+    let mut target = [0u8; muon::MAX_SYMLINK_LEN];
+    assert_eq!(fs.read_link_into("/deep", &mut target).unwrap(), file.len());
+    assert_eq!(&target[..file.len()], file.as_bytes());
+    let mut short = [0u8; muon::MAX_PATH_LEN];
+    assert_eq!(fs.read_link("/deep", &mut short).unwrap_err(), Error::PathTooLong);
+    let mut buf = [0u8; 4];
+    fs.fread("/deep", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"deep");
+
+    // The longest target spans several blocks; relative targets are followed as well.
+    let pad = muon::MAX_SYMLINK_LEN - (file.len() - 1);
+    let relative = format!("{}{}{}", "./".repeat(pad / 2), "/".repeat(pad % 2), &file[1..]);
+    assert_eq!(relative.len(), muon::MAX_SYMLINK_LEN);
+    let inode_id = fs.symlink(&relative, "/relative").unwrap();
+    let inode = fs.get_inode(inode_id).unwrap();
+    assert_eq!(inode.blocks as usize, relative.len().div_ceil(BLOCK_SIZE));
+    assert_eq!(fs.read_link_into("/relative", &mut target).unwrap(), relative.len());
+    assert_eq!(&target[..relative.len()], relative.as_bytes());
+    fs.fread("/relative", 0, &mut buf).unwrap();
+    let too_long = "x".repeat(muon::MAX_SYMLINK_LEN + 1);
+    assert_eq!(fs.symlink(&too_long, "/too_long").unwrap_err(), Error::PathTooLong);
+    // Short targets still live in the inode.
+    let inode_id = fs.symlink("/deep", "/short").unwrap();
+    assert!(!fs.get_inode(inode_id).unwrap().is_slow_symlink());
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let mut buf = [0u8; 4];
+    fs.fread("/short", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"deep");
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    for link in ["/deep", "/relative", "/short"] {
+        fs.remove(link, FileType::Symlink).unwrap();
+    }
+    assert_eq!(fs.superblock().free_blocks, free_before_links);
+}
+
+#[test]
+fn test_long_symlink_out_of_space() {
+    let rd = Arc::new(RamDevice::new(128));
+    let mut fs = FileSystem::format(rd.clone(), 128, 32).unwrap();
+    // Files of direct blocks only, until a single block is left.
+    let mut i = 0;
+    while fs.superblock().free_blocks > 1 {
+        let path = format!("/file{}", i / muon::NUM_DIRECT_PTRS);
+        if i % muon::NUM_DIRECT_PTRS == 0 {
+            fs.creat(&path, FileType::Regular, Mode::RW).unwrap();
+        } else {
+            fs.fwrite(&path, (i % muon::NUM_DIRECT_PTRS - 1) * BLOCK_SIZE, &[1u8; BLOCK_SIZE]).unwrap();
+        }
+        i += 1;
+    }
+    assert_eq!(fs.superblock().free_blocks, 1);
+    let (free_blocks, free_inodes) = (fs.superblock().free_blocks, fs.superblock().free_inodes);
+
+    // The target needs two blocks, the one it got and the inode are released.
+    // The data bitmap reports running out of blocks as OutOfBounds.
+    let target = format!("/{}", "x".repeat(BLOCK_SIZE));
+    assert_eq!(fs.symlink(&target, "/link").unwrap_err(), Error::OutOfBounds);
+    assert_eq!(fs.superblock().free_blocks, free_blocks);
+    assert_eq!(fs.superblock().free_inodes, free_inodes);
+    assert_eq!(fs.lookup("/link").unwrap_err(), Error::NotFound);
+    fs.symlink("/file0", "/link").unwrap();
+    fs.unmount().unwrap();
+
+    let fs = FileSystem::mount(rd).unwrap();
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+}
@@ -1,14 +1,13 @@
 #![allow(unused)]
-use std::{collections::VecDeque, path, sync::{Arc, Mutex}};
+use std::{collections::VecDeque, path, sync::{Arc, Mutex, OnceLock, Weak}};
 
-use common::{LruCache, RamDisk};
-use muon::{BlockDevice, Cache, Cached, FileSystem, FileType, Mode, Result, BLOCK_SIZE};
+use muon::{bmap, BlockDevice, BufferCache, Cache, Cached, ClockCache, LruCache, RamDevice, FileSystem, FileType, Mode, Result, BLOCK_SIZE, CSUMS_PER_BLOCK, NUM_DIRECT_PTRS, READAHEAD_MAX_BLOCKS, READAHEAD_MIN_BLOCKS};
 
 mod common;
 
 #[test]
 fn test_cached() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let cache = LruCache::new(4);
     let cached = Cached::new(rd, cache);
     let mut fs = FileSystem::format(Arc::new(cached), 64, 80).unwrap();
@@ -20,7 +19,7 @@ fn test_cached() {
 
 #[test]
 fn test_hard_link() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let cache = LruCache::new(4);
     let cached = Cached::new(rd, cache);
     let mut fs = FileSystem::format(Arc::new(cached), 64, 80).unwrap();
@@ -82,7 +81,7 @@ fn test_hard_link() {
 
 #[test]
 fn test_mkdir_3() {
-    let rd = RamDisk::new(64);
+    let rd = RamDevice::new(64);
     let cache = LruCache::new(4);
     let cached = Cached::new(rd, cache);
     let mut fs = FileSystem::format(Arc::new(cached), 64, 80).unwrap();
@@ -153,4 +152,449 @@ fn read_dir_recursive(fs: &mut FileSystem<impl BlockDevice>, path: &str, depth:
     for next_path in next_level_entries {
         read_dir_recursive(fs, &next_path, depth - 1);
     }
-}
\ No newline at end of file
+}
+
+/// Block device recording the reads and writes reaching the underlying device.
+struct CountingDevice {
+    inner: Arc<RamDevice>,
+    reads: BlockLog,
+    writes: BlockLog,
+    /// Number of blocks of each `read_blocks` call.
+    read_runs: Arc<Mutex<Vec<usize>>>,
+}
+
+impl CountingDevice {
+    fn new(inner: Arc<RamDevice>, writes: BlockLog) -> Self {
+        CountingDevice { inner, reads: Arc::new(Mutex::new(Vec::new())), writes, read_runs: Arc::new(Mutex::new(Vec::new())) }
+    }
+}
+
+impl BlockDevice for CountingDevice {
+    fn num_blocks(&self) -> usize {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.reads.lock().unwrap().push(block_id);
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.writes.lock().unwrap().push(block_id);
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn read_blocks(&self, start_block_id: u32, buf: &mut [u8]) -> Result<()> {
+        let count = buf.len() / BLOCK_SIZE;
+        self.read_runs.lock().unwrap().push(count);
+        self.reads.lock().unwrap().extend(start_block_id..start_block_id + count as u32);
+        self.inner.read_blocks(start_block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+type BlockLog = Arc<Mutex<Vec<u32>>>;
+
+fn counting_cached<C: Cache>(cache: C) -> (Cached<CountingDevice, C>, Arc<RamDevice>, BlockLog) {
+    let rd = Arc::new(RamDevice::new(16));
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let device = CountingDevice::new(rd.clone(), writes.clone());
+    (Cached::new(device, cache), rd, writes)
+}
+
+fn read_raw(rd: &RamDevice, block_id: u32) -> u8 {
+    let mut buf = [0u8; BLOCK_SIZE];
+    rd.read_block(block_id, &mut buf).unwrap();
+    buf[0]
+}
+
+#[test]
+fn test_lru_cache_write_back() {
+    let (cached, rd, writes) = counting_cached(LruCache::new(2));
+    cached.write_block(1, &[1u8; BLOCK_SIZE]).unwrap();
+    cached.write_block(2, &[2u8; BLOCK_SIZE]).unwrap();
+    assert!(writes.lock().unwrap().is_empty(), "Writes should stay in the cache");
+
+    // Block 1 becomes the most recently used, so block 2 is evicted and written back.
+    let mut buf = [0u8; BLOCK_SIZE];
+    cached.read_block(1, &mut buf).unwrap();
+    cached.write_block(3, &[3u8; BLOCK_SIZE]).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![2]);
+    assert_eq!(read_raw(&rd, 2), 2);
+    assert_eq!(read_raw(&rd, 1), 0);
+
+    // Clean blocks filled on a read miss are not written back.
+    cached.read_block(5, &mut buf).unwrap();
+    cached.read_block(6, &mut buf).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![2, 1, 3]);
+
+    cached.write_block(6, &[6u8; BLOCK_SIZE]).unwrap();
+    cached.flush().unwrap();
+    cached.flush().unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![2, 1, 3, 6], "Flush should write dirty blocks once");
+    assert_eq!(read_raw(&rd, 6), 6);
+    cached.read_block(2, &mut buf).unwrap();
+    assert_eq!(buf[0], 2);
+}
+
+#[test]
+fn test_clock_cache_write_back() {
+    let (cached, rd, writes) = counting_cached(ClockCache::new(3));
+    cached.write_block(1, &[1u8; BLOCK_SIZE]).unwrap();
+    cached.write_block(2, &[2u8; BLOCK_SIZE]).unwrap();
+    cached.write_block(3, &[3u8; BLOCK_SIZE]).unwrap();
+    assert!(writes.lock().unwrap().is_empty(), "Writes should stay in the cache");
+
+    // Every block has its reference bit set, so the hand sweeps once and evicts block 1.
+    cached.write_block(4, &[4u8; BLOCK_SIZE]).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![1]);
+    assert_eq!(read_raw(&rd, 1), 1);
+
+    // Block 2 lost its reference bit in the sweep, block 3 gets a second chance once accessed.
+    let mut buf = [0u8; BLOCK_SIZE];
+    cached.read_block(3, &mut buf).unwrap();
+    cached.read_block(7, &mut buf).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![1, 2]);
+
+    cached.flush().unwrap();
+    let mut flushed = writes.lock().unwrap().clone();
+    flushed.sort();
+    assert_eq!(flushed, vec![1, 2, 3, 4], "Flush should write dirty blocks only");
+    for block_id in 1..=4 {
+        assert_eq!(read_raw(&rd, block_id), block_id as u8);
+    }
+}
+
+#[test]
+fn test_clock_cached_fs() {
+    let rd = Arc::new(RamDevice::new(128));
+    let device = CountingDevice::new(rd.clone(), Arc::new(Mutex::new(Vec::new())));
+    let mut fs = FileSystem::format(Arc::new(Cached::new(device, ClockCache::new(8))), 128, 32).unwrap();
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/dir/file", FileType::Regular, Mode::RW).unwrap();
+    let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 253) as u8).collect();
+    fs.fwrite("/dir/file", 0, &data).unwrap();
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd).unwrap();
+    let mut buf = vec![0u8; data.len()];
+    fs.fread("/dir/file", 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn test_buffer_cache_shares_buffers() {
+    let rd = Arc::new(RamDevice::new(16));
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let cache = BufferCache::new(CountingDevice::new(rd.clone(), writes.clone()), 2);
+
+    let bh = cache.get_block(1).unwrap();
+    let bh2 = cache.get_block(1).unwrap();
+    assert!(Arc::ptr_eq(&bh, &bh2), "The same block should be handed out as the same buffer");
+    bh.lock()[0] = 1;
+    bh.mark_dirty();
+    cache.put_block(bh).unwrap();
+    assert_eq!(bh2.lock()[0], 1);
+    drop(bh2);
+    assert!(writes.lock().unwrap().is_empty(), "Dirty buffers should stay in the cache");
+
+    // Block 1 is the least recently used, unreferenced buffer, so it is written back and evicted.
+    let bh2 = cache.new_block(2).unwrap();
+    bh2.lock()[0] = 2;
+    cache.put_block(bh2).unwrap();
+    let bh3 = cache.get_block(3).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![1]);
+    assert_eq!(read_raw(&rd, 1), 1);
+
+    // Referenced buffers are never evicted, the cache grows instead.
+    let bh4 = cache.get_block(4).unwrap();
+    let bh5 = cache.get_block(5).unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![1, 2]);
+    assert_eq!(cache.len(), 3);
+
+    cache.flush().unwrap();
+    cache.flush().unwrap();
+    assert_eq!(*writes.lock().unwrap(), vec![1, 2], "Clean buffers should not be written back");
+    let mut buf = [0u8; BLOCK_SIZE];
+    cache.read_block(2, &mut buf).unwrap();
+    assert_eq!(buf[0], 2);
+}
+
+/// Looks up block 2 in the cache above it while writing, as other threads may meanwhile.
+struct LookupDevice {
+    inner: Arc<RamDevice>,
+    cache: Arc<OnceLock<Weak<BufferCache<LookupDevice>>>>,
+    seen: Arc<Mutex<Vec<u8>>>,
+}
+
+impl BlockDevice for LookupDevice {
+    fn num_blocks(&self) -> usize {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        if let Some(cache) = self.cache.get().and_then(Weak::upgrade) {
+            let bh = cache.get_block(2)?;
+            self.seen.lock().unwrap().push(bh.lock()[0]);
+        }
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_buffer_cache_unlocked_during_write_back() {
+    let rd = Arc::new(RamDevice::new(16));
+    let slot = Arc::new(OnceLock::new());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let device = LookupDevice { inner: rd.clone(), cache: slot.clone(), seen: seen.clone() };
+    let cache = Arc::new(BufferCache::new(device, 2));
+    slot.set(Arc::downgrade(&cache)).unwrap();
+
+    // Lookups while writing back, on flush or on eviction, do not wait for the write.
+    cache.write_block(1, &[1u8; BLOCK_SIZE]).unwrap();
+    cache.write_block(2, &[2u8; BLOCK_SIZE]).unwrap();
+    cache.flush().unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![2, 2]);
+    cache.write_block(1, &[3u8; BLOCK_SIZE]).unwrap();
+    cache.get_block(2).unwrap();
+    cache.get_block(4).unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![2, 2, 2]);
+    assert_eq!(read_raw(&rd, 1), 3);
+}
+
+#[test]
+fn test_buffer_cached_fs() {
+    let rd = Arc::new(RamDevice::new(128));
+    let device = CountingDevice::new(rd.clone(), Arc::new(Mutex::new(Vec::new())));
+    let mut fs = FileSystem::format(Arc::new(BufferCache::new(device, 8)), 128, 32).unwrap();
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/dir/file", FileType::Regular, Mode::RW).unwrap();
+    fs.link("/dir/file", "/link").unwrap();
+    let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    fs.fwrite("/dir/file", 0, &data).unwrap();
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd).unwrap();
+    let mut buf = vec![0u8; data.len()];
+    fs.fread("/link", 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+    let (inode_id, _) = fs.lookup("/dir/file").unwrap();
+    assert_eq!(fs.get_inode(inode_id).unwrap().links_cnt, 2);
+}
+
+#[test]
+fn test_cached_readahead() {
+    let device = CountingDevice::new(Arc::new(RamDevice::new(16)), Arc::new(Mutex::new(Vec::new())));
+    let reads = device.reads.clone();
+    let cached = Cached::new(device, LruCache::new(8));
+    cached.readahead(&[3, 4, 5]).unwrap();
+    assert_eq!(*reads.lock().unwrap(), vec![3, 4, 5]);
+
+    // Prefetched blocks are hits, and already cached blocks are not fetched again.
+    let mut buf = [0u8; BLOCK_SIZE];
+    cached.read_block(4, &mut buf).unwrap();
+    cached.readahead(&[5, 6]).unwrap();
+    assert_eq!(*reads.lock().unwrap(), vec![3, 4, 5, 6]);
+
+    // A prefetch never overwrites dirty cached data.
+    cached.write_block(7, &[7u8; BLOCK_SIZE]).unwrap();
+    cached.readahead(&[7]).unwrap();
+    cached.read_block(7, &mut buf).unwrap();
+    assert_eq!(buf[0], 7);
+}
+
+#[test]
+fn test_fread_readahead() {
+    let rd = Arc::new(RamDevice::new(128));
+    let mut fs = FileSystem::format(rd.clone(), 128, 32).unwrap();
+    let inode_id = fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let data: Vec<u8> = (0..40 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    fs.fwrite("/file", 0, &data).unwrap();
+    let mut superblock = *fs.superblock();
+    let mut inode = fs.get_inode(inode_id).unwrap();
+    let blocks: Vec<u32> = (0..40)
+        .map(|i| bmap(&*rd, &mut superblock, &mut inode, (i * BLOCK_SIZE) as u64, false).unwrap())
+        .collect();
+
+    let mount = |max_window| {
+        let device = CountingDevice::new(rd.clone(), Arc::new(Mutex::new(Vec::new())));
+        let reads = device.reads.clone();
+        let mut fs = FileSystem::mount(Arc::new(Cached::new(device, LruCache::new(64)))).unwrap();
+        fs.set_readahead(max_window);
+        (fs, reads)
+    };
+    let mut buf = vec![0u8; BLOCK_SIZE];
+
+    // Reading from the start opens the window past the requested block.
+    let (mut fs, reads) = mount(READAHEAD_MAX_BLOCKS);
+    fs.fread_by_inode(inode_id, 0, &mut buf).unwrap();
+    assert!(reads.lock().unwrap().contains(&blocks[READAHEAD_MIN_BLOCKS]));
+    assert!(!reads.lock().unwrap().contains(&blocks[READAHEAD_MIN_BLOCKS + 1]));
+
+    // The window grows with every sequential read.
+    fs.fread_by_inode(inode_id, BLOCK_SIZE, &mut buf).unwrap();
+    assert!(reads.lock().unwrap().contains(&blocks[1 + 2 * READAHEAD_MIN_BLOCKS]));
+    let mut out = data[..2 * BLOCK_SIZE].to_vec();
+    for offset in (2 * BLOCK_SIZE..data.len()).step_by(BLOCK_SIZE) {
+        fs.fread_by_inode(inode_id, offset, &mut buf).unwrap();
+        out.extend_from_slice(&buf);
+    }
+    assert_eq!(out, data);
+    let data_reads: Vec<u32> = reads.lock().unwrap().iter().copied().filter(|b| blocks.contains(b)).collect();
+    assert_eq!(data_reads.len(), blocks.len(), "Every data block should be fetched once");
+
+    // Disabled read-ahead only fetches the requested blocks.
+    let (mut fs, reads) = mount(0);
+    fs.fread_by_inode(inode_id, 0, &mut buf).unwrap();
+    assert!(!reads.lock().unwrap().contains(&blocks[1]));
+}
+
+#[test]
+fn test_cached_multi_block_transfers() {
+    let (cached, rd, writes) = counting_cached(LruCache::new(4));
+    let data: Vec<u8> = (0..6 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    rd.write_blocks(2, &data).unwrap();
+
+    // Cached blocks win over the device, even when read as part of a run.
+    cached.write_block(4, &[9u8; BLOCK_SIZE]).unwrap();
+    let mut out = vec![0u8; 6 * BLOCK_SIZE];
+    cached.read_blocks(2, &mut out).unwrap();
+    assert_eq!(&out[..2 * BLOCK_SIZE], &data[..2 * BLOCK_SIZE]);
+    assert_eq!(&out[2 * BLOCK_SIZE..3 * BLOCK_SIZE], &[9u8; BLOCK_SIZE][..]);
+    assert_eq!(&out[3 * BLOCK_SIZE..], &data[3 * BLOCK_SIZE..]);
+
+    // Writes stay in the cache until flushed.
+    cached.write_blocks(10, &data[..2 * BLOCK_SIZE]).unwrap();
+    assert_eq!(read_raw(&rd, 10), 0);
+    cached.flush().unwrap();
+    assert_eq!(read_raw(&rd, 11), data[BLOCK_SIZE]);
+}
+
+#[test]
+fn test_buffer_cache_multi_block_reads() {
+    let rd = Arc::new(RamDevice::new(16));
+    let data: Vec<u8> = (0..6 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    rd.write_blocks(2, &data).unwrap();
+    let device = CountingDevice::new(rd.clone(), Arc::new(Mutex::new(Vec::new())));
+    let read_runs = device.read_runs.clone();
+    let cache = BufferCache::new(device, 4);
+
+    // The cached block splits the request into two device transfers.
+    cache.write_block(4, &[9u8; BLOCK_SIZE]).unwrap();
+    let mut out = vec![0u8; 6 * BLOCK_SIZE];
+    cache.read_blocks(2, &mut out).unwrap();
+    assert_eq!(&out[..2 * BLOCK_SIZE], &data[..2 * BLOCK_SIZE]);
+    assert_eq!(&out[2 * BLOCK_SIZE..3 * BLOCK_SIZE], &[9u8; BLOCK_SIZE][..]);
+    assert_eq!(&out[3 * BLOCK_SIZE..], &data[3 * BLOCK_SIZE..]);
+    assert_eq!(*read_runs.lock().unwrap(), vec![2, 3]);
+
+    // Blocks read as part of a run are cached.
+    let mut block = [0u8; BLOCK_SIZE];
+    cache.read_block(7, &mut block).unwrap();
+    assert_eq!(&block[..], &data[5 * BLOCK_SIZE..]);
+    assert_eq!(read_runs.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_fsync_after_link() {
+    let rd = Arc::new(RamDevice::new(256));
+    let device = CountingDevice::new(rd.clone(), Arc::new(Mutex::new(Vec::new())));
+    let mut fs = FileSystem::format(Arc::new(Cached::new(device, LruCache::new(256))), 256, 32).unwrap();
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/a", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/a", 0, b"linked").unwrap();
+    fs.flush().unwrap();
+
+    // The new entry in '/dir' reaches the disk with fsync of the file.
+    fs.link("/a", "/dir/b").unwrap();
+    fs.fsync("/a").unwrap();
+    let mut on_disk = FileSystem::mount(rd.clone()).unwrap();
+    let mut buf = [0u8; 6];
+    on_disk.fread("/dir/b", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"linked");
+}
+
+#[test]
+fn test_fsync_special_inode() {
+    let mut fs = FileSystem::format(Arc::new(Cached::new(RamDevice::new(128), LruCache::new(64))), 128, 32).unwrap();
+    // The reserved inode is a special file without data blocks.
+    assert_eq!(fs.get_inode(0).unwrap().ftype, FileType::Special);
+    fs.fsync_by_inode_id(0).unwrap();
+    fs.fdatasync_by_inode_id(0).unwrap();
+}
+
+#[test]
+fn test_fsync_writes_back_one_file() {
+    for buffered in [false, true] {
+        let rd = Arc::new(RamDevice::new(128));
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let device = CountingDevice::new(rd.clone(), writes.clone());
+        if buffered {
+            check_fsync(FileSystem::format(Arc::new(BufferCache::new(device, 64)), 128, 32).unwrap(), &rd, &writes);
+        } else {
+            check_fsync(FileSystem::format(Arc::new(Cached::new(device, LruCache::new(64))), 128, 32).unwrap(), &rd, &writes);
+        }
+    }
+}
+
+fn check_fsync(mut fs: FileSystem<impl BlockDevice>, rd: &Arc<RamDevice>, writes: &BlockLog) {
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.flush().unwrap();
+    fs.creat("/dir/a", FileType::Regular, Mode::RW).unwrap();
+    fs.creat("/b", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/dir/a", 0, &[1u8; 2 * BLOCK_SIZE]).unwrap();
+    fs.fwrite("/b", 0, &[2u8; 2 * BLOCK_SIZE]).unwrap();
+    let (a_id, _) = fs.lookup("/dir/a").unwrap();
+    let (b_id, _) = fs.lookup("/b").unwrap();
+    let a_blocks = *fs.get_inode(a_id).unwrap().get_block_ptrs().unwrap();
+    let b_blocks = *fs.get_inode(b_id).unwrap().get_block_ptrs().unwrap();
+    writes.lock().unwrap().clear();
+
+    fs.fdatasync("/dir/a").unwrap();
+    let written = writes.lock().unwrap().clone();
+    assert!(written.contains(&a_blocks.direct[0].unwrap()));
+    assert!(written.contains(&a_blocks.direct[1].unwrap()));
+    assert!(!written.contains(&b_blocks.direct[0].unwrap()), "Other files should stay cached");
+
+    // The new file's entry in '/dir' reaches the disk with fsync.
+    fs.fsync("/dir/a").unwrap();
+    let mut on_disk = FileSystem::mount(rd.clone()).unwrap();
+    let mut buf = [0u8; 2 * BLOCK_SIZE];
+    on_disk.fread("/dir/a", 0, &mut buf).unwrap();
+    assert_eq!(buf, [1u8; 2 * BLOCK_SIZE]);
+    assert!(!writes.lock().unwrap().contains(&b_blocks.direct[1].unwrap()));
+}
+
+#[test]
+fn test_fwrite_writes_new_blocks_once() {
+    // Without a cache, blocks written whole are not zeroed before their contents are written.
+    let rd = Arc::new(RamDevice::new(256));
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let mut fs = FileSystem::format(Arc::new(CountingDevice::new(rd.clone(), writes.clone())), 256, 32).unwrap();
+    fs.creat("/a", FileType::Regular, Mode::RW).unwrap();
+    writes.lock().unwrap().clear();
+    fs.fwrite("/a", 0, &[1u8; 4 * BLOCK_SIZE]).unwrap();
+    let (a_id, _) = fs.lookup("/a").unwrap();
+    let blocks = *fs.get_inode(a_id).unwrap().get_block_ptrs().unwrap();
+    let written = writes.lock().unwrap().clone();
+    for block_id in blocks.direct[..4].iter().map(|b| b.unwrap()) {
+        assert_eq!(written.iter().filter(|&&b| b == block_id).count(), 1, "{:?}", written);
+    }
+
+    // A partially written block still reads as zeros elsewhere.
+    fs.fwrite("/a", 5 * BLOCK_SIZE + 10, b"x").unwrap();
+    let mut buf = [1u8; BLOCK_SIZE];
+    fs.fread("/a", 5 * BLOCK_SIZE, &mut buf[..11]).unwrap();
+    assert_eq!(&buf[..11], b"\0\0\0\0\0\0\0\0\0\0x");
+}
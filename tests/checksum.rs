@@ -0,0 +1,306 @@
+#![allow(unused)]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+mod common;
+
+use muon::*;
+
+/// Formats a device holding `/file`, unmounted.
+fn formatted() -> Arc<RamDevice> {
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, b"data").unwrap();
+    fs.unmount().unwrap();
+    rd
+}
+
+/// Flips one bit of a block, behind the back of the file system.
+fn flip_bit(rd: &RamDevice, block_id: u32, offset: usize) {
+    let mut buf = [0u8; BLOCK_SIZE];
+    rd.read_block(block_id, &mut buf).unwrap();
+    buf[offset] ^= 0x10;
+    rd.write_block(block_id, &buf).unwrap();
+}
+
+#[test]
+fn test_crc32c() {
+    assert_eq!(crc32c(b""), 0);
+    assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    assert_eq!(crc32c_append(crc32c(b"1234"), b"56789"), crc32c(b"123456789"));
+}
+
+#[test]
+fn test_superblock_checksum() {
+    let rd = formatted();
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_ne!(sb.feature_ro_compat & RO_COMPAT_METADATA_CSUM, 0, "New file systems should have checksums");
+
+    // free_blocks
+    flip_bit(&rd, SUPERBLOCK_ID, 12);
+    assert_eq!(FileSystem::mount(rd).unwrap_err(), Error::ChecksumMismatch);
+}
+
+#[test]
+fn test_inode_checksum() {
+    let rd = formatted();
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let (inode_id, _) = fs.lookup("/file").unwrap();
+    let sb = *fs.superblock();
+    fs.unmount().unwrap();
+
+    let inodes_per_block = (BLOCK_SIZE / INODE_SIZE) as u32;
+    let block_id = sb.inode_table_start + inode_id / inodes_per_block;
+    let offset = (inode_id % inodes_per_block) as usize * INODE_SIZE;
+    // Low byte of the file size.
+    flip_bit(&rd, block_id, offset + INODE_SIZE - 8);
+
+    let mut fs = FileSystem::mount(rd).unwrap();
+    assert_eq!(fs.get_inode(inode_id).unwrap_err(), Error::ChecksumMismatch);
+    let mut buf = [0u8; 4];
+    assert_eq!(fs.fread("/file", 0, &mut buf).unwrap_err(), Error::ChecksumMismatch);
+    assert!(fs.get_inode(fs.root_inode_id()).is_ok(), "Other inodes of the block are intact");
+}
+
+#[test]
+fn test_large_inode_checksum() {
+    let rd = Arc::new(RamDevice::new(64));
+    let options = FormatOptions { inode_size: 256, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 64, 16, options).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let (inode_id, _) = fs.lookup("/file").unwrap();
+    let sb = *fs.superblock();
+    fs.unmount().unwrap();
+
+    // The extra fields area is covered as well.
+    let block_id = sb.inode_table_start + inode_id / sb.inodes_per_block();
+    let offset = (inode_id % sb.inodes_per_block()) as usize * sb.inode_size();
+    flip_bit(&rd, block_id, offset + sb.inode_size() - 1);
+    let fs = FileSystem::mount(rd).unwrap();
+    assert_eq!(fs.get_inode(inode_id).unwrap_err(), Error::ChecksumMismatch);
+    assert!(fs.get_inode(fs.root_inode_id()).is_ok());
+}
+
+#[test]
+fn test_directory_checksum() {
+    let rd = formatted();
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let root = fs.get_inode(fs.root_inode_id()).unwrap();
+    let dir_block = root.get_block_ptrs().unwrap().direct[0].unwrap();
+    fs.unmount().unwrap();
+
+    // First character of the name of `/file`, after '.' and '..'.
+    flip_bit(&rd, dir_block, 2 * DIR_ENTRY_SIZE + 4);
+
+    let mut fs = FileSystem::mount(rd).unwrap();
+    assert_eq!(fs.lookup("/file").unwrap_err(), Error::ChecksumMismatch);
+    assert_eq!(fs.read_dir("/").unwrap_err(), Error::ChecksumMismatch);
+    assert!(fs.superblock().check_due().is_some());
+}
+
+#[test]
+fn test_bitmap_checksum() {
+    let rd = formatted();
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    // A free data block, far from the allocated ones.
+    flip_bit(&rd, sb.data_bitmap_start, 2);
+
+    let mut fs = FileSystem::mount(rd).unwrap();
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+    assert_eq!(fs.fwrite("/other", 0, b"data").unwrap_err(), Error::ChecksumMismatch);
+}
+
+#[test]
+fn test_image_without_checksums() {
+    // Images from before the checksums have no features set.
+    let rd = formatted();
+    let mut sb = read_superblock(rd.as_ref()).unwrap();
+    sb.feature_ro_compat = 0;
+    sb.checksum = 0;
+    write_superblock(rd.as_ref(), &sb).unwrap();
+    // Neither verified nor updated.
+    flip_bit(&rd, sb.data_bitmap_start, 2);
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/other", 0, b"data").unwrap();
+    let mut buf = [0u8; 4];
+    fs.fread("/file", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    fs.unmount().unwrap();
+
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!(sb.feature_ro_compat, 0);
+    assert_eq!(sb.checksum, 0);
+}
+
+/// Formats a device with data checksums, holding `/dir/file` with an indirect block
+/// and a partial last block, unmounted.
+fn formatted_with_data_checksums() -> (Arc<RamDevice>, Vec<u8>) {
+    let rd = Arc::new(RamDevice::new(256));
+    let options = FormatOptions { data_checksums: true, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 256, 32, options).unwrap();
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/dir/file", FileType::Regular, Mode::RW).unwrap();
+    let data: Vec<u8> = (0..20 * BLOCK_SIZE + 100).map(|i| (i % 253) as u8).collect();
+    fs.fwrite("/dir/file", 0, &data).unwrap();
+    // A partial write into the middle of a block.
+    fs.fwrite("/dir/file", 3 * BLOCK_SIZE + 7, b"patched").unwrap();
+    fs.unmount().unwrap();
+    let mut data = data;
+    data[3 * BLOCK_SIZE + 7..3 * BLOCK_SIZE + 14].copy_from_slice(b"patched");
+    (rd, data)
+}
+
+fn file_blocks(rd: &Arc<RamDevice>, path: &str) -> (u32, BlockPtr) {
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let (inode_id, _) = fs.lookup(path).unwrap();
+    let block_ptrs = *fs.get_inode(inode_id).unwrap().get_block_ptrs().unwrap();
+    fs.unmount().unwrap();
+    (inode_id, block_ptrs)
+}
+
+#[test]
+fn test_data_checksums() {
+    let (rd, data) = formatted_with_data_checksums();
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_ne!(sb.feature_ro_compat & RO_COMPAT_DATA_CSUM, 0);
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(fs.fread("/dir/file", 0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+    fs.unmount().unwrap();
+
+    let (_, block_ptrs) = file_blocks(&rd, "/dir/file");
+    flip_bit(&rd, block_ptrs.direct[3].unwrap(), 100);
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.fread("/dir/file", 0, &mut buf).unwrap_err(), Error::ChecksumMismatch);
+    let mut small = [0u8; 10];
+    assert_eq!(fs.fread("/dir/file", 3 * BLOCK_SIZE + 50, &mut small).unwrap_err(), Error::ChecksumMismatch);
+    assert_eq!(fs.fwrite("/dir/file", 3 * BLOCK_SIZE + 50, b"x").unwrap_err(), Error::ChecksumMismatch);
+    // Blocks before the damaged one read fine.
+    assert_eq!(fs.fread("/dir/file", 0, &mut buf[..3 * BLOCK_SIZE]).unwrap(), 3 * BLOCK_SIZE);
+    // Overwriting the whole block repairs it.
+    fs.fwrite("/dir/file", 3 * BLOCK_SIZE, &data[3 * BLOCK_SIZE..4 * BLOCK_SIZE]).unwrap();
+    fs.fread("/dir/file", 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+    fs.unmount().unwrap();
+
+    flip_bit(&rd, block_ptrs.indirect.unwrap(), 0);
+    let mut fs = FileSystem::mount(rd).unwrap();
+    assert_eq!(fs.fread("/dir/file", 0, &mut buf).unwrap_err(), Error::ChecksumMismatch);
+}
+
+/// RAM device whose multi-block writes fail once `failing` is set.
+#[derive(Debug)]
+struct FailingRuns {
+    inner: RamDevice,
+    failing: AtomicBool,
+}
+
+impl BlockDevice for FailingRuns {
+    fn num_blocks(&self) -> usize {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, start_block_id: u32, buf: &[u8]) -> Result<()> {
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(Error::IoError);
+        }
+        self.inner.write_blocks(start_block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_failed_write_keeps_owned_blocks() {
+    let device = Arc::new(FailingRuns { inner: RamDevice::new(128), failing: AtomicBool::new(false) });
+    let options = FormatOptions { data_checksums: true, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(device.clone(), 128, 16, options).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let data = [7u8; 2 * BLOCK_SIZE];
+    fs.fwrite("/file", 0, &data).unwrap();
+    let free_blocks = fs.superblock().free_blocks;
+
+    // The run covers the two blocks of the file and two new ones.
+    device.failing.store(true, Ordering::Relaxed);
+    assert_eq!(fs.fwrite("/file", 0, &[9u8; 4 * BLOCK_SIZE]).unwrap_err(), Error::IoError);
+    device.failing.store(false, Ordering::Relaxed);
+
+    // Only the new blocks are given back, the old ones keep their contents and checksums.
+    assert_eq!(fs.superblock().free_blocks, free_blocks);
+    let (inode_id, _) = fs.lookup("/file").unwrap();
+    let inode = fs.get_inode(inode_id).unwrap();
+    assert_eq!(inode.blocks, 2);
+    assert_eq!(inode.get_block_ptrs().unwrap().direct[2], None);
+    let mut buf = [0u8; 2 * BLOCK_SIZE];
+    assert_eq!(fs.fread("/file", 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(buf, data);
+    assert!(fs.scrub().unwrap().is_clean());
+}
+
+#[test]
+fn test_scrub() {
+    let (rd, _) = formatted_with_data_checksums();
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/other", 0, b"other").unwrap();
+    fs.symlink("/dir/file", "/link").unwrap();
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    // Placeholder, root, '/dir', '/dir/file', '/other' and '/link'.
+    assert_eq!(report.inodes_checked, 6);
+    let (other_id, _) = fs.lookup("/other").unwrap();
+    fs.unmount().unwrap();
+
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    let (file_id, file_blocks) = file_blocks(&rd, "/dir/file");
+    flip_bit(&rd, file_blocks.direct[5].unwrap(), 0);
+    flip_bit(&rd, sb.inode_bitmap_start, 100);
+    let inodes_per_block = (BLOCK_SIZE / INODE_SIZE) as u32;
+    flip_bit(&rd, sb.inode_table_start + other_id / inodes_per_block, (other_id % inodes_per_block) as usize * INODE_SIZE + 8);
+
+    let fs = FileSystem::mount(rd.clone()).unwrap();
+    let report = fs.scrub().unwrap();
+    log!("Scrub report: {:?}", report);
+    assert_eq!(report.errors, vec![
+        ScrubError::Bitmap { block_id: sb.inode_bitmap_start },
+        ScrubError::Block { inode_id: file_id, block_id: file_blocks.direct[5].unwrap() },
+        ScrubError::Inode { inode_id: other_id },
+    ]);
+    assert_eq!(report.damaged_inodes(), vec![file_id, other_id]);
+    // Damage found keeps the file system dirty.
+    fs.unmount().unwrap();
+    assert!(read_superblock(rd.as_ref()).unwrap().check_due().is_some());
+}
+
+#[test]
+fn test_scrub_without_data_checksums() {
+    let rd = formatted();
+    let (file_id, file_blocks) = file_blocks(&rd, "/file");
+    flip_bit(&rd, file_blocks.direct[0].unwrap(), 0);
+    let fs = FileSystem::mount(rd.clone()).unwrap();
+    assert!(fs.scrub().unwrap().is_clean(), "File contents are not verified");
+
+    let root = fs.get_inode(fs.root_inode_id()).unwrap();
+    let dir_block = root.get_block_ptrs().unwrap().direct[0].unwrap();
+    fs.unmount().unwrap();
+    flip_bit(&rd, dir_block, 100);
+    let fs = FileSystem::mount(rd).unwrap();
+    let report = fs.scrub().unwrap();
+    assert_eq!(report.errors, vec![ScrubError::Block { inode_id: fs.root_inode_id(), block_id: dir_block }]);
+}
@@ -0,0 +1,110 @@
+#![allow(unused)]
+
+use std::sync::Arc;
+
+mod common;
+
+use muon::*;
+
+fn temp_image(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("muon-{}-{}.img", name, std::process::id()))
+}
+
+#[test]
+fn test_ram_device() {
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd.clone(), 64, 32).unwrap();
+    fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/test.txt", 0, b"Hello, RAM!").unwrap();
+
+    // Out of range blocks are rejected.
+    let mut buf = [0u8; BLOCK_SIZE];
+    assert_eq!(rd.read_block(64, &mut buf).unwrap_err(), Error::InvalidBlockId);
+
+    // An image copied out of the device mounts again.
+    let copy = Arc::new(RamDevice::from_vec(rd.to_vec()));
+    assert_eq!(copy.num_blocks(), 64);
+    let mut fs2 = FileSystem::mount(copy).unwrap();
+    let mut data = [0u8; 11];
+    fs2.fread("/test.txt", 0, &mut data).unwrap();
+    assert_eq!(&data, b"Hello, RAM!");
+}
+
+#[test]
+fn test_file_device() {
+    let path = temp_image("file-device");
+    {
+        let device = FileDevice::create(&path, 100).unwrap();
+        assert_eq!(device.num_blocks(), 100);
+        let mut fs = FileSystem::format(Arc::new(device), 100, 32).unwrap();
+        fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
+        fs.fwrite("/test.txt", 0, b"Hello, file!").unwrap();
+        fs.unmount().unwrap();
+    }
+
+    // Size is derived from the image length.
+    let device = FileDevice::open(&path).unwrap();
+    assert_eq!(device.num_blocks(), 100);
+    let mut fs = FileSystem::mount(Arc::new(device)).unwrap();
+    let mut data = [0u8; 12];
+    fs.fread("/test.txt", 0, &mut data).unwrap();
+    assert_eq!(&data, b"Hello, file!");
+
+    // Read-only devices reject writes.
+    let device = Arc::new(FileDevice::open_read_only(&path).unwrap());
+    assert!(device.is_read_only());
+    assert_eq!(FileSystem::mount(device.clone()).unwrap_err(), Error::ReadOnly);
+    let mut fs = FileSystem::mount_with_options(device.clone(), MountOptions { read_only: true, ..Default::default() }).unwrap();
+    let res = fs.creat("/other.txt", FileType::Regular, Mode::RW);
+    assert_eq!(res.unwrap_err(), Error::ReadOnly, "Creating a file on a read-only device should fail");
+    assert_eq!(device.write_block(0, &[0u8; BLOCK_SIZE]).unwrap_err(), Error::NotWritable);
+
+    let mut buf = [0u8; BLOCK_SIZE];
+    assert_eq!(device.read_block(100, &mut buf).unwrap_err(), Error::InvalidBlockId);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_file_device_direct() {
+    let path = temp_image("file-device-direct");
+    FileDevice::create(&path, 64).unwrap();
+    // Some file systems (e.g. tmpfs) do not support O_DIRECT.
+    let Ok(device) = FileDevice::open_direct(&path, false) else {
+        log!("O_DIRECT not supported in {}, skipped", std::env::temp_dir().display());
+        std::fs::remove_file(&path).unwrap();
+        return;
+    };
+    let mut fs = FileSystem::format(Arc::new(device), 64, 32).unwrap();
+    fs.creat("/test.txt", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/test.txt", 0, b"Hello, direct!").unwrap();
+    let mut data = [0u8; 14];
+    fs.fread("/test.txt", 0, &mut data).unwrap();
+    assert_eq!(&data, b"Hello, direct!");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_multi_block_transfers() {
+    let path = temp_image("multi-block");
+    let file_device = FileDevice::create(&path, 32).unwrap();
+    let ram_device = RamDevice::new(32);
+    let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    for device in [&file_device as &dyn BlockDevice, &ram_device as &dyn BlockDevice] {
+        device.write_blocks(3, &data).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        device.read_block(5, &mut buf).unwrap();
+        assert_eq!(&buf[..], &data[2 * BLOCK_SIZE..3 * BLOCK_SIZE]);
+
+        let mut out = vec![0u8; 5 * BLOCK_SIZE];
+        device.read_blocks(3, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        // Transfers must be whole blocks, within the device.
+        assert_eq!(device.read_blocks(0, &mut out[..100]).unwrap_err(), Error::InvalidArgument);
+        assert_eq!(device.write_blocks(30, &data).unwrap_err(), Error::InvalidBlockId);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
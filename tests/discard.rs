@@ -0,0 +1,130 @@
+#![allow(unused)]
+
+use std::sync::{Arc, Mutex};
+
+mod common;
+
+use muon::*;
+
+/// RAM device recording the discard requests it receives.
+struct DiscardLog {
+    inner: RamDevice,
+    discards: Mutex<Vec<(u32, u32)>>,
+}
+
+impl DiscardLog {
+    fn new(num_blocks: usize) -> Self {
+        DiscardLog { inner: RamDevice::new(num_blocks), discards: Mutex::new(Vec::new()) }
+    }
+
+    fn take(&self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut *self.discards.lock().unwrap())
+    }
+}
+
+impl BlockDevice for DiscardLog {
+    fn num_blocks(&self) -> usize {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<()> {
+        self.discards.lock().unwrap().push((start_block_id, count));
+        self.inner.discard(start_block_id, count)
+    }
+}
+
+#[test]
+fn test_discard_on_free() {
+    let device = Arc::new(DiscardLog::new(128));
+    let mut fs = FileSystem::format(device.clone(), 128, 32).unwrap();
+    let data_start = fs.superblock().data_start;
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, &vec![1u8; 20 * BLOCK_SIZE]).unwrap();
+    let free_before = fs.superblock().free_blocks;
+    device.take();
+
+    // The data blocks and the indirect block are contiguous, so they go in a single request.
+    fs.ftruncate("/file").unwrap();
+    let discards = device.take();
+    assert_eq!(discards.len(), 1, "Freed runs should be batched: {:?}", discards);
+    assert_eq!(discards[0].1, 21);
+    assert_eq!(fs.superblock().free_blocks, free_before + 21);
+
+    // Removing a file discards its blocks too.
+    fs.fwrite("/file", 0, &vec![2u8; 3 * BLOCK_SIZE]).unwrap();
+    device.take();
+    fs.remove("/file", FileType::Regular).unwrap();
+    let discarded: u32 = device.take().iter().map(|&(_, count)| count).sum();
+    assert_eq!(discarded, 3);
+
+    // Trimming covers every free data block, i.e. all but the root directory block.
+    let trimmed = fs.trim_free_space().unwrap();
+    assert_eq!(trimmed, fs.superblock().free_blocks);
+    let discards = device.take();
+    assert_eq!(discards.iter().map(|&(_, count)| count).sum::<u32>(), trimmed);
+    assert!(discards.iter().all(|&(start, _)| start >= data_start));
+
+    // The file system is still usable afterwards.
+    fs.creat("/again", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/again", 0, b"still here").unwrap();
+    let mut buf = [0u8; 10];
+    fs.fread("/again", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"still here");
+}
+
+#[test]
+fn test_cached_discard() {
+    let cached = Cached::new(DiscardLog::new(16), LruCache::new(4));
+    cached.write_block(3, &[3u8; BLOCK_SIZE]).unwrap();
+    cached.write_block(4, &[4u8; BLOCK_SIZE]).unwrap();
+    cached.discard(3, 1).unwrap();
+    cached.flush().unwrap();
+
+    // The discarded block is dropped from the cache instead of being written back.
+    let mut buf = [0u8; BLOCK_SIZE];
+    cached.read_block(3, &mut buf).unwrap();
+    assert_eq!(buf, [0u8; BLOCK_SIZE]);
+    cached.read_block(4, &mut buf).unwrap();
+    assert_eq!(buf, [4u8; BLOCK_SIZE]);
+
+    let buffered = BufferCache::new(RamDevice::new(16), 4);
+    let bh = buffered.new_block(5).unwrap();
+    bh.lock().fill(5);
+    buffered.put_block(bh).unwrap();
+    buffered.discard(5, 1).unwrap();
+    buffered.flush().unwrap();
+    buffered.read_block(5, &mut buf).unwrap();
+    assert_eq!(buf, [0u8; BLOCK_SIZE]);
+}
+
+#[test]
+fn test_file_device_discard() {
+    let path = std::env::temp_dir().join(format!("muon-discard-{}.img", std::process::id()));
+    let device = FileDevice::create(&path, 16).unwrap();
+    device.write_blocks(2, &[7u8; 4 * BLOCK_SIZE]).unwrap();
+    match device.discard(3, 2) {
+        Ok(()) => {
+            let mut buf = [0u8; BLOCK_SIZE];
+            device.read_block(3, &mut buf).unwrap();
+            assert_eq!(buf, [0u8; BLOCK_SIZE]);
+            device.read_block(5, &mut buf).unwrap();
+            assert_eq!(buf, [7u8; BLOCK_SIZE]);
+        },
+        // The host file system may not support punching holes.
+        Err(e) => log!("Discard not supported here: {:?}", e),
+    }
+    assert_eq!(device.discard(15, 2).unwrap_err(), Error::InvalidBlockId);
+    std::fs::remove_file(&path).unwrap();
+}
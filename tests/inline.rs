@@ -0,0 +1,184 @@
+#![allow(unused)]
+
+use std::sync::Arc;
+
+mod common;
+
+use muon::*;
+
+fn read_all(fs: &mut FileSystem<RamDevice>, path: &str) -> Vec<u8> {
+    let size = inode_of(fs, path).size as usize;
+    let mut buf = vec![0u8; size];
+    assert_eq!(fs.fread(path, 0, &mut buf).unwrap(), size);
+    buf
+}
+
+fn inode_of(fs: &mut FileSystem<RamDevice>, path: &str) -> Inode {
+    let (inode_id, _) = fs.lookup(path).unwrap();
+    fs.get_inode(inode_id).unwrap()
+}
+
+#[test]
+fn test_inline_file() {
+    let rd = Arc::new(RamDevice::new(256));
+    let options = FormatOptions { inline_data: true, data_checksums: true, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 256, 32, options).unwrap();
+    assert!(fs.superblock().has_incompat(INCOMPAT_INLINE_DATA));
+    assert_eq!(fs.superblock().max_inline_size(), MAX_PATH_LEN);
+    let free = fs.superblock().free_blocks;
+
+    fs.creat("/config", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/config", 0, b"key = value\n").unwrap();
+    fs.fwrite("/config", 20, b"tail").unwrap();
+    let inode = inode_of(&mut fs, "/config");
+    assert!(inode.has_inline_data());
+    assert_eq!((inode.size, inode.blocks), (24, 0));
+    assert_eq!(fs.superblock().free_blocks, free);
+    assert_eq!(read_all(&mut fs, "/config"), b"key = value\n\0\0\0\0\0\0\0\0tail");
+    let mut buf = [0u8; 8];
+    assert_eq!(fs.fread("/config", 4, &mut buf).unwrap(), 8);
+    assert_eq!(&buf, b"= value\n");
+    assert_eq!(fs.fread("/config", 30, &mut buf).unwrap_err(), Error::EOF(Some(0)));
+
+    // Exactly the inline limit still fits.
+    let full: Vec<u8> = (0..MAX_PATH_LEN as u8).collect();
+    fs.fwrite("/config", 0, &full).unwrap();
+    assert!(inode_of(&mut fs, "/config").has_inline_data());
+    assert_eq!(read_all(&mut fs, "/config"), full);
+
+    // Growing past it moves the contents to a block.
+    fs.fwrite("/config", MAX_PATH_LEN, b"!").unwrap();
+    let inode = inode_of(&mut fs, "/config");
+    assert!(!inode.has_inline_data());
+    assert_eq!(inode.blocks, 1);
+    assert_eq!(fs.superblock().free_blocks, free - 1);
+    let mut expected = full.clone();
+    expected.push(b'!');
+    assert_eq!(read_all(&mut fs, "/config"), expected);
+    let large = vec![7u8; 3 * BLOCK_SIZE];
+    fs.fwrite("/config", 0, &large).unwrap();
+    assert_eq!(read_all(&mut fs, "/config"), large);
+
+    // Truncated, the file is stored inline again.
+    fs.ftruncate("/config").unwrap();
+    assert_eq!(fs.superblock().free_blocks, free);
+    fs.fwrite("/config", 0, b"small again").unwrap();
+    let inode = inode_of(&mut fs, "/config");
+    assert!(inode.has_inline_data());
+    assert_eq!(fs.superblock().free_blocks, free);
+    fs.ftruncate("/config").unwrap();
+    assert_eq!(inode_of(&mut fs, "/config").size, 0);
+    fs.fwrite("/config", 2, b"x").unwrap();
+    assert_eq!(read_all(&mut fs, "/config"), b"\0\0x");
+
+    // A write past the limit of an empty file goes straight to blocks.
+    fs.creat("/sparse", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/sparse", 1000, b"end").unwrap();
+    let inode = inode_of(&mut fs, "/sparse");
+    assert_eq!(inode.blocks, 1);
+    assert_eq!(read_all(&mut fs, "/sparse")[..1000], [0u8; 1000]);
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(read_all(&mut fs, "/config"), b"\0\0x");
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    fs.remove("/config", FileType::Regular).unwrap();
+    fs.remove("/sparse", FileType::Regular).unwrap();
+    assert_eq!(fs.superblock().free_blocks, free);
+}
+
+#[test]
+fn test_inline_file_in_large_inode() {
+    let rd = Arc::new(RamDevice::new(256));
+    let options = FormatOptions { inline_data: true, inode_size: 256, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 256, 32, options).unwrap();
+    let max = fs.superblock().max_inline_size();
+    assert_eq!(max, MAX_PATH_LEN + 256 - INODE_SIZE - std::mem::size_of::<InodeExtra>());
+    fs.creat("/a", FileType::Regular, Mode::RW).unwrap();
+    fs.creat("/b", FileType::Regular, Mode::RW).unwrap();
+    let a: Vec<u8> = (0..max).map(|i| i as u8).collect();
+    let b: Vec<u8> = (0..max).map(|i| !i as u8).collect();
+    fs.fwrite("/a", 0, &a).unwrap();
+    fs.fwrite("/b", 0, &b).unwrap();
+    assert!(inode_of(&mut fs, "/a").has_inline_data());
+    assert!(inode_of(&mut fs, "/b").has_inline_data());
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(read_all(&mut fs, "/a"), a);
+    assert_eq!(read_all(&mut fs, "/b"), b);
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    fs.fwrite("/a", max, b"more").unwrap();
+    assert_eq!(inode_of(&mut fs, "/a").blocks, 1);
+    assert_eq!(read_all(&mut fs, "/a")[..max], a[..]);
+    assert_eq!(read_all(&mut fs, "/b"), b);
+}
+
+#[test]
+fn test_inline_directory() {
+    let rd = Arc::new(RamDevice::new(256));
+    let options = FormatOptions { inline_data: true, inode_size: 512, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 256, 32, options).unwrap();
+    let per_inode = fs.superblock().max_inline_size() / DIR_ENTRY_SIZE;
+    assert_eq!(per_inode, 7);
+    let free = fs.superblock().free_blocks;
+    assert!(inode_of(&mut fs, "/").has_inline_data());
+
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    for i in 0..per_inode - 2 {
+        fs.creat(&format!("/dir/file{}", i), FileType::Regular, Mode::RW).unwrap();
+    }
+    let dir = inode_of(&mut fs, "/dir");
+    assert!(dir.has_inline_data());
+    assert_eq!(fs.read_dir("/dir").unwrap().len(), per_inode);
+    assert_eq!(fs.superblock().free_blocks, free);
+    fs.remove("/dir/file1", FileType::Regular).unwrap();
+    assert_eq!(fs.lookup("/dir/file1").unwrap_err(), Error::NotFound);
+    assert!(fs.lookup("/dir/file2").is_ok());
+
+    // Outgrows the inode.
+    for i in 1..per_inode + 2 {
+        fs.creat(&format!("/dir/more{}", i), FileType::Regular, Mode::RW).unwrap();
+    }
+    let dir = inode_of(&mut fs, "/dir");
+    assert_eq!(dir.blocks, 2);
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let names: Vec<String> = fs.read_dir("/dir").unwrap().iter()
+        .map(|entry| String::from_utf8_lossy(muon::trim_zero(&entry.name)).into_owned())
+        .collect();
+    assert_eq!(names.len(), 2 + per_inode - 3 + per_inode + 1);
+    assert!(names.contains(&"file0".to_string()) && !names.contains(&"file1".to_string()));
+    assert!(names.contains(&"more8".to_string()));
+    assert_eq!(fs.lookup("/dir/..").unwrap().0, ROOT_INODE_ID);
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+
+    // An inline directory is removed like any other.
+    fs.creat("/empty", FileType::Directory, Mode::RW).unwrap();
+    assert!(inode_of(&mut fs, "/empty").has_inline_data());
+    fs.remove("/empty", FileType::Directory).unwrap();
+}
+
+#[test]
+fn test_directory_in_small_inode() {
+    let rd = Arc::new(RamDevice::new(128));
+    let options = FormatOptions { inline_data: true, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd, 128, 32, options).unwrap();
+    // Room for '.', but not for '..' as well.
+    assert_eq!(fs.superblock().max_inline_size() / DIR_ENTRY_SIZE, 1);
+    let free = fs.superblock().free_blocks;
+
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    let dir = inode_of(&mut fs, "/dir");
+    assert_eq!(dir.blocks, 1);
+    assert_eq!(dir.size, 2 * DIR_ENTRY_SIZE as u64);
+    assert_eq!(fs.superblock().free_blocks, free - 1);
+    assert_eq!(fs.lookup("/dir/..").unwrap().0, ROOT_INODE_ID);
+    assert_eq!(fs.lookup("/dir/.").unwrap().0, dir.id);
+    fs.remove("/dir", FileType::Directory).unwrap();
+    assert_eq!(fs.superblock().free_blocks, free);
+}
@@ -0,0 +1,483 @@
+#![allow(unused)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+mod common;
+
+use muon::*;
+
+/// Write protected view of a RAM device, failing the test on any attempt to modify it.
+#[derive(Debug)]
+struct WriteProtected(Arc<RamDevice>);
+
+impl BlockDevice for WriteProtected {
+    fn num_blocks(&self) -> usize {
+        self.0.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.0.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        panic!("Block {} written to a read-only device", block_id);
+    }
+
+    fn discard(&self, start_block_id: u32, count: u32) -> Result<()> {
+        panic!("Blocks {}+{} discarded on a read-only device", start_block_id, count);
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+fn read_only_fs() -> FileSystem<WriteProtected> {
+    let rd = Arc::new(RamDevice::new(128));
+    let mut fs = FileSystem::format(rd.clone(), 128, 32).unwrap();
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/dir/file", FileType::Regular, Mode::RW).unwrap();
+    // Leaves a hole in the first blocks.
+    fs.fwrite("/dir/file", 2 * BLOCK_SIZE, b"data").unwrap();
+    fs.symlink("/dir/file", "/link").unwrap();
+    fs.unmount().unwrap();
+
+    let device = Arc::new(WriteProtected(rd));
+    assert_eq!(FileSystem::mount(device.clone()).unwrap_err(), Error::ReadOnly);
+    FileSystem::mount_with_options(device, MountOptions { read_only: true, ..Default::default() }).unwrap()
+}
+
+#[test]
+fn test_read_only_mount_reads() {
+    let mut fs = read_only_fs();
+    assert!(fs.is_read_only());
+
+    let mut buf = vec![0xffu8; 2 * BLOCK_SIZE + 4];
+    assert_eq!(fs.fread("/link", 0, &mut buf).unwrap(), buf.len());
+    assert!(buf[..2 * BLOCK_SIZE].iter().all(|&b| b == 0), "Holes should read as zeros");
+    assert_eq!(&buf[2 * BLOCK_SIZE..], b"data");
+    let (inode_id, _) = fs.lookup("/dir/file").unwrap();
+    assert_eq!(fs.get_inode(inode_id).unwrap().blocks, 1, "Reading should not fill holes");
+
+    assert_eq!(fs.read_dir("/dir").unwrap().len(), 3);
+    let mut target = [0u8; MAX_PATH_LEN];
+    fs.read_link("/link", &mut target).unwrap();
+    assert_eq!(trim_zero(&target), b"/dir/file");
+
+    fs.fsync("/dir/file").unwrap();
+    fs.flush().unwrap();
+    fs.unmount().unwrap();
+}
+
+#[test]
+fn test_read_only_mount_rejects_changes() {
+    let mut fs = read_only_fs();
+    let (inode_id, _) = fs.lookup("/dir/file").unwrap();
+
+    assert_eq!(fs.creat("/new", FileType::Regular, Mode::RW).unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.creat("/newdir", FileType::Directory, Mode::RW).unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.remove("/dir/file", FileType::Regular).unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.fwrite("/dir/file", 0, b"x").unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.ftruncate("/dir/file").unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.chmod_by_inode_id(inode_id, Mode::Read).unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.link("/dir/file", "/hard").unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.symlink("/dir/file", "/link2").unwrap_err(), Error::ReadOnly);
+    assert_eq!(fs.trim_free_space().unwrap_err(), Error::ReadOnly);
+    assert_eq!(import_tar(&mut fs), Error::ReadOnly);
+    assert!(fs.lookup("/new").is_err());
+}
+
+fn import_tar(fs: &mut FileSystem<WriteProtected>) -> Error {
+    let mut archive = Vec::new();
+    let mut src = FileSystem::format(Arc::new(RamDevice::new(64)), 64, 16).unwrap();
+    src.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    export_tar(&mut src, "/file", |record| {
+        archive.extend_from_slice(record);
+        Ok(())
+    }).unwrap();
+
+    let mut feed = || -> Result<usize> {
+        let mut importer = TarImporter::new(fs, "/")?;
+        for record in archive.chunks(TAR_RECORD_SIZE) {
+            importer.feed(record.try_into().unwrap())?;
+        }
+        importer.finish()
+    };
+    feed().unwrap_err()
+}
+
+/// RAM device counting flushes, barriers and ordering flushes of single blocks aside.
+#[derive(Debug)]
+struct FlushCounter {
+    inner: RamDevice,
+    flushes: AtomicUsize,
+}
+
+impl BlockDevice for FlushCounter {
+    fn num_blocks(&self) -> usize {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn barrier(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush_blocks(&self, _block_ids: &[u32]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Number of flushes issued by creating a file, then writing to it.
+fn count_flushes(options: MountOptions) -> (usize, usize) {
+    let device = Arc::new(FlushCounter { inner: RamDevice::new(64), flushes: AtomicUsize::new(0) });
+    FileSystem::format(device.clone(), 64, 16).unwrap().unmount().unwrap();
+    let mut fs = FileSystem::mount_with_options(device.clone(), options).unwrap();
+
+    let before = device.flushes.load(Ordering::Relaxed);
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let after_creat = device.flushes.load(Ordering::Relaxed);
+    fs.fwrite("/file", 0, b"data").unwrap();
+    let mut buf = [0u8; 4];
+    fs.fread("/file", 0, &mut buf).unwrap();
+    let after_write = device.flushes.load(Ordering::Relaxed);
+    (after_creat - before, after_write - after_creat)
+}
+
+#[test]
+fn test_sync_mount_options() {
+    assert_eq!(count_flushes(MountOptions::default()), (0, 0));
+    assert_eq!(count_flushes(MountOptions { dirsync: true, ..Default::default() }), (1, 0));
+    assert_eq!(count_flushes(MountOptions { sync: true, ..Default::default() }), (1, 1));
+
+    // Trimming counts as a modification too.
+    let device = Arc::new(FlushCounter { inner: RamDevice::new(64), flushes: AtomicUsize::new(0) });
+    FileSystem::format(device.clone(), 64, 16).unwrap().unmount().unwrap();
+    let options = MountOptions { sync: true, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(device.clone(), options).unwrap();
+    let before = device.flushes.load(Ordering::Relaxed);
+    fs.trim_free_space().unwrap();
+    assert_eq!(device.flushes.load(Ordering::Relaxed) - before, 1);
+}
+
+/// Formats a device holding `/file`, whose directory entry points past the inode table.
+/// The checksum of the directory block is updated, as if a faulty driver had written the entry.
+fn corrupted_device() -> Arc<RamDevice> {
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let root = fs.get_inode(fs.root_inode_id()).unwrap();
+    let dir_block = root.get_block_ptrs().unwrap().direct[0].unwrap();
+    fs.unmount().unwrap();
+
+    // '.' and '..' come first.
+    let mut buf = [0u8; BLOCK_SIZE];
+    rd.read_block(dir_block, &mut buf).unwrap();
+    buf[2 * DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE + 4].copy_from_slice(&1000u32.to_le_bytes());
+    rd.write_block(dir_block, &buf).unwrap();
+
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    let table_block = sb.csum_table_start + dir_block / CSUMS_PER_BLOCK as u32;
+    let offset = (dir_block as usize % CSUMS_PER_BLOCK) * 4;
+    let mut table = [0u8; BLOCK_SIZE];
+    rd.read_block(table_block, &mut table).unwrap();
+    table[offset..offset + 4].copy_from_slice(&crc32c(&buf).to_le_bytes());
+    rd.write_block(table_block, &table).unwrap();
+    rd
+}
+
+#[test]
+fn test_errors_mount_option() {
+    let options = MountOptions { errors: ErrorBehavior::Continue, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(corrupted_device(), options).unwrap();
+    assert_eq!(fs.lookup("/file").unwrap_err(), Error::Corrupted);
+    assert!(!fs.is_read_only());
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+
+    let options = MountOptions { errors: ErrorBehavior::RemountReadOnly, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(corrupted_device(), options).unwrap();
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+    assert_eq!(fs.fwrite("/file", 0, b"data").unwrap_err(), Error::Corrupted);
+    assert!(fs.is_read_only());
+    assert_eq!(fs.creat("/another", FileType::Regular, Mode::RW).unwrap_err(), Error::ReadOnly);
+    assert!(fs.lookup("/other").is_ok(), "Reads should keep working");
+
+    let options = MountOptions { errors: ErrorBehavior::Panic, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(corrupted_device(), options).unwrap();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fs.lookup("/file")));
+    assert!(res.is_err(), "Corruption should panic");
+}
+
+#[test]
+fn test_clean_state_tracking() {
+    let rd = Arc::new(RamDevice::new(64));
+    FileSystem::format(rd.clone(), 64, 16).unwrap().unmount().unwrap();
+    let options = MountOptions { require_clean: true, time: 1000, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(rd.clone(), options).unwrap();
+    assert_eq!(fs.check_due(), None);
+    assert_eq!(fs.superblock().state, FS_STATE_DIRTY);
+    assert_eq!(fs.superblock().mount_count, 1);
+    assert_eq!(fs.superblock().last_mount_time, 1000);
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+
+    // Pulled out without unmounting.
+    drop(fs);
+    assert_eq!(FileSystem::mount_with_options(rd.clone(), options).unwrap_err(), Error::NeedsCheck);
+    let fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.check_due(), Some(CheckReason::NotCleanlyUnmounted));
+    drop(fs);
+
+    mark_checked(rd.as_ref(), 2000).unwrap();
+    let mut fs = FileSystem::mount_with_options(rd.clone(), options).unwrap();
+    assert_eq!(fs.superblock().last_check_time, 2000);
+    assert_eq!(fs.superblock().mount_count, 1);
+    fs.set_max_mount_count(2).unwrap();
+    fs.unmount().unwrap();
+
+    FileSystem::mount_with_options(rd.clone(), options).unwrap().unmount().unwrap();
+    assert_eq!(FileSystem::mount_with_options(rd.clone(), options).unwrap_err(), Error::NeedsCheck);
+    let fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.check_due(), Some(CheckReason::MaxMountCount));
+}
+
+#[test]
+fn test_corruption_keeps_fs_dirty() {
+    let rd = corrupted_device();
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.lookup("/file").unwrap_err(), Error::Corrupted);
+    fs.unmount().unwrap();
+    let fs = FileSystem::mount(rd).unwrap();
+    assert_eq!(fs.check_due(), Some(CheckReason::NotCleanlyUnmounted));
+}
+
+#[test]
+fn test_superblock_backups() {
+    let rd = Arc::new(RamDevice::new(512));
+    let mut fs = FileSystem::format(rd.clone(), 512, 32).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, b"data").unwrap();
+    let backups = fs.superblock().backup_block_ids();
+    assert_eq!(backups.len(), 2);
+    assert_eq!(*backups.last().unwrap(), 511);
+    fs.unmount().unwrap();
+
+    // Backups are kept in sync with the primary.
+    let mut primary = [0u8; BLOCK_SIZE];
+    rd.read_block(SUPERBLOCK_ID, &mut primary).unwrap();
+    for &block_id in &backups {
+        let mut backup = [0u8; BLOCK_SIZE];
+        rd.read_block(block_id, &mut backup).unwrap();
+        assert_eq!(backup, primary);
+    }
+
+    // Mounted from the last block, which restores the primary.
+    rd.write_block(SUPERBLOCK_ID, &[0u8; BLOCK_SIZE]).unwrap();
+    assert_eq!(read_superblock(rd.as_ref()).unwrap_err(), Error::InvalidSuperBlock);
+    let (_, backup) = read_superblock_or_backup(rd.as_ref()).unwrap();
+    assert_eq!(backup, Some(511));
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.check_due(), Some(CheckReason::DamagedSuperBlock));
+    let mut buf = [0u8; 4];
+    fs.fread("/file", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    fs.unmount().unwrap();
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!(sb.check_due(), Some(CheckReason::NotCleanlyUnmounted), "Should stay dirty until checked");
+
+    // Then from the middle of the data region, as recorded in the damaged primary.
+    let mut primary = [0u8; BLOCK_SIZE];
+    rd.read_block(SUPERBLOCK_ID, &mut primary).unwrap();
+    primary[12] ^= 0xff;
+    rd.write_block(SUPERBLOCK_ID, &primary).unwrap();
+    rd.write_block(511, &[0u8; BLOCK_SIZE]).unwrap();
+    let (_, backup) = read_superblock_or_backup(rd.as_ref()).unwrap();
+    assert_eq!(backup, Some(backups[0]));
+    mark_checked(rd.as_ref(), 0).unwrap();
+    assert_eq!(read_superblock(rd.as_ref()).unwrap().check_due(), None);
+    assert_eq!(find_backup_superblock(rd.as_ref()).unwrap().1, 511, "Backups should be rewritten as well");
+
+    // Updates reach the backups at sync, not with every superblock write.
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+    let free_inodes = fs.superblock().free_inodes;
+    assert_ne!(find_backup_superblock(rd.as_ref()).unwrap().0.free_inodes, free_inodes);
+    fs.flush().unwrap();
+    assert_eq!(find_backup_superblock(rd.as_ref()).unwrap().0.free_inodes, free_inodes);
+}
+
+#[test]
+fn test_unrecorded_backup_locations() {
+    let rd = Arc::new(RamDevice::new(1024));
+    let fs = FileSystem::format(rd.clone(), 512, 32).unwrap();
+    let backups = fs.superblock().backup_block_ids();
+    fs.unmount().unwrap();
+    // As written before the locations were recorded.
+    let mut sb = read_superblock(rd.as_ref()).unwrap();
+    sb.backup_blocks = [0, 0];
+    write_superblock_with_backups(rd.as_ref(), &sb).unwrap();
+
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!(sb.backup_block_ids(), backups);
+    let mut primary = [0u8; BLOCK_SIZE];
+    rd.read_block(SUPERBLOCK_ID, &mut primary).unwrap();
+    primary[12] ^= 0xff;
+    rd.write_block(SUPERBLOCK_ID, &primary).unwrap();
+    assert_eq!(read_superblock_or_backup(rd.as_ref()).unwrap().1, Some(511));
+    mark_checked(rd.as_ref(), 0).unwrap();
+
+    // Growing keeps the middle backup reserved.
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let free_blocks = fs.superblock().free_blocks;
+    fs.grow(1024).unwrap();
+    let sb = *fs.superblock();
+    assert_eq!(sb.backup_blocks, [backups[0], 1023]);
+    assert_eq!(sb.free_blocks, free_blocks + 512 - sb.csum_table_blocks);
+    fs.unmount().unwrap();
+    assert_eq!(find_backup_superblock(rd.as_ref()).unwrap().1, 1023);
+}
+
+#[test]
+fn test_small_fs_without_superblock_backups() {
+    let rd = Arc::new(RamDevice::new(64));
+    let fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    assert!(fs.superblock().backup_block_ids().is_empty());
+    fs.unmount().unwrap();
+    rd.write_block(SUPERBLOCK_ID, &[0u8; BLOCK_SIZE]).unwrap();
+    assert_eq!(FileSystem::mount(rd).unwrap_err(), Error::InvalidSuperBlock);
+}
+
+/// Formats a device holding `/file`, then lets `patch` edit its superblock.
+fn patched_device(patch: impl FnOnce(&mut SuperBlock)) -> Arc<RamDevice> {
+    let rd = Arc::new(RamDevice::new(64));
+    let mut fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.unmount().unwrap();
+    let mut sb = read_superblock(rd.as_ref()).unwrap();
+    patch(&mut sb);
+    write_superblock(rd.as_ref(), &sb).unwrap();
+    rd
+}
+
+#[test]
+fn test_feature_flags() {
+    let rd = patched_device(|_| {});
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!(sb.rev_level, MUON_REV);
+    assert!(sb.has_ro_compat(RO_COMPAT_METADATA_CSUM));
+    assert!(!sb.has_ro_compat(RO_COMPAT_METADATA_CSUM | RO_COMPAT_DATA_CSUM));
+    assert_eq!(sb.unsupported_incompat(), 0);
+    assert_eq!(sb.unsupported_ro_compat(), 0);
+
+    // Unknown compatible features are ignored.
+    let rd = patched_device(|sb| sb.feature_compat |= 1 << 31);
+    let mut fs = FileSystem::mount(rd).unwrap();
+    assert!(fs.superblock().has_compat(1 << 31));
+    fs.creat("/other", FileType::Regular, Mode::RW).unwrap();
+
+    // Unknown incompatible features and newer revisions are refused.
+    let rd = patched_device(|sb| sb.feature_incompat |= 1 << 31);
+    assert_eq!(read_superblock(rd.as_ref()).unwrap_err(), Error::UnsupportedFeature);
+    let options = MountOptions { read_only: true, ..Default::default() };
+    assert_eq!(FileSystem::mount_with_options(rd, options).unwrap_err(), Error::UnsupportedFeature);
+    let rd = patched_device(|sb| sb.rev_level = MUON_REV + 1);
+    assert_eq!(FileSystem::mount(rd).unwrap_err(), Error::UnsupportedFeature);
+
+    // Unknown read-only compatible features force read-only.
+    let rd = patched_device(|sb| sb.feature_ro_compat |= 1 << 31);
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert!(fs.is_read_only());
+    assert!(fs.lookup("/file").is_ok());
+    assert_eq!(fs.creat("/other", FileType::Regular, Mode::RW).unwrap_err(), Error::ReadOnly);
+    fs.unmount().unwrap();
+    assert_eq!(read_superblock(rd.as_ref()).unwrap().unsupported_ro_compat(), 1 << 31);
+}
+
+#[test]
+fn test_revision_0_superblock() {
+    let rd = Arc::new(RamDevice::new(512));
+    let mut fs = FileSystem::format(rd.clone(), 512, 32).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, b"data").unwrap();
+    fs.unmount().unwrap();
+
+    // Fields up to the checksum only, which covers those before it.
+    let mut block = [0u8; BLOCK_SIZE];
+    rd.read_block(SUPERBLOCK_ID, &mut block).unwrap();
+    let offset = std::mem::offset_of!(SuperBlock, checksum);
+    assert_eq!(offset, 100);
+    block[offset..].fill(0);
+    let checksum = crc32c(&block[..offset]);
+    block[offset..offset + 4].copy_from_slice(&checksum.to_ne_bytes());
+    rd.write_block(SUPERBLOCK_ID, &block).unwrap();
+
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!((sb.rev_level, sb.inode_size, sb.backup_blocks), (0, 0, [0, 0]));
+    assert!(sb.volume_id().uuid.is_nil());
+    assert_eq!(sb.backup_block_ids().len(), 2);
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    let mut buf = [0u8; 4];
+    fs.fread("/file", 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+}
+
+#[test]
+fn test_volume_id() {
+    let rd = Arc::new(RamDevice::new(512));
+    let random = [0xa5u8; 16];
+    let options = FormatOptions { uuid_random: Some(random), label: "backups", ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 512, 32, options).unwrap();
+    let id = fs.volume_id();
+    assert_eq!(id.uuid, Uuid::from_random_bytes(random));
+    assert_eq!(id.uuid.to_string(), "a5a5a5a5-a5a5-45a5-a5a5-a5a5a5a5a5a5");
+    assert_eq!(id.label(), b"backups");
+
+    assert_eq!(fs.set_label(&"x".repeat(MAX_LABEL_LEN + 1)).unwrap_err(), Error::InvalidArgument);
+    assert_eq!(fs.set_label("a\0b").unwrap_err(), Error::InvalidArgument);
+    fs.set_label(&"x".repeat(MAX_LABEL_LEN)).unwrap();
+    fs.set_label("photos").unwrap();
+    fs.unmount().unwrap();
+
+    let id = probe(rd.as_ref()).unwrap();
+    assert_eq!(id.uuid, Uuid::from_random_bytes(random));
+    assert_eq!(id.label(), b"photos");
+
+    // Identified from a backup, even with features this driver does not know.
+    let mut sb = read_superblock(rd.as_ref()).unwrap();
+    sb.feature_incompat |= 1 << 31;
+    write_superblock_with_backups(rd.as_ref(), &sb).unwrap();
+    rd.write_block(SUPERBLOCK_ID, &[0u8; BLOCK_SIZE]).unwrap();
+    assert_eq!(FileSystem::mount(rd.clone()).unwrap_err(), Error::UnsupportedFeature);
+    assert_eq!(probe(rd.as_ref()).unwrap(), id);
+
+    // Random bytes are required, the default ones come from the host.
+    let rd = Arc::new(RamDevice::new(64));
+    for uuid_random in [None, Some([0; 16])] {
+        let options = FormatOptions { uuid_random, ..Default::default() };
+        assert_eq!(FileSystem::format_with_options(rd.clone(), 64, 16, options).unwrap_err(), Error::InvalidArgument);
+    }
+    let first = probe(patched_device(|_| {}).as_ref()).unwrap();
+    let second = probe(patched_device(|_| {}).as_ref()).unwrap();
+    assert!(!first.uuid.is_nil());
+    assert_ne!(first.uuid, second.uuid);
+    assert_eq!(first.label(), b"");
+    assert_eq!(probe(&RamDevice::new(64)).unwrap_err(), Error::InvalidSuperBlock);
+}
@@ -0,0 +1,169 @@
+#![allow(unused)]
+
+use std::sync::{Arc, Mutex};
+
+mod common;
+
+use muon::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Write(u32),
+    Barrier,
+    Fua(u32),
+    Flush(u32),
+}
+
+/// RAM device recording the order of writes, barriers and block flushes.
+struct OpLog {
+    inner: RamDevice,
+    ops: Mutex<Vec<Op>>,
+}
+
+impl OpLog {
+    fn new(num_blocks: usize) -> Self {
+        OpLog { inner: RamDevice::new(num_blocks), ops: Mutex::new(Vec::new()) }
+    }
+
+    fn take(&self) -> Vec<Op> {
+        std::mem::take(&mut *self.ops.lock().unwrap())
+    }
+}
+
+impl BlockDevice for OpLog {
+    fn num_blocks(&self) -> usize {
+        self.inner.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.ops.lock().unwrap().push(Op::Write(block_id));
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn barrier(&self) -> Result<()> {
+        self.ops.lock().unwrap().push(Op::Barrier);
+        Ok(())
+    }
+
+    fn flush_blocks(&self, block_ids: &[u32]) -> Result<()> {
+        self.ops.lock().unwrap().extend(block_ids.iter().map(|&block_id| Op::Flush(block_id)));
+        Ok(())
+    }
+
+    fn write_block_fua(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.ops.lock().unwrap().push(Op::Fua(block_id));
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+/// Asserts that a barrier or a flush of `before` separates a write of it from the first write of `after`.
+/// Later writes of `before` are allowed, inodes of parent and child may share a block.
+fn assert_ordered(ops: &[Op], before: u32, after: u32) {
+    let Some(first_after) = ops.iter().position(|&op| op == Op::Write(after)) else {
+        panic!("Block {} should be written: {:?}", after, ops);
+    };
+    let Some(first_before) = ops[..first_after].iter().position(|&op| op == Op::Write(before)) else {
+        panic!("Block {} should be written before block {}: {:?}", before, after, ops);
+    };
+    assert!(
+        ops[first_before..first_after].iter().any(|&op| op == Op::Barrier || op == Op::Flush(before)),
+        "Block {} should reach the disk before block {}: {:?}", before, after, ops
+    );
+}
+
+#[test]
+fn test_create_and_remove_ordering() {
+    let device = Arc::new(OpLog::new(128));
+    let mut fs = FileSystem::format(device.clone(), 128, 32).unwrap();
+    let sb = *fs.superblock();
+    let inode_block = |inode_id: u32| sb.inode_table_start + inode_id / (BLOCK_SIZE / INODE_SIZE) as u32;
+    // The root directory holds the first data block.
+    let root_dir_block = sb.data_start;
+    device.take();
+
+    // Initialized inodes before the entries pointing to them, flushing only the blocks they depend on.
+    let file_id = fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let ops = device.take();
+    assert_ordered(&ops, inode_block(file_id), root_dir_block);
+    assert!(!ops.contains(&Op::Barrier), "{:?}", ops);
+    assert!(!ops.contains(&Op::Flush(root_dir_block)), "{:?}", ops);
+    let dir_id = fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    assert_ordered(&device.take(), inode_block(dir_id), root_dir_block);
+    let link_id = fs.symlink("/file", "/link").unwrap();
+    assert_ordered(&device.take(), inode_block(link_id), root_dir_block);
+    fs.link("/file", "/hard").unwrap();
+    assert_ordered(&device.take(), inode_block(file_id), root_dir_block);
+
+    // Entries removed before the inode and its blocks are released.
+    fs.fwrite("/file", 0, b"data").unwrap();
+    fs.remove("/hard", FileType::Regular).unwrap();
+    device.take();
+    fs.remove("/file", FileType::Regular).unwrap();
+    let ops = device.take();
+    assert_ordered(&ops, root_dir_block, sb.inode_bitmap_start);
+    assert_ordered(&ops, inode_block(file_id), sb.data_bitmap_start);
+    assert_ordered(&ops, inode_block(file_id), sb.inode_bitmap_start);
+
+    // Truncated inodes no longer point to blocks when those are freed.
+    fs.creat("/big", FileType::Regular, Mode::RW).unwrap();
+    let (big_id, _) = fs.lookup("/big").unwrap();
+    fs.fwrite("/big", 0, &[1u8; 3 * BLOCK_SIZE]).unwrap();
+    device.take();
+    fs.ftruncate("/big").unwrap();
+    assert_ordered(&device.take(), inode_block(big_id), sb.data_bitmap_start);
+}
+
+#[test]
+fn test_unmount_commits_superblock_last() {
+    let device = Arc::new(OpLog::new(64));
+    let mut fs = FileSystem::format(device.clone(), 64, 16).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    device.take();
+    fs.unmount().unwrap();
+    let ops = device.take();
+    assert_eq!(ops[ops.len() - 2..], [Op::Barrier, Op::Fua(SUPERBLOCK_ID)]);
+}
+
+/// RAM device whose FUA writes always fail.
+struct FailingFua(RamDevice);
+
+impl BlockDevice for FailingFua {
+    fn num_blocks(&self) -> usize {
+        self.0.num_blocks()
+    }
+
+    fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
+        self.0.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: u32, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        self.0.write_block(block_id, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_block_fua(&self, _block_id: u32, _buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+        Err(Error::IoError)
+    }
+}
+
+#[test]
+fn test_failed_fua_write_is_not_cached() {
+    let rd = RamDevice::new(16);
+    rd.write_block(SUPERBLOCK_ID, &[5u8; BLOCK_SIZE]).unwrap();
+    let cache = BufferCache::new(FailingFua(rd), 4);
+    assert_eq!(cache.write_block_fua(SUPERBLOCK_ID, &[6u8; BLOCK_SIZE]).unwrap_err(), Error::IoError);
+    let mut buf = [0u8; BLOCK_SIZE];
+    cache.read_block(SUPERBLOCK_ID, &mut buf).unwrap();
+    assert_eq!(buf, [5u8; BLOCK_SIZE]);
+}
@@ -0,0 +1,306 @@
+#![allow(unused)]
+
+use std::sync::Arc;
+
+mod common;
+
+use muon::*;
+
+/// Contents of the `i`th test file.
+fn contents(i: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|j| (i * 7 + j % 251) as u8).collect()
+}
+
+fn assert_contents(fs: &mut FileSystem<RamDevice>, path: &str, expected: &[u8]) {
+    let mut buf = vec![0u8; expected.len()];
+    assert_eq!(fs.fread(path, 0, &mut buf).unwrap(), expected.len());
+    assert_eq!(buf, expected, "{}", path);
+}
+
+/// Damages the primary superblock, leaving the backup locations it records readable.
+fn damage_superblock(rd: &RamDevice) {
+    let mut buf = [0u8; BLOCK_SIZE];
+    rd.read_block(SUPERBLOCK_ID, &mut buf).unwrap();
+    buf[12] ^= 0xff;
+    rd.write_block(SUPERBLOCK_ID, &buf).unwrap();
+}
+
+#[test]
+fn test_grow() {
+    let rd = Arc::new(RamDevice::new(2048));
+    let options = FormatOptions { data_checksums: true, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 512, 32, options).unwrap();
+    let old = fs.statfs();
+    let old_backups = fs.superblock().backup_block_ids();
+    let old_table_start = fs.superblock().csum_table_start;
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let data = contents(0, 100 * BLOCK_SIZE + 10);
+    fs.fwrite("/file", 0, &data).unwrap();
+    let before = *fs.superblock();
+
+    fs.grow(1024).unwrap();
+    let sb = *fs.superblock();
+    log!("Grown superblock: {:?}", sb);
+    assert_eq!(sb.num_blocks, 1024);
+    assert_eq!(sb.data_start, before.data_start, "The data region should not move");
+    // The checksum table covered 512 blocks only.
+    assert_ne!(sb.csum_table_start, old_table_start);
+    assert_eq!(sb.csum_table_start, 512);
+    assert_eq!(sb.csum_table_blocks, 1024 / CSUMS_PER_BLOCK as u32);
+    assert_eq!(sb.data_bitmap_start, before.data_bitmap_start, "The data bitmap is large enough");
+    assert_eq!(sb.backup_block_ids(), vec![old_backups[0], 1023]);
+    // Gained 512 blocks, minus the new checksum table. The old last backup is freed, the new one taken.
+    assert_eq!(sb.free_blocks, before.free_blocks + 512 - sb.csum_table_blocks);
+    assert_contents(&mut fs, "/file", &data);
+
+    // The added blocks are allocated once the old ones run out.
+    let mut files = Vec::new();
+    for i in 1.. {
+        let path = format!("/fill{}", i);
+        fs.creat(&path, FileType::Regular, Mode::RW).unwrap();
+        let data = contents(i, 50 * BLOCK_SIZE);
+        match fs.fwrite(&path, 0, &data) {
+            Ok(_) => files.push((path, data)),
+            // The data bitmap is exhausted.
+            Err(Error::OutOfBounds) => break,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    assert!(files.len() * 50 > 512, "Only {} files written", files.len());
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(fs.superblock().num_blocks, 1024);
+    assert_contents(&mut fs, "/file", &data);
+    for (path, data) in &files {
+        assert_contents(&mut fs, path, data);
+    }
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    fs.unmount().unwrap();
+
+    // Both backups are current.
+    damage_superblock(&rd);
+    let (backup, block_id) = find_backup_superblock(rd.as_ref()).unwrap();
+    assert_eq!((backup.num_blocks, block_id), (1024, 1023));
+    rd.write_block(1023, &[0u8; BLOCK_SIZE]).unwrap();
+    let (backup, block_id) = find_backup_superblock(rd.as_ref()).unwrap();
+    assert_eq!((backup.num_blocks, block_id), (1024, old_backups[0]));
+}
+
+#[test]
+fn test_grow_relocates_bitmap() {
+    // 8 data bitmap blocks cover 32768 blocks.
+    let rd = Arc::new(RamDevice::new(80000));
+    let mut fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    assert_eq!(fs.superblock().data_bitmap_blocks, 8);
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    let data = contents(0, 20 * BLOCK_SIZE);
+    fs.fwrite("/file", 0, &data).unwrap();
+
+    fs.grow(40000).unwrap();
+    let sb = *fs.superblock();
+    assert_eq!(sb.data_bitmap_start, 64);
+    assert_eq!(sb.data_bitmap_blocks, (40000 - sb.data_start).div_ceil(BLOCK_SIZE as u32 * 8));
+    assert_eq!(sb.csum_table_start, 64 + sb.data_bitmap_blocks);
+    let stat = fs.statfs();
+    assert_eq!(sb.free_blocks, stat.free_blocks);
+    // The relocated bitmap and checksum table, and the backups, count as metadata.
+    let metadata_blocks = sb.data_start + sb.data_bitmap_blocks + sb.csum_table_blocks + sb.backup_block_ids().len() as u32;
+    assert_eq!(stat.metadata_bytes, (metadata_blocks as usize * BLOCK_SIZE) as u64);
+    assert_eq!(stat.metadata_bytes + stat.data_bytes + (stat.free_blocks as usize * BLOCK_SIZE) as u64, (40000 * BLOCK_SIZE) as u64);
+
+    fs.creat("/big", FileType::Regular, Mode::RW).unwrap();
+    let big = contents(1, 100 * BLOCK_SIZE);
+    fs.fwrite("/big", 0, &big).unwrap();
+    fs.unmount().unwrap();
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_contents(&mut fs, "/file", &data);
+    assert_contents(&mut fs, "/big", &big);
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    let free_before = fs.superblock().free_blocks;
+    fs.remove("/big", FileType::Regular).unwrap();
+    assert!(fs.superblock().free_blocks > free_before);
+
+    // Relocated again, the previous copies in the data region are freed.
+    let old = *fs.superblock();
+    fs.grow(80000).unwrap();
+    let sb = *fs.superblock();
+    assert_eq!(sb.data_bitmap_start, 40000);
+    assert_eq!(sb.csum_table_start, 40000 + sb.data_bitmap_blocks);
+    let added = 40000 - sb.data_bitmap_blocks - sb.csum_table_blocks;
+    assert_eq!(sb.free_blocks, old.free_blocks + added + old.data_bitmap_blocks + old.csum_table_blocks);
+    fs.unmount().unwrap();
+    let mut fs = FileSystem::mount(rd).unwrap();
+    assert_contents(&mut fs, "/file", &data);
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+}
+
+#[test]
+fn test_grow_errors() {
+    let rd = Arc::new(RamDevice::new(128));
+    let mut fs = FileSystem::format(rd.clone(), 64, 16).unwrap();
+    assert_eq!(fs.grow(256).unwrap_err(), Error::OutOfBounds);
+    assert_eq!(fs.grow(32).unwrap_err(), Error::InvalidArgument);
+    fs.grow(64).unwrap();
+    assert_eq!(fs.superblock().num_blocks, 64);
+    fs.unmount().unwrap();
+
+    let options = MountOptions { read_only: true, ..Default::default() };
+    let mut fs = FileSystem::mount_with_options(rd, options).unwrap();
+    assert_eq!(fs.grow(128).unwrap_err(), Error::ReadOnly);
+}
+
+/// Fills a file system with files of `blocks` blocks, then removes every other one,
+/// leaving free blocks below used ones. Returns the remaining files.
+fn fill_with_holes(fs: &mut FileSystem<RamDevice>, count: usize, blocks: usize) -> Vec<(String, Vec<u8>)> {
+    fs.creat("/dir", FileType::Directory, Mode::RW).unwrap();
+    let mut files = Vec::new();
+    for i in 0..count {
+        let path = format!("/dir/file{}", i);
+        fs.creat(&path, FileType::Regular, Mode::RW).unwrap();
+        let data = contents(i, blocks * BLOCK_SIZE + i);
+        fs.fwrite(&path, 0, &data).unwrap();
+        files.push((path, data));
+    }
+    let mut kept = Vec::new();
+    for (i, file) in files.into_iter().enumerate() {
+        if i % 2 == 0 {
+            fs.remove(&file.0, FileType::Regular).unwrap();
+        } else {
+            kept.push(file);
+        }
+    }
+    kept
+}
+
+#[test]
+fn test_shrink() {
+    let rd = Arc::new(RamDevice::new(1024));
+    let options = FormatOptions { data_checksums: true, ..Default::default() };
+    let mut fs = FileSystem::format_with_options(rd.clone(), 1024, 64, options).unwrap();
+    let files = fill_with_holes(&mut fs, 16, 40);
+    let middle_backup = fs.superblock().backup_block_ids()[0];
+    let used = fs.superblock().num_blocks - fs.superblock().data_start - fs.superblock().free_blocks;
+    fs.unmount().unwrap();
+
+    // Mounted file systems are refused.
+    let fs = FileSystem::mount(rd.clone()).unwrap();
+    assert_eq!(shrink(rd.as_ref(), 600, true).unwrap_err(), Error::NeedsCheck);
+    fs.unmount().unwrap();
+
+    let mut image = vec![0u8; 1024 * BLOCK_SIZE];
+    rd.read_blocks(0, &mut image).unwrap();
+    let report = shrink(rd.as_ref(), 600, true).unwrap();
+    log!("Dry run: {:?}", report);
+    assert!(report.blocks_moved > 0);
+    let mut after = vec![0u8; 1024 * BLOCK_SIZE];
+    rd.read_blocks(0, &mut after).unwrap();
+    assert!(image == after, "A dry run should not write");
+    assert_eq!(shrink(rd.as_ref(), 1024, true).unwrap().blocks_moved, 0);
+    assert_eq!(shrink(rd.as_ref(), 1025, true).unwrap_err(), Error::InvalidArgument);
+    let data_start = read_superblock(rd.as_ref()).unwrap().data_start;
+    assert_eq!(shrink(rd.as_ref(), data_start + used / 2, true).unwrap_err(), Error::OutOfSpace);
+    assert_eq!(shrink(rd.as_ref(), data_start + used / 2, false).unwrap_err(), Error::OutOfSpace);
+
+    assert_eq!(shrink(rd.as_ref(), 600, false).unwrap(), report);
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!(sb.num_blocks, 600);
+    assert_eq!(sb.free_blocks, report.free_blocks);
+    assert_eq!(sb.backup_block_ids(), vec![middle_backup, 599]);
+    assert_eq!(sb.check_due(), None);
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    for (path, data) in &files {
+        assert_contents(&mut fs, path, data);
+    }
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    // The free blocks are all below the new end.
+    let free = fs.superblock().free_blocks;
+    fs.creat("/rest", FileType::Regular, Mode::RW).unwrap();
+    let rest = contents(99, (free as usize - 2) * BLOCK_SIZE);
+    fs.fwrite("/rest", 0, &rest).unwrap();
+    assert_contents(&mut fs, "/rest", &rest);
+    fs.unmount().unwrap();
+
+    // The stale last backup is gone.
+    damage_superblock(&rd);
+    let (backup, block_id) = find_backup_superblock(rd.as_ref()).unwrap();
+    assert_eq!((backup.num_blocks, block_id), (600, 599));
+}
+
+#[test]
+fn test_shrink_after_grow() {
+    let rd = Arc::new(RamDevice::new(1024));
+    let mut fs = FileSystem::format(rd.clone(), 512, 64).unwrap();
+    fs.grow(1024).unwrap();
+    let files = fill_with_holes(&mut fs, 12, 40);
+    fs.unmount().unwrap();
+
+    let report = shrink(rd.as_ref(), 512, false).unwrap();
+    log!("Shrunk: {:?}", report);
+    assert!(report.blocks_moved > 0);
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert!(sb.csum_table_start >= sb.data_start && sb.csum_table_start + sb.csum_table_blocks <= 512);
+    assert_eq!(sb.csum_table_blocks, 4);
+    assert_eq!(*sb.backup_block_ids().last().unwrap(), 511);
+
+    let mut fs = FileSystem::mount(rd).unwrap();
+    for (path, data) in &files {
+        assert_contents(&mut fs, path, data);
+    }
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+}
+
+#[test]
+fn test_shrink_keeps_middle_backup_apart() {
+    let rd = Arc::new(RamDevice::new(1024));
+    let mut fs = FileSystem::format(rd.clone(), 512, 64).unwrap();
+    let middle = fs.superblock().backup_block_ids()[0];
+    fs.grow(1024).unwrap();
+    let sb = *fs.superblock();
+    assert!(sb.csum_table_start >= sb.data_start, "The checksum table should be relocated");
+
+    // Every block below the middle backup is taken, the first free ones follow it.
+    let metadata_blocks = 1 + sb.csum_table_blocks + 1;
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    loop {
+        let used = fs.superblock().num_blocks - sb.data_start - fs.superblock().free_blocks;
+        if used - metadata_blocks >= middle - sb.data_start {
+            assert_eq!(used - metadata_blocks, middle - sb.data_start);
+            break;
+        }
+        if files.last().is_none_or(|(_, data)| data.len() == NUM_DIRECT_PTRS * BLOCK_SIZE) {
+            let path = format!("/file{}", files.len());
+            fs.creat(&path, FileType::Regular, Mode::RW).unwrap();
+            files.push((path, Vec::new()));
+        }
+        let (path, data) = files.last_mut().unwrap();
+        let block = contents(data.len() / BLOCK_SIZE, BLOCK_SIZE);
+        fs.fwrite(path, data.len(), &block).unwrap();
+        data.extend_from_slice(&block);
+    }
+    fs.unmount().unwrap();
+
+    shrink(rd.as_ref(), 512, false).unwrap();
+    let sb = read_superblock(rd.as_ref()).unwrap();
+    assert_eq!(sb.backup_block_ids(), vec![middle, 511]);
+    assert!(sb.csum_table_start > middle && sb.csum_table_start + sb.csum_table_blocks <= 511);
+    // The data bitmap in front of the data region shrinks as well.
+    assert_eq!(sb.data_bitmap_blocks, (512 - sb.data_start).div_ceil(BLOCK_SIZE as u32 * 8));
+
+    let mut fs = FileSystem::mount(rd.clone()).unwrap();
+    for (path, data) in &files {
+        assert_contents(&mut fs, path, data);
+    }
+    let report = fs.scrub().unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    fs.unmount().unwrap();
+    damage_superblock(&rd);
+    assert_eq!(find_backup_superblock(rd.as_ref()).unwrap().1, 511);
+}
@@ -0,0 +1,98 @@
+#![allow(unused)]
+
+use std::sync::Arc;
+
+mod common;
+
+use muon::*;
+
+fn export(fs: &mut FileSystem<RamDevice>, path: &str) -> Vec<u8> {
+    let mut archive = Vec::new();
+    export_tar(fs, path, |record| {
+        archive.extend_from_slice(record);
+        Ok(())
+    }).unwrap();
+    archive
+}
+
+fn import(fs: &mut FileSystem<RamDevice>, archive: &[u8], dest: &str) -> Result<usize> {
+    let mut importer = TarImporter::new(fs, dest)?;
+    for record in archive.chunks(TAR_RECORD_SIZE) {
+        importer.feed(record.try_into().unwrap())?;
+    }
+    importer.finish()
+}
+
+#[test]
+fn test_tar_roundtrip() {
+    let mut fs = FileSystem::format(Arc::new(RamDevice::new(256)), 256, 64).unwrap();
+    fs.creat("/src", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/src/sub", FileType::Directory, Mode::RW).unwrap();
+    fs.creat("/src/sub/data.bin", FileType::Regular, Mode::RW).unwrap();
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE + 17).map(|i| (i % 251) as u8).collect();
+    fs.fwrite("/src/sub/data.bin", 0, &data).unwrap();
+    fs.creat("/src/empty", FileType::Regular, Mode::Read).unwrap();
+    fs.link("/src/sub/data.bin", "/src/hard").unwrap();
+    fs.symlink("/src/sub/data.bin", "/src/sym").unwrap();
+    let long_target = format!("/{}", "x".repeat(300));
+    fs.symlink(&long_target, "/src/long_sym").unwrap();
+
+    let archive = export(&mut fs, "/src");
+    log!("Archive size: {}", archive.len());
+    assert_eq!(archive.len() % TAR_RECORD_SIZE, 0);
+
+    let mut fs2 = FileSystem::format(Arc::new(RamDevice::new(256)), 256, 64).unwrap();
+    let skipped = import(&mut fs2, &archive, "/dst").unwrap();
+    assert_eq!(skipped, 0);
+
+    let mut buf = vec![0u8; data.len()];
+    fs2.fread("/dst/sub/data.bin", 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    let (data_inode_id, _) = fs2.lookup("/dst/sub/data.bin").unwrap();
+    let (hard_inode_id, _) = fs2.lookup("/dst/hard").unwrap();
+    assert_eq!(data_inode_id, hard_inode_id, "Hard links should share an inode");
+    assert_eq!(fs2.get_inode(data_inode_id).unwrap().links_cnt, 2);
+
+    let (empty_inode_id, ftype) = fs2.lookup("/dst/empty").unwrap();
+    assert_eq!(ftype, FileType::Regular);
+    assert_eq!(fs2.get_inode(empty_inode_id).unwrap().mode, Mode::Read);
+
+    let mut target = [0u8; MAX_PATH_LEN];
+    fs2.read_link("/dst/sym", &mut target).unwrap();
+    assert_eq!(trim_zero(&target), b"/src/sub/data.bin");
+    let mut target = [0u8; MAX_SYMLINK_LEN];
+    let len = fs2.read_link_into("/dst/long_sym", &mut target).unwrap();
+    assert_eq!(&target[..len], long_target.as_bytes());
+}
+
+#[test]
+fn test_tar_import_rejects_bad_archives() {
+    let mut fs = FileSystem::format(Arc::new(RamDevice::new(128)), 128, 32).unwrap();
+    fs.creat("/file", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/file", 0, &[7u8; 2 * BLOCK_SIZE]).unwrap();
+    let archive = export(&mut fs, "/file");
+
+    // Cut in the middle of the file contents.
+    let mut fs2 = FileSystem::format(Arc::new(RamDevice::new(128)), 128, 32).unwrap();
+    let res = import(&mut fs2, &archive[..2 * TAR_RECORD_SIZE], "/");
+    assert_eq!(res.unwrap_err(), Error::InvalidArchive);
+
+    // Corrupted header checksum.
+    let mut corrupted = archive.clone();
+    corrupted[0] ^= 0xff;
+    let res = import(&mut fs2, &corrupted, "/");
+    assert_eq!(res.unwrap_err(), Error::InvalidArchive);
+
+    // Member names must not escape the destination directory.
+    let mut escaping = archive.clone();
+    escaping[..7].copy_from_slice(b"../file");
+    let sum: u32 = escaping[..TAR_RECORD_SIZE]
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| if (148..156).contains(&i) { b' ' as u32 } else { c as u32 })
+        .sum();
+    escaping[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    let res = import(&mut fs2, &escaping, "/dst");
+    assert_eq!(res.unwrap_err(), Error::InvalidPath);
+}
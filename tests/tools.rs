@@ -0,0 +1,74 @@
+#![allow(unused)]
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+mod common;
+
+use muon::*;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("muon-tools-{}-{}", name, std::process::id()))
+}
+
+/// Saves the image of a device to a host file.
+fn save_image(rd: &RamDevice, name: &str) -> PathBuf {
+    let path = temp_path(name).with_extension("img");
+    std::fs::write(&path, rd.to_vec()).unwrap();
+    path
+}
+
+#[test]
+fn test_extract_sparse_file() {
+    let rd = Arc::new(RamDevice::new(256));
+    let mut fs = FileSystem::format(rd.clone(), 256, 32).unwrap();
+    fs.creat("/sparse", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/sparse", 0, b"head").unwrap();
+    // Leaves holes in direct and indirect blocks.
+    fs.fwrite("/sparse", 20 * BLOCK_SIZE + 3, b"tail").unwrap();
+    fs.unmount().unwrap();
+    let image = save_image(&rd, "extract");
+    let dest = temp_path("extract-dest");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_muon-extract")).arg(&image).arg(&dest).output().unwrap();
+    log!("muon-extract: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+    let data = std::fs::read(dest.join("sparse")).unwrap();
+    assert_eq!(data.len(), 20 * BLOCK_SIZE + 7);
+    assert_eq!(&data[..4], b"head");
+    assert!(data[4..20 * BLOCK_SIZE + 3].iter().all(|&b| b == 0));
+    assert_eq!(&data[20 * BLOCK_SIZE + 3..], b"tail");
+
+    std::fs::remove_file(&image).unwrap();
+    std::fs::remove_dir_all(&dest).unwrap();
+}
+
+#[test]
+fn test_debug_cat_does_not_write() {
+    let rd = Arc::new(RamDevice::new(256));
+    let mut fs = FileSystem::format(rd.clone(), 256, 32).unwrap();
+    fs.creat("/sparse", FileType::Regular, Mode::RW).unwrap();
+    fs.fwrite("/sparse", 3 * BLOCK_SIZE, b"tail").unwrap();
+    fs.unmount().unwrap();
+    let image = save_image(&rd, "debug-cat");
+    let before = std::fs::read(&image).unwrap();
+
+    // Even with the image opened for writing, reading holes must not allocate.
+    let output = Command::new(env!("CARGO_BIN_EXE_muon-debug"))
+        .arg("-w").arg("-R").arg("cat /sparse").arg(&image)
+        .output().unwrap();
+    log!("muon-debug: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+    assert_eq!(output.stdout.len(), 3 * BLOCK_SIZE + 4);
+    assert!(output.stdout[..3 * BLOCK_SIZE].iter().all(|&b| b == 0));
+    assert_eq!(&output.stdout[3 * BLOCK_SIZE..], b"tail");
+    assert!(std::fs::read(&image).unwrap() == before);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_muon-debug"))
+        .arg("-R").arg("cat /missing").arg(&image)
+        .output().unwrap();
+    assert!(!output.status.success());
+
+    std::fs::remove_file(&image).unwrap();
+}